@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Label under which ACME DNS-01 challenges are published (RFC 8555 §8.4).
+pub const CHALLENGE_LABEL: &str = "_acme-challenge";
+
+/// TTL of the challenge TXT records, short so a CA retrying validation
+/// doesn't hit a resolver's cached copy of an older token.
+pub const TTL: u32 = 1;
+
+/// Challenges are normally cleared by the client once validated; this
+/// removes the ones it forgot.
+const LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Values pending at each name, with the time they were presented.
+type Pending = HashMap<String, Vec<(String, Instant)>>;
+
+/// Pending DNS-01 challenge tokens, served as TXT records. Cheap to clone;
+/// the HTTP API and the DNS listeners share one store.
+#[derive(Clone)]
+pub struct Challenges {
+    inner: Arc<Mutex<Pending>>,
+}
+
+impl Challenges {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Pending> {
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Publish `value` at `name`, next to any other pending value: a
+    /// certificate for `example.com` and `*.example.com` needs two.
+    pub fn present(&self, name: &str, value: &str) {
+        let mut challenges = self.lock();
+        let values = challenges.entry(key(name)).or_default();
+        values.retain(|(existing, _)| existing != value);
+        values.push((value.to_string(), Instant::now()));
+    }
+
+    /// Remove `value` from `name`, or every value if `None`.
+    pub fn cleanup(&self, name: &str, value: Option<&str>) {
+        let mut challenges = self.lock();
+        let key = key(name);
+        if let Some(values) = challenges.get_mut(&key) {
+            values.retain(|(existing, _)| value.is_some_and(|value| existing != value));
+            if values.is_empty() {
+                challenges.remove(&key);
+            }
+        }
+    }
+
+    /// The values pending at `name`, oldest first.
+    pub fn get(&self, name: &str) -> Vec<String> {
+        self.expire()
+            .get(&key(name))
+            .map(|values| values.iter().map(|(value, _)| value.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Every name with pending values, sorted by name.
+    pub fn all(&self) -> Vec<(String, Vec<String>)> {
+        let mut all: Vec<(String, Vec<String>)> = self
+            .expire()
+            .iter()
+            .map(|(name, values)| {
+                let values = values.iter().map(|(value, _)| value.clone()).collect();
+                (name.clone(), values)
+            })
+            .collect();
+        all.sort();
+        all
+    }
+
+    fn expire(&self) -> MutexGuard<'_, Pending> {
+        let mut challenges = self.lock();
+        challenges.retain(|_, values| {
+            values.retain(|(_, added)| added.elapsed() < LIFETIME);
+            !values.is_empty()
+        });
+        challenges
+    }
+}
+
+fn key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether `name` is a challenge name, `_acme-challenge.<something>`.
+pub fn is_challenge_name(name: &str) -> bool {
+    name.split('.')
+        .next()
+        .is_some_and(|label| label.eq_ignore_ascii_case(CHALLENGE_LABEL))
+        && name.contains('.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenges_are_presented_and_cleaned_up() {
+        let challenges = Challenges::new();
+        let name = "_acme-challenge.app-10-0-0-1.ns.example.com.";
+        challenges.present(name, "first");
+        challenges.present("_ACME-challenge.app-10-0-0-1.ns.example.com", "second");
+        challenges.present(name, "first");
+        assert_eq!(challenges.get(name), vec!["second", "first"]);
+        assert_eq!(
+            challenges.all(),
+            vec![(
+                name.trim_end_matches('.').to_string(),
+                vec!["second".to_string(), "first".to_string()]
+            )]
+        );
+
+        challenges.cleanup(name, Some("second"));
+        assert_eq!(challenges.get(name), vec!["first"]);
+        challenges.cleanup(name, None);
+        assert!(challenges.get(name).is_empty());
+
+        assert!(is_challenge_name(name));
+        assert!(!is_challenge_name("_acme-challenge"));
+        assert!(!is_challenge_name("app.ns.example.com"));
+    }
+}
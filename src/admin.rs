@@ -0,0 +1,638 @@
+use crate::config::{Config, Reloadable};
+use crate::dns::message::{Message, Record};
+use crate::dns::query::{handle_query_internal, is_in_zone, qtype_name, qtype_number, rcode_name};
+use crate::dns::records::{self, apex_conflict, format_record, parse_record, TYPE_CAA};
+use crate::dns::response::encode_domain_name;
+use crate::dns::update;
+use crate::http::{self, HttpState, Request, Response};
+use crate::query_log::json_string;
+use log::info;
+use std::net::{Ipv4Addr, SocketAddr};
+
+/// Clients listed by `/admin/rate-limits` unless `top` says otherwise.
+const DEFAULT_TOP: usize = 20;
+
+/// The settings `POST /admin/reload` replaces; the rest need a restart.
+const RELOADABLE: [&str; 5] = [
+    "STATIC_RECORDS",
+    "TXT_RECORDS",
+    "CAA_RECORDS",
+    "RATE_LIMIT_EXEMPT",
+    "RATE_LIMIT_OVERRIDES",
+];
+
+/// The admin API, enabled by `ADMIN_API_TOKEN`:
+///
+/// - `GET /admin/config`: the effective configuration, without secrets.
+/// - `GET /admin/rate-limits?top=N`: rate limiter state and top talkers.
+/// - `GET|POST|DELETE /admin/records`: list static records, or add or
+///   delete the ones in the body, one per line in presentation format.
+/// - `GET /admin/acme`, `POST /admin/acme/present|cleanup`: ACME challenges.
+/// - `POST /admin/records/reload`: re-read `RECORDS_FILE`.
+/// - `POST /admin/reload`: replace the settings in `RELOADABLE` with the
+///   `NAME=value` lines of the body.
+/// - `GET /admin/query?name=N&type=T`: resolve a query and decode it.
+pub fn route(action: &str, request: &Request, state: &HttpState) -> Response {
+    let config = &state.config;
+    let Some(token) = &config.admin_api_token else {
+        return Response::text(404, "Not Found\n");
+    };
+    if !http::authorized(request.authorization.as_deref(), token) {
+        return Response::text(401, "Unauthorized\n");
+    }
+
+    match (request.method.as_str(), action) {
+        ("GET", "config") => Response::json(200, config_json(state)),
+        ("GET", "rate-limits") => {
+            let top = param(&request.query, "top")
+                .and_then(|top| top.parse().ok())
+                .unwrap_or(DEFAULT_TOP);
+            Response::json(200, rate_limits_json(state, top))
+        }
+        ("GET", "records") => {
+            let lines: Vec<String> = config.records.all().iter().map(format_record).collect();
+            Response::json(
+                200,
+                format!(
+                    "{{\"serial\":{},\"records\":{}}}",
+                    config.records.serial(),
+                    json_list(lines.iter().map(|line| json_string(line)))
+                ),
+            )
+        }
+        ("POST" | "DELETE", "records") => change_records(request, config),
+        ("GET", "acme") => {
+            let names = config.challenges.all().into_iter().map(|(name, values)| {
+                format!(
+                    "{{\"fqdn\":{},\"values\":{}}}",
+                    json_string(&format!("{name}.")),
+                    json_list(values.iter().map(|value| json_string(value)))
+                )
+            });
+            Response::json(200, json_list(names))
+        }
+        ("POST", "acme/present" | "acme/cleanup") => {
+            http::challenge(&action["acme/".len()..], request, config)
+        }
+        ("POST", "records/reload") => match config.records.reload() {
+            Ok(count) => {
+                info!("Admin API reloaded {count} record(s)");
+                Response::json(
+                    200,
+                    format!(
+                        "{{\"records\":{count},\"serial\":{}}}",
+                        config.records.serial()
+                    ),
+                )
+            }
+            Err(e) => Response::text(409, format!("{e}\n")),
+        },
+        ("POST", "reload") => reload(request, state),
+        ("GET", "query") => query(request, config),
+        (
+            _,
+            "config" | "rate-limits" | "records" | "acme" | "acme/present" | "acme/cleanup"
+            | "records/reload" | "reload" | "query",
+        ) => Response::text(405, "Method Not Allowed\n"),
+        _ => Response::text(404, "Not Found\n"),
+    }
+}
+
+/// Add (`POST`) or delete (`DELETE`) the records in the body, all or none.
+/// Adding follows the same rules as a dynamic update: a CNAME can't sit
+/// next to other data, and adding one replaces the CNAME already there.
+/// The apex takes what `STATIC_RECORDS` can set there: no CNAME, no A.
+fn change_records(request: &Request, config: &Config) -> Response {
+    let mut parsed = Vec::new();
+    for line in request.body.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let record = match parse_record(line) {
+            Ok(record) => record,
+            Err(e) => return Response::text(400, format!("{line}: {e}\n")),
+        };
+        if !is_in_zone(&record.name, &config.glue_name) {
+            return Response::text(400, format!("{line}: not in the zone\n"));
+        }
+        parsed.push(record);
+    }
+
+    let adding = request.method == "POST";
+    let result = config.records.update(|records| {
+        let mut changed = false;
+        for record in &parsed {
+            if let Some(conflict) = apex_conflict(record, &config.glue_name).filter(|_| adding) {
+                return Err(format!("{}: {conflict}", format_record(record)));
+            }
+            let applied = if adding {
+                update::add(record, records)
+            } else {
+                update::delete(record, records)
+            };
+            let conflict = adding
+                && !applied
+                && !records.iter().any(|existing| {
+                    existing.name == record.name
+                        && existing.rtype == record.rtype
+                        && existing.rdata == record.rdata
+                });
+            if conflict {
+                return Err(format!("{}: conflicts with a CNAME", format_record(record)));
+            }
+            changed |= applied;
+        }
+        Ok(changed)
+    });
+
+    match result {
+        Ok(changed) => {
+            info!(
+                "Admin API {} {} record(s)",
+                if adding { "added" } else { "deleted" },
+                parsed.len()
+            );
+            Response::json(
+                200,
+                format!(
+                    "{{\"changed\":{changed},\"serial\":{}}}",
+                    config.records.serial()
+                ),
+            )
+        }
+        Err(conflict) => Response::text(409, format!("{conflict}\n")),
+    }
+}
+
+/// Replace the settings named in the body, one `NAME=value` line each, as
+/// if the server had been started with them. A setting left out is unset,
+/// just like a missing environment variable; nothing changes unless every
+/// setting is valid.
+fn reload(request: &Request, state: &HttpState) -> Response {
+    let config = &state.config;
+    let mut values = Vec::new();
+    for line in request.body.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, value)) = line.split_once('=') else {
+            return Response::text(400, format!("{line}: expected NAME=value\n"));
+        };
+        let name = name.trim();
+        if !RELOADABLE.contains(&name) {
+            return Response::text(400, format!("{name}: can't be reloaded\n"));
+        }
+        values.push((name, value.trim()));
+    }
+
+    let var = |name: &str| {
+        values
+            .iter()
+            .find(|(set, _)| *set == name)
+            .map(|(_, value)| value.to_string())
+    };
+    let reloadable = match Reloadable::parse(
+        var,
+        &config.glue_name,
+        config.rate_limit_ipv4_prefix,
+        config.rate_limit_ipv6_prefix,
+    ) {
+        Ok(reloadable) => reloadable,
+        Err(e) => return Response::text(400, format!("{e}\n")),
+    };
+    let fixed = reloadable.fixed.len();
+    let changed = config
+        .records
+        .replace_fixed(reloadable.fixed, reloadable.caa);
+    state
+        .rate_limiter
+        .set_overrides(reloadable.rate_limit_overrides);
+    info!("Admin API reloaded {fixed} fixed record(s) and the rate limit overrides");
+    Response::json(
+        200,
+        format!(
+            "{{\"changed\":{changed},\"serial\":{}}}",
+            config.records.serial()
+        ),
+    )
+}
+
+/// Resolve `name` and `type` through the same code path as a UDP query from
+/// localhost, and return the response decoded.
+fn query(request: &Request, config: &Config) -> Response {
+    let Some(name) = param(&request.query, "name") else {
+        return Response::text(400, "Missing name\n");
+    };
+    let qtype = param(&request.query, "type").unwrap_or_else(|| "A".to_string());
+    let Some(qtype) = qtype_number(&qtype) else {
+        return Response::text(400, format!("Unknown type {qtype}\n"));
+    };
+
+    let mut query = vec![0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+    query.extend(encode_domain_name(name.trim_end_matches('.')));
+    query.extend(&qtype.to_be_bytes());
+    query.extend(&[0x00, 0x01]); // Class IN
+    let source = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let resolution = handle_query_internal(&query, source, config);
+
+    let Some(message) = Message::parse(&resolution.response) else {
+        return Response::text(500, "Unparsable response\n");
+    };
+    let section = |records: &[Record]| {
+        json_list(records.iter().map(|record| {
+            format!(
+                "{{\"name\":{},\"type\":{},\"ttl\":{},\"data\":{}}}",
+                json_string(&format!("{}.", record.name)),
+                json_string(&qtype_name(record.rtype)),
+                record.ttl,
+                json_string(&records::rdata_text(record.rtype, &record.rdata))
+            )
+        }))
+    };
+    Response::json(
+        200,
+        format!(
+            "{{\"qname\":{},\"qtype\":{},\"rcode\":{},\"outcome\":{},\"answer\":{},\"authority\":{}}}",
+            json_string(&name),
+            json_string(&qtype_name(qtype)),
+            json_string(&rcode_name(resolution.rcode().unwrap_or(0))),
+            json_string(&format!("{:?}", resolution.outcome)),
+            section(&message.answers),
+            section(&message.authority)
+        ),
+    )
+}
+
+fn rate_limits_json(state: &HttpState, top: usize) -> String {
+    let limiter = &state.rate_limiter;
+    let clients = limiter.top_clients(top).into_iter().map(|usage| {
+        format!(
+            "{{\"client\":{},\"used\":{},\"limit\":{}}}",
+            json_string(&format!("{}/{}", usage.client, usage.prefix_len)),
+            usage.used,
+            usage.limit
+        )
+    });
+    format!(
+        "{{\"enabled\":{},\"tracked_clients\":{},\"evictions\":{},\"tcp_connections\":{},\"top\":{}}}",
+        limiter.is_enabled(),
+        limiter.tracked_clients(),
+        limiter.evictions(),
+        state.connections.open_connections(),
+        json_list(clients)
+    )
+}
+
+/// The settings in effect, as JSON. Secrets (cookie secret, TSIG secrets,
+/// API tokens) are left out.
+fn config_json(state: &HttpState) -> String {
+    let config = &state.config;
+    let text = |value: &str| json_string(value);
+    let debug = |value: &dyn std::fmt::Debug| json_string(&format!("{value:?}"));
+    let networks = |networks: &[crate::cidr::Cidr]| {
+        json_list(networks.iter().map(|network| text(&network.to_string())))
+    };
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+
+    let fields = [
+        ("glue_name", text(&config.glue_name)),
+        ("glue_ip", text(&config.glue_ip.to_string())),
+        ("soa_name", text(&config.soa_name)),
+        ("hostmaster", text(&config.hostmaster)),
+        ("serial", config.records.serial().to_string()),
+        ("refresh", config.refresh.to_string()),
+        ("retry", config.retry.to_string()),
+        ("expire", config.expire.to_string()),
+        ("minimum", config.minimum.to_string()),
+        ("version", text(&config.version)),
+        (
+            "server_version",
+            optional(config.server_version.as_deref().map(text)),
+        ),
+        ("server_id", optional(config.server_id.as_deref().map(text))),
+        ("rate_limit_seconds", config.rate_limit_seconds.to_string()),
+        (
+            "rate_limit_requests",
+            config.rate_limit_requests.to_string(),
+        ),
+        ("rate_limit_algorithm", debug(&config.rate_limit_algorithm)),
+        (
+            "rate_limit_ipv4_prefix",
+            config.rate_limit_ipv4_prefix.to_string(),
+        ),
+        (
+            "rate_limit_ipv6_prefix",
+            config.rate_limit_ipv6_prefix.to_string(),
+        ),
+        (
+            "rate_limit_overrides",
+            json_list(
+                state
+                    .rate_limiter
+                    .overrides()
+                    .iter()
+                    .map(|(network, limit)| text(&format!("{network} {limit:?}"))),
+            ),
+        ),
+        (
+            "rate_limit_max_clients",
+            config.rate_limit_max_clients.to_string(),
+        ),
+        ("rrl_limits", debug(&config.rrl_limits)),
+        ("rrl_slip", config.rrl_slip.to_string()),
+        ("rrl_ipv4_prefix", config.rrl_ipv4_prefix.to_string()),
+        ("rrl_ipv6_prefix", config.rrl_ipv6_prefix.to_string()),
+        (
+            "dnssec",
+            optional(config.dnssec.as_ref().map(|key| {
+                format!(
+                    "{{\"algorithm\":{},\"key_tag\":{}}}",
+                    debug(&key.algorithm()),
+                    key.key_tag()
+                )
+            })),
+        ),
+        ("any_mode", debug(&config.any_mode)),
+        ("any_udp_truncate", config.any_udp_truncate.to_string()),
+        ("axfr_allow", networks(&config.axfr_allow)),
+        ("axfr_require_tsig", config.axfr_require_tsig.to_string()),
+        (
+            "tsig_keys",
+            json_list(
+                config
+                    .tsig_keys
+                    .iter()
+                    .map(|key| text(&format!("{}:{}", key.algorithm.name(), key.name))),
+            ),
+        ),
+        (
+            "records_file",
+            optional(
+                config
+                    .records
+                    .path()
+                    .map(|path| text(&path.display().to_string())),
+            ),
+        ),
+        ("update_allow", networks(&config.update_allow)),
+        (
+            "update_require_tsig",
+            config.update_require_tsig.to_string(),
+        ),
+        (
+            "caa",
+            json_list(
+                config
+                    .records
+                    .caa()
+                    .iter()
+                    .map(|caa| text(&records::rdata_text(TYPE_CAA, &caa.rdata()))),
+            ),
+        ),
+        (
+            "synthesize_types",
+            json_list(
+                config
+                    .synthesize_types
+                    .iter()
+                    .map(|&rtype| text(&qtype_name(rtype))),
+            ),
+        ),
+        (
+            "synthesize_alpn",
+            json_list(config.synthesize_alpn.iter().map(|id| text(id))),
+        ),
+        ("acme_api", config.acme_api_token.is_some().to_string()),
+        (
+            "cookie_rate_limit_exempt",
+            config.cookie_rate_limit_exempt.to_string(),
+        ),
+        ("cookie_badcookie", config.cookie_badcookie.to_string()),
+        (
+            "tcp_max_connections_per_client",
+            config.tcp_max_connections_per_client.to_string(),
+        ),
+        (
+            "tcp_max_connections",
+            config.tcp_max_connections.to_string(),
+        ),
+        ("tcp_idle_timeout", config.tcp_idle_timeout.to_string()),
+        (
+            "http_listen",
+            optional(config.http_listen.map(|addr| text(&addr.to_string()))),
+        ),
+        (
+            "query_log",
+            optional(config.query_log.as_ref().map(|log| debug(log))),
+        ),
+        (
+            "query_log_max_bytes",
+            config.query_log_max_bytes.to_string(),
+        ),
+        (
+            "query_log_max_files",
+            config.query_log_max_files.to_string(),
+        ),
+        (
+            "dnstap",
+            optional(config.dnstap.as_ref().map(|output| debug(output))),
+        ),
+        ("dnstap_identity", text(&config.dnstap_identity)),
+    ];
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| format!("{}:{value}", json_string(name)))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn json_list(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+/// The value of `key` in a query string, percent-decoded.
+fn param(query: &str, key: &str) -> Option<String> {
+    let value = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)?
+        .1;
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::Health;
+    use crate::metrics::Metrics;
+    use crate::rate_limit::RateLimiter;
+    use crate::tcp::ConnectionLimiter;
+
+    fn state() -> HttpState {
+        let mut config = Config::for_tests("ns.example.com");
+        config.records = records::Records::new(1);
+        config.admin_api_token = Some("admin".to_string());
+        config.acme_api_token = Some("acme".to_string());
+        HttpState {
+            config,
+            metrics: Metrics::new(),
+            rate_limiter: RateLimiter::new(60, 10),
+            connections: ConnectionLimiter::new(0, 0),
+            health: Health::new(),
+        }
+    }
+
+    fn request(method: &str, target: &str, body: &str) -> Request {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            authorization: Some("Bearer admin".to_string()),
+            body: body.to_string(),
+        }
+    }
+
+    fn call(state: &HttpState, method: &str, target: &str, body: &str) -> Response {
+        let request = request(method, target, body);
+        route(&request.path["/admin/".len()..], &request, state)
+    }
+
+    #[test]
+    fn admin_api_needs_its_token() {
+        let mut state = state();
+        let mut acme_token = request("GET", "/admin/config", "");
+        acme_token.authorization = Some("Bearer acme".to_string());
+        assert_eq!(route("config", &acme_token, &state).status, 401);
+
+        let config = call(&state, "GET", "/admin/config", "");
+        assert_eq!(config.status, 200);
+        assert!(config.body.contains("\"glue_name\":\"ns.example.com\""));
+        assert!(!config.body.contains("admin") && !config.body.contains("acme\""));
+        assert_eq!(call(&state, "DELETE", "/admin/config", "").status, 405);
+        assert_eq!(call(&state, "GET", "/admin/nope", "").status, 404);
+
+        state.config.admin_api_token = None;
+        assert_eq!(call(&state, "GET", "/admin/config", "").status, 404);
+    }
+
+    #[test]
+    fn records_are_changed_and_queried() {
+        let state = state();
+        let add = "www.ns.example.com 60 A 192.0.2.1\nwww.ns.example.com 60 TXT hello\n";
+        let added = call(&state, "POST", "/admin/records", add);
+        assert_eq!(added.body, r#"{"changed":true,"serial":2}"#);
+        assert_eq!(state.config.records.all().len(), 2);
+
+        let cname = "www.ns.example.com CNAME other.example.org";
+        assert_eq!(call(&state, "POST", "/admin/records", cname).status, 409);
+        for apex in [
+            "ns.example.com CNAME other.example.org",
+            "ns.example.com A 192.0.2.1",
+            "ns.example.com TYPE99 \\# 2 abcd",
+        ] {
+            let refused = call(&state, "POST", "/admin/records", apex);
+            assert_eq!(refused.status, 409);
+            assert!(refused.body.contains("apex"));
+        }
+        let outside = "www.example.org A 192.0.2.1";
+        assert_eq!(call(&state, "POST", "/admin/records", outside).status, 400);
+
+        let answer = call(
+            &state,
+            "GET",
+            "/admin/query?name=WWW.ns.example.com.&type=a",
+            "",
+        );
+        assert_eq!(
+            answer.body,
+            r#"{"qname":"WWW.ns.example.com.","qtype":"A","rcode":"NOERROR","outcome":"Static","answer":[{"name":"WWW.ns.example.com.","type":"A","ttl":60,"data":"192.0.2.1"}],"authority":[]}"#
+        );
+
+        let deleted = call(&state, "DELETE", "/admin/records", add);
+        assert_eq!(deleted.body, r#"{"changed":true,"serial":3}"#);
+        assert!(state.config.records.all().is_empty());
+        // Without a file there is nothing to reload from.
+        assert_eq!(
+            call(&state, "POST", "/admin/records/reload", "").status,
+            409
+        );
+    }
+
+    #[test]
+    fn settings_are_reloaded() {
+        let state = state();
+        let settings = "STATIC_RECORDS=@ MX 10 mail.example.com.\n\
+            CAA_RECORDS=0 issue \"letsencrypt.org\"\n\
+            RATE_LIMIT_OVERRIDES=192.0.2.0/24=exempt\n";
+        let reloaded = call(&state, "POST", "/admin/reload", settings);
+        assert_eq!(reloaded.body, r#"{"changed":true,"serial":2}"#);
+        assert_eq!(state.config.records.at("ns.example.com").len(), 1);
+        assert_eq!(state.config.records.caa().len(), 1);
+        for _ in 0..20 {
+            assert!(state.rate_limiter.check("192.0.2.1".parse().unwrap()));
+        }
+        let config = call(&state, "GET", "/admin/config", "").body;
+        assert!(config.contains("\"rate_limit_overrides\":[\"192.0.2.0/24 Exempt\"]"));
+
+        // The same settings change nothing; invalid ones are refused whole.
+        let again = call(&state, "POST", "/admin/reload", settings);
+        assert_eq!(again.body, r#"{"changed":false,"serial":2}"#);
+        for invalid in [
+            "GLUE_IP=192.0.2.1",
+            "CAA_RECORDS",
+            "STATIC_RECORDS=@ CNAME x.",
+        ] {
+            assert_eq!(call(&state, "POST", "/admin/reload", invalid).status, 400);
+        }
+        assert_eq!(state.config.records.caa().len(), 1);
+
+        // Settings left out are unset.
+        let cleared = call(&state, "POST", "/admin/reload", "");
+        assert_eq!(cleared.body, r#"{"changed":true,"serial":3}"#);
+        assert!(state.config.records.all().is_empty());
+        assert!(state.rate_limiter.overrides().is_empty());
+    }
+
+    #[test]
+    fn rate_limits_and_challenges_are_listed() {
+        let state = state();
+        state.rate_limiter.check("192.0.2.1".parse().unwrap());
+        let limits = call(&state, "GET", "/admin/rate-limits?top=5", "");
+        assert_eq!(
+            limits.body,
+            r#"{"enabled":true,"tracked_clients":1,"evictions":0,"tcp_connections":0,"top":[{"client":"192.0.2.1/32","used":1,"limit":10}]}"#
+        );
+
+        let present = r#"{"fqdn":"_acme-challenge.ns.example.com.","value":"token"}"#;
+        assert_eq!(
+            call(&state, "POST", "/admin/acme/present", present).status,
+            200
+        );
+        assert_eq!(
+            call(&state, "GET", "/admin/acme", "").body,
+            r#"[{"fqdn":"_acme-challenge.ns.example.com.","values":["token"]}]"#
+        );
+    }
+
+    #[test]
+    fn query_parameters_are_decoded() {
+        assert_eq!(param("a=1&name=x%2Ey+z", "name").as_deref(), Some("x.y z"));
+        assert_eq!(param("a=1", "name"), None);
+        assert_eq!(param("name=%zz", "name"), None);
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 network such as `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    network: IpAddr,
+    len: u8,
+}
+
+impl Cidr {
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.network.is_ipv4()
+    }
+
+    /// True if `ip` is inside this network. IPv4-mapped IPv6 addresses are
+    /// matched against IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if ip.is_ipv4() != self.network.is_ipv4() {
+            return false;
+        }
+        client_prefix(ip, self.len, self.len) == self.network
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parse `address/len`; a bare address is a single-host network.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, len) = match s.split_once('/') {
+            Some((address, len)) => (address, Some(len)),
+            None => (s, None),
+        };
+        let ip: IpAddr = address
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid address in {s:?}"))?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(|| format!("invalid prefix length in {s:?}"))?,
+            None => max,
+        };
+        Ok(Self {
+            network: client_prefix(ip, len, len),
+            len,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.len)
+    }
+}
+
+/// Parse a comma-separated list of networks, ignoring empty entries.
+pub fn parse_list(s: &str) -> Result<Vec<Cidr>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Zero all but the first `ipv4_prefix` / `ipv6_prefix` bits of `ip`, so
+/// every address in the same network maps to one key. IPv4-mapped IPv6
+/// addresses (as seen on the dual-stack socket) are treated as IPv4.
+pub fn client_prefix(ip: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(ipv4_prefix.min(32)))
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(ipv6_prefix.min(128)))
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefixes_are_masked() {
+        let v4 = ip("192.0.2.77");
+        assert_eq!(client_prefix(v4, 24, 56), ip("192.0.2.0"));
+        assert_eq!(client_prefix(v4, 32, 56), v4);
+        assert_eq!(client_prefix(v4, 0, 56), ip("0.0.0.0"));
+
+        let mapped = ip("::ffff:192.0.2.77");
+        assert_eq!(client_prefix(mapped, 24, 56), ip("192.0.2.0"));
+
+        let v6 = ip("2001:db8:aaaa:bbcc:1:2:3:4");
+        assert_eq!(client_prefix(v6, 24, 56), ip("2001:db8:aaaa:bb00::"));
+        assert_eq!(client_prefix(v6, 24, 128), v6);
+    }
+
+    #[test]
+    fn networks_parse_and_match() {
+        let net: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert!(net.contains(ip("10.255.0.1")));
+        assert!(net.contains(ip("::ffff:10.0.0.1")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(!net.contains(ip("::a00:1")));
+
+        let host: Cidr = "2001:db8::1".parse().unwrap();
+        assert_eq!(host.prefix_len(), 128);
+        assert!(host.contains(ip("2001:db8::1")));
+        assert!(!host.contains(ip("2001:db8::2")));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn lists_parse() {
+        let list = parse_list(" 10.0.0.0/8, ,192.0.2.1 ").unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[1].to_string(), "192.0.2.1/32");
+        assert!(parse_list("10.0.0.0/8,bogus").is_err());
+        assert!(parse_list("").unwrap().is_empty());
+    }
+}
@@ -1,5 +1,19 @@
+use crate::acme::Challenges;
+use crate::cidr::{self, Cidr};
+use crate::dns::cookie;
+use crate::dns::dnssec::{self, SigningKey};
+use crate::dns::message::Record;
+use crate::dns::query::AnyMode;
+use crate::dns::records::{self, Records};
+use crate::dns::response::Caa;
+use crate::dns::services;
+use crate::dns::tsig;
+use crate::dnstap::Output as DnstapOutput;
+use crate::query_log::Destination;
+use crate::rate_limit::{self, Algorithm, Override};
+use crate::rrl::Limits as RrlLimits;
 use std::env;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
 
 #[derive(Clone)]
 pub struct Config {
@@ -7,62 +21,375 @@ pub struct Config {
     pub glue_ip: Ipv4Addr,
     pub soa_name: String,
     pub hostmaster: String,
-    pub serial: u32,
     pub refresh: u32,
     pub retry: u32,
     pub expire: u32,
     pub minimum: u32,
     pub version: String,
+    pub server_version: Option<String>,
+    pub server_id: Option<String>,
     pub rate_limit_seconds: u64,
     pub rate_limit_requests: u32,
+    pub rate_limit_algorithm: Algorithm,
+    pub rate_limit_ipv4_prefix: u8,
+    pub rate_limit_ipv6_prefix: u8,
+    pub rate_limit_overrides: Vec<(Cidr, Override)>,
+    pub rate_limit_max_clients: usize,
+    pub rrl_limits: RrlLimits,
+    pub rrl_slip: u32,
+    pub rrl_ipv4_prefix: u8,
+    pub rrl_ipv6_prefix: u8,
+    pub dnssec: Option<SigningKey>,
+    pub any_mode: AnyMode,
+    pub any_udp_truncate: bool,
+    pub axfr_allow: Vec<Cidr>,
+    pub axfr_require_tsig: bool,
+    pub tsig_keys: Vec<tsig::Key>,
+    pub records: Records,
+    pub update_allow: Vec<Cidr>,
+    pub update_require_tsig: bool,
+    pub synthesize_types: Vec<u16>,
+    pub synthesize_alpn: Vec<String>,
+    pub challenges: Challenges,
+    pub acme_api_token: Option<String>,
+    pub admin_api_token: Option<String>,
+    pub cookie_secret: [u8; 16],
+    pub cookie_rate_limit_exempt: bool,
+    pub cookie_badcookie: bool,
+    pub tcp_max_connections_per_client: usize,
+    pub tcp_max_connections: usize,
+    pub tcp_idle_timeout: u64,
+    pub http_listen: Option<SocketAddr>,
+    pub query_log: Option<Destination>,
+    pub query_log_max_bytes: u64,
+    pub query_log_max_files: u32,
+    pub dnstap: Option<DnstapOutput>,
+    pub dnstap_identity: String,
 }
 
 impl Config {
     pub fn from_env() -> Self {
+        Self::from_vars(|name| env::var(name))
+    }
+
+    /// Defaults for everything but `GLUE_NAME`, whatever the environment
+    /// the tests run in.
+    #[cfg(test)]
+    pub fn for_tests(glue_name: &str) -> Self {
+        Self::from_vars(|name| match name {
+            "GLUE_NAME" => Ok(glue_name.to_string()),
+            _ => Err(env::VarError::NotPresent),
+        })
+    }
+
+    /// Read the settings with `var`, the environment outside of tests.
+    fn from_vars(var: impl Fn(&str) -> Result<String, env::VarError>) -> Self {
         // Baked in at compile time from Cargo.toml; never falls back to "unknown".
         let version = env!("CARGO_PKG_VERSION").to_string();
 
+        // Response rate limiting is off unless RRL_RESPONSES_PER_SECOND is set;
+        // the other kinds default to the same limit, as in BIND.
+        let rrl_responses: u32 = var("RRL_RESPONSES_PER_SECOND")
+            .unwrap_or_else(|_| "0".to_string())
+            .parse()
+            .expect("Invalid RRL_RESPONSES_PER_SECOND");
+        let rrl_limit = |name: &str| -> u32 {
+            var(name)
+                .map(|value| value.parse().unwrap_or_else(|_| panic!("Invalid {name}")))
+                .unwrap_or(rrl_responses)
+        };
+
+        let glue_name = var("GLUE_NAME").unwrap_or_else(|_| "ns.example.com".to_string());
+
+        // Signing is on once DNSSEC_KEY_FILE is set; a missing file is
+        // created with a new DNSSEC_ALGORITHM key.
+        let dnssec = var("DNSSEC_KEY_FILE").ok().map(|path| {
+            let algorithm = dnssec::Algorithm::parse(
+                &var("DNSSEC_ALGORITHM").unwrap_or_else(|_| "ecdsap256sha256".to_string()),
+            )
+            .expect("Invalid DNSSEC_ALGORITHM");
+            SigningKey::load_or_generate(path.as_ref(), algorithm, &glue_name)
+                .expect("Invalid DNSSEC_KEY_FILE")
+        });
+
+        // Records added by dynamic updates, with the SOA serial they bump;
+        // kept in memory only unless RECORDS_FILE is set.
+        let serial = var("SERIAL")
+            .unwrap_or_else(|_| "1".to_string())
+            .parse()
+            .expect("Invalid SERIAL");
+        let records = match var("RECORDS_FILE") {
+            Ok(path) => Records::load(path.as_ref(), serial).expect("Invalid RECORDS_FILE"),
+            Err(_) => Records::new(serial),
+        };
+
+        let rate_limit_ipv4_prefix = var("RATE_LIMIT_IPV4_PREFIX")
+            .unwrap_or_else(|_| "32".to_string())
+            .parse()
+            .expect("Invalid RATE_LIMIT_IPV4_PREFIX");
+        let rate_limit_ipv6_prefix = var("RATE_LIMIT_IPV6_PREFIX")
+            .unwrap_or_else(|_| "128".to_string())
+            .parse()
+            .expect("Invalid RATE_LIMIT_IPV6_PREFIX");
+        // Fixed records, CAA records and rate limit overrides, which the
+        // admin API can replace later.
+        let reloadable = Reloadable::parse(
+            |name| var(name).ok(),
+            &glue_name,
+            rate_limit_ipv4_prefix,
+            rate_limit_ipv6_prefix,
+        )
+        .unwrap_or_else(|e| panic!("{e}"));
+        let records = records
+            .with_fixed(reloadable.fixed)
+            .with_caa(reloadable.caa);
+
         Self {
-            glue_name: env::var("GLUE_NAME").unwrap_or_else(|_| "ns.example.com".to_string()),
-            glue_ip: env::var("GLUE_IP")
+            glue_name,
+            glue_ip: var("GLUE_IP")
                 .unwrap_or_else(|_| "127.0.0.1".to_string())
                 .parse()
                 .expect("Invalid GLUE_IP"),
-            soa_name: env::var("SOA_NAME").unwrap_or_else(|_| "ns.example.com".to_string()),
-            hostmaster: env::var("HOSTMASTER")
-                .unwrap_or_else(|_| "hostmaster.example.com".to_string()),
-            serial: env::var("SERIAL")
-                .unwrap_or_else(|_| "1".to_string())
-                .parse()
-                .expect("Invalid SERIAL"),
-            refresh: env::var("REFRESH")
+            soa_name: var("SOA_NAME").unwrap_or_else(|_| "ns.example.com".to_string()),
+            hostmaster: var("HOSTMASTER").unwrap_or_else(|_| "hostmaster.example.com".to_string()),
+            refresh: var("REFRESH")
                 .unwrap_or_else(|_| "3600".to_string())
                 .parse()
                 .expect("Invalid REFRESH"),
-            retry: env::var("RETRY")
+            retry: var("RETRY")
                 .unwrap_or_else(|_| "1800".to_string())
                 .parse()
                 .expect("Invalid RETRY"),
-            expire: env::var("EXPIRE")
+            expire: var("EXPIRE")
                 .unwrap_or_else(|_| "604800".to_string())
                 .parse()
                 .expect("Invalid EXPIRE"),
-            minimum: env::var("MINIMUM")
+            minimum: var("MINIMUM")
                 .unwrap_or_else(|_| "3600".to_string())
                 .parse()
                 .expect("Invalid MINIMUM"),
+            // Answered to version queries (`version`, CHAOS `version.bind`);
+            // set it empty to hide the version.
+            server_version: match var("SERVER_VERSION") {
+                Ok(value) => Some(value).filter(|value| !value.is_empty()),
+                Err(_) => Some(format!("RustyAlias v{version}")),
+            },
+            // Which replica answered, for CHAOS `id.server`/`hostname.bind`
+            // and NSID; hidden while unset.
+            server_id: var("SERVER_ID").ok().filter(|id| !id.is_empty()),
             version,
             // Both default to 0 (disabled). Set both to a non-zero value to
             // enable: e.g. RATE_LIMIT_REQUESTS=20 RATE_LIMIT_SECONDS=1 allows
             // up to 20 requests per source IP every 1 second.
-            rate_limit_seconds: env::var("RATE_LIMIT_SECONDS")
+            rate_limit_seconds: var("RATE_LIMIT_SECONDS")
                 .unwrap_or_else(|_| "0".to_string())
                 .parse()
                 .expect("Invalid RATE_LIMIT_SECONDS"),
-            rate_limit_requests: env::var("RATE_LIMIT_REQUESTS")
+            rate_limit_requests: var("RATE_LIMIT_REQUESTS")
                 .unwrap_or_else(|_| "0".to_string())
                 .parse()
                 .expect("Invalid RATE_LIMIT_REQUESTS"),
+            // `token-bucket` refills RATE_LIMIT_REQUESTS tokens per
+            // RATE_LIMIT_SECONDS and lets a client spend up to
+            // RATE_LIMIT_BURST at once (defaults to RATE_LIMIT_REQUESTS).
+            rate_limit_algorithm: Algorithm::parse(
+                &var("RATE_LIMIT_ALGORITHM").unwrap_or_else(|_| "fixed-window".to_string()),
+                var("RATE_LIMIT_BURST")
+                    .unwrap_or_else(|_| "0".to_string())
+                    .parse()
+                    .expect("Invalid RATE_LIMIT_BURST"),
+            )
+            .expect("Invalid RATE_LIMIT_ALGORITHM"),
+            // Default to one quota per address; e.g. 24 and 56 share a quota
+            // across each IPv4 /24 and IPv6 /56.
+            rate_limit_ipv4_prefix,
+            rate_limit_ipv6_prefix,
+            rate_limit_overrides: reloadable.rate_limit_overrides,
+            rate_limit_max_clients: var("RATE_LIMIT_MAX_CLIENTS")
+                .unwrap_or_else(|_| "100000".to_string())
+                .parse()
+                .expect("Invalid RATE_LIMIT_MAX_CLIENTS"),
+            rrl_limits: RrlLimits {
+                answers: rrl_responses,
+                nxdomains: rrl_limit("RRL_NXDOMAINS_PER_SECOND"),
+                refused: rrl_limit("RRL_REFUSED_PER_SECOND"),
+                errors: rrl_limit("RRL_ERRORS_PER_SECOND"),
+            },
+            rrl_slip: var("RRL_SLIP")
+                .unwrap_or_else(|_| "2".to_string())
+                .parse()
+                .expect("Invalid RRL_SLIP"),
+            rrl_ipv4_prefix: var("RRL_IPV4_PREFIX")
+                .unwrap_or_else(|_| "24".to_string())
+                .parse()
+                .expect("Invalid RRL_IPV4_PREFIX"),
+            rrl_ipv6_prefix: var("RRL_IPV6_PREFIX")
+                .unwrap_or_else(|_| "56".to_string())
+                .parse()
+                .expect("Invalid RRL_IPV6_PREFIX"),
+            dnssec,
+            // `minimal` (the default) and `hinfo` answer ANY with one small
+            // RRset; `refused` and `notimp` don't answer it at all.
+            any_mode: AnyMode::parse(&var("ANY_MODE").unwrap_or_else(|_| "minimal".to_string()))
+                .expect("Invalid ANY_MODE"),
+            // Send every ANY over UDP back with TC set, so only clients that
+            // retry over TCP get an answer.
+            any_udp_truncate: var("ANY_UDP_TRUNCATE")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .expect("Invalid ANY_UDP_TRUNCATE"),
+            // Networks allowed to transfer the zone over TCP; AXFR is
+            // refused to everyone while the list is empty.
+            axfr_allow: cidr::parse_list(&var("AXFR_ALLOW").unwrap_or_default())
+                .expect("Invalid AXFR_ALLOW"),
+            // On top of AXFR_ALLOW, only accept transfers signed with one
+            // of TSIG_KEYS.
+            axfr_require_tsig: var("AXFR_REQUIRE_TSIG")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .expect("Invalid AXFR_REQUIRE_TSIG"),
+            tsig_keys: tsig::parse_keys(&var("TSIG_KEYS").unwrap_or_default())
+                .expect("Invalid TSIG_KEYS"),
+            records,
+            // Dynamic updates need both a client in UPDATE_ALLOW and, by
+            // default, a valid TSIG signature.
+            update_allow: cidr::parse_list(&var("UPDATE_ALLOW").unwrap_or_default())
+                .expect("Invalid UPDATE_ALLOW"),
+            update_require_tsig: var("UPDATE_REQUIRE_TSIG")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse()
+                .expect("Invalid UPDATE_REQUIRE_TSIG"),
+            // Record types synthesized for encoded names on top of A and
+            // AAAA; none by default.
+            synthesize_types: services::parse_types(&var("SYNTHESIZE_TYPES").unwrap_or_default())
+                .expect("Invalid SYNTHESIZE_TYPES"),
+            // Protocols advertised in synthesized HTTPS and SVCB records.
+            synthesize_alpn: services::parse_alpn(
+                &var("SYNTHESIZE_ALPN").unwrap_or_else(|_| "h2,http/1.1".to_string()),
+            )
+            .expect("Invalid SYNTHESIZE_ALPN"),
+            challenges: Challenges::new(),
+            // Bearer token for the ACME challenge API on HTTP_LISTEN; the API
+            // is disabled while it is unset.
+            acme_api_token: var("ACME_API_TOKEN").ok().filter(|token| !token.is_empty()),
+            // Bearer token for the admin API on HTTP_LISTEN; disabled while
+            // unset.
+            admin_api_token: var("ADMIN_API_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            // Set the same 128-bit secret on every replica so they accept
+            // each other's server cookies; random per process otherwise.
+            cookie_secret: var("COOKIE_SECRET")
+                .map(|hex| cookie::parse_secret(&hex).expect("Invalid COOKIE_SECRET"))
+                .unwrap_or_else(|_| cookie::random_secret()),
+            // Clients with a valid server cookie can't be spoofed, so rate
+            // limiting them only hurts real users.
+            cookie_rate_limit_exempt: var("COOKIE_RATE_LIMIT_EXEMPT")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse()
+                .expect("Invalid COOKIE_RATE_LIMIT_EXEMPT"),
+            // Answer rate-limited UDP clients that sent a client cookie with
+            // BADCOOKIE and a fresh server cookie instead of dropping them.
+            cookie_badcookie: var("COOKIE_BADCOOKIE")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse()
+                .expect("Invalid COOKIE_BADCOOKIE"),
+            // Concurrent TCP connections; `0` removes the cap. A connection
+            // that sends nothing for TCP_IDLE_TIMEOUT seconds is closed so a
+            // slow client can't hold its slot forever.
+            tcp_max_connections_per_client: var("TCP_MAX_CONNECTIONS_PER_CLIENT")
+                .unwrap_or_else(|_| "8".to_string())
+                .parse()
+                .expect("Invalid TCP_MAX_CONNECTIONS_PER_CLIENT"),
+            tcp_max_connections: var("TCP_MAX_CONNECTIONS")
+                .unwrap_or_else(|_| "256".to_string())
+                .parse()
+                .expect("Invalid TCP_MAX_CONNECTIONS"),
+            tcp_idle_timeout: var("TCP_IDLE_TIMEOUT")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .expect("Invalid TCP_IDLE_TIMEOUT"),
+            // Unset by default: the HTTP listener (metrics) only starts when
+            // an address such as `0.0.0.0:9153` is given.
+            http_listen: var("HTTP_LISTEN")
+                .ok()
+                .map(|addr| addr.parse().expect("Invalid HTTP_LISTEN")),
+            // `stdout` or a file path; unset disables the structured query log.
+            query_log: var("QUERY_LOG")
+                .ok()
+                .map(|value| Destination::parse(&value)),
+            query_log_max_bytes: var("QUERY_LOG_MAX_BYTES")
+                .unwrap_or_else(|_| "10485760".to_string())
+                .parse()
+                .expect("Invalid QUERY_LOG_MAX_BYTES"),
+            query_log_max_files: var("QUERY_LOG_MAX_FILES")
+                .unwrap_or_else(|_| "5".to_string())
+                .parse()
+                .expect("Invalid QUERY_LOG_MAX_FILES"),
+            // DNSTAP_SOCKET takes precedence if both are set.
+            dnstap: var("DNSTAP_SOCKET")
+                .ok()
+                .map(|path| DnstapOutput::Socket(path.into()))
+                .or_else(|| {
+                    var("DNSTAP_FILE")
+                        .ok()
+                        .map(|path| DnstapOutput::File(path.into()))
+                }),
+            dnstap_identity: var("DNSTAP_IDENTITY").unwrap_or_default(),
         }
     }
 }
+
+/// The settings that can be replaced without a restart, by
+/// `POST /admin/reload`.
+pub struct Reloadable {
+    pub fixed: Vec<Record>,
+    pub caa: Vec<Caa>,
+    pub rate_limit_overrides: Vec<(Cidr, Override)>,
+}
+
+impl Reloadable {
+    /// Parse `STATIC_RECORDS`, `TXT_RECORDS`, `CAA_RECORDS`,
+    /// `RATE_LIMIT_EXEMPT` and `RATE_LIMIT_OVERRIDES`, looked up with `var`.
+    /// Overrides must not split the prefixes that share one quota.
+    pub fn parse(
+        var: impl Fn(&str) -> Option<String>,
+        glue_name: &str,
+        ipv4_prefix: u8,
+        ipv6_prefix: u8,
+    ) -> Result<Self, String> {
+        let var = |name: &str| var(name).unwrap_or_default();
+        // Fixed records answered before synthesis, e.g.
+        // `@ MX 10 mail.example.com.; www.ns.example.com. CNAME lb.example.net.`
+        let mut fixed = records::parse_static(&var("STATIC_RECORDS"), glue_name)
+            .map_err(|e| format!("Invalid STATIC_RECORDS: {e}"))?;
+        // TXT records by name, e.g. `@ "v=spf1 -all"; _dmarc.ns.example.com "v=DMARC1; p=reject"`
+        records::add_txt_records(&mut fixed, &var("TXT_RECORDS"), glue_name)
+            .map_err(|e| format!("Invalid TXT_RECORDS: {e}"))?;
+        // CAA records at the apex, inherited by every name below it:
+        // `0 issue "letsencrypt.org"; 0 iodef "mailto:security@example.com"`
+        let caa = records::parse_caa_records(&var("CAA_RECORDS"))
+            .map_err(|e| format!("Invalid CAA_RECORDS: {e}"))?;
+
+        // RATE_LIMIT_EXEMPT is shorthand for `network=exempt` entries in
+        // RATE_LIMIT_OVERRIDES; both lists are merged.
+        let mut rate_limit_overrides: Vec<(Cidr, Override)> =
+            cidr::parse_list(&var("RATE_LIMIT_EXEMPT"))
+                .map_err(|e| format!("Invalid RATE_LIMIT_EXEMPT: {e}"))?
+                .into_iter()
+                .map(|network| (network, Override::Exempt))
+                .collect();
+        rate_limit_overrides.extend(
+            rate_limit::parse_overrides(&var("RATE_LIMIT_OVERRIDES"))
+                .map_err(|e| format!("Invalid RATE_LIMIT_OVERRIDES: {e}"))?,
+        );
+        rate_limit::check_overrides(&rate_limit_overrides, ipv4_prefix, ipv6_prefix)
+            .map_err(|e| format!("Invalid RATE_LIMIT_EXEMPT or RATE_LIMIT_OVERRIDES: {e}"))?;
+
+        Ok(Self {
+            fixed,
+            caa,
+            rate_limit_overrides,
+        })
+    }
+}
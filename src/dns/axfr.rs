@@ -0,0 +1,224 @@
+use super::message::{encode_name, Message, Record};
+use super::query::{soa_params, Outcome, Question, Resolution};
+use super::records::{TYPE_A, TYPE_CAA, TYPE_NS, TYPE_SOA};
+use super::response::{build_error_response, encode_domain_name, soa_rdata, CAA_TTL, SOA_TTL};
+use crate::config::Config;
+use log::info;
+use std::net::SocketAddr;
+
+pub const TYPE_AXFR: u16 = 252;
+
+/// TTL of the NS and glue records, the same as in referrals.
+const APEX_TTL: u32 = 60;
+
+/// Size a transfer message is kept under, leaving room for a TSIG record
+/// well within the 65535 bytes TCP framing allows.
+const MAX_MESSAGE_SIZE: usize = 16_384;
+
+const RCODE_REFUSED: u8 = 5;
+
+/// The static part of the zone, in transfer order: the apex SOA first,
+/// then the apex NS, glue and CAA records and the static records.
+/// Synthesized names are left out; they can't be listed and the secondary
+/// answers them by forwarding or not at all.
+pub fn zone_records(config: &Config) -> Vec<Record> {
+    let record = |rtype: u16, ttl: u32, rdata: Vec<u8>| Record {
+        name: config.glue_name.trim_end_matches('.').to_string(),
+        rtype,
+        class: 1,
+        ttl,
+        rdata,
+    };
+    let mut records = vec![
+        record(TYPE_SOA, SOA_TTL, soa_rdata(&soa_params(config))),
+        record(TYPE_NS, APEX_TTL, encode_domain_name(&config.glue_name)),
+        record(TYPE_A, APEX_TTL, config.glue_ip.octets().to_vec()),
+    ];
+    let apex = config.records.at(&config.glue_name);
+    if !apex.iter().any(|record| record.rtype == TYPE_CAA) {
+        records.extend(
+            config
+                .records
+                .caa()
+                .iter()
+                .map(|caa| record(TYPE_CAA, CAA_TTL, caa.rdata())),
+        );
+    }
+    records.extend(config.records.all());
+    records
+}
+
+/// Answer an AXFR received over TCP (RFC 5936). The zone's records,
+/// followed by the SOA again, are split across as many messages as it
+/// takes to keep each under [`MAX_MESSAGE_SIZE`] (§2.2): the resolution's
+/// response is the first and the rest are returned alongside it.
+/// Transfers of anything but the zone apex, from clients outside
+/// `AXFR_ALLOW`, and unsigned ones if `AXFR_REQUIRE_TSIG` is set, are
+/// refused. `signed` says whether the request carried a valid TSIG.
+pub fn handle_axfr(
+    query: &[u8],
+    question: Question,
+    src: SocketAddr,
+    signed: bool,
+    config: &Config,
+) -> (Resolution, Vec<Vec<u8>>) {
+    let allowed = config
+        .axfr_allow
+        .iter()
+        .any(|network| network.contains(src.ip()));
+    let apex = question.name.eq_ignore_ascii_case(&config.glue_name);
+
+    let reason = if !allowed {
+        Some("not allowed")
+    } else if config.axfr_require_tsig && !signed {
+        Some("no TSIG")
+    } else if !apex {
+        Some("not the zone")
+    } else {
+        None
+    };
+    let message = Message::parse(&build_error_response(query, 0));
+    let (None, Some(mut message)) = (reason, message) else {
+        info!(
+            "Client [{src}] refused AXFR of [{}] ({})",
+            question.name,
+            reason.unwrap_or("malformed")
+        );
+        let refused = Resolution {
+            response: build_error_response(query, RCODE_REFUSED),
+            question: Some(question),
+            outcome: Outcome::Transfer,
+            answers: Vec::new(),
+        };
+        return (refused, Vec::new());
+    };
+
+    message.header[2] |= 0x04; // AA
+    let mut records = zone_records(config);
+    records.push(records[0].clone());
+    let mut messages = Vec::new();
+    let mut size = message.encode().len();
+    for record in records {
+        // An upper bound: names matching the question are compressed.
+        let record_size = encode_name(&record.name).len() + 10 + record.rdata.len();
+        if size + record_size > MAX_MESSAGE_SIZE && !message.answers.is_empty() {
+            messages.push(message.encode());
+            message.answers.clear();
+            size = message.encode().len();
+        }
+        size += record_size;
+        message.answers.push(record);
+    }
+    messages.push(message.encode());
+
+    info!(
+        "Client [{src}] transferred [{}] in {} messages",
+        question.name,
+        messages.len()
+    );
+    let rest = messages.split_off(1);
+    let resolution = Resolution {
+        response: messages.remove(0),
+        question: Some(question),
+        outcome: Outcome::Transfer,
+        answers: Vec::new(),
+    };
+    (resolution, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn axfr_query(name: &str) -> Vec<u8> {
+        let mut query = vec![0xAB, 0xCD, 0x00, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        query.extend(encode_domain_name(name));
+        query.extend(&TYPE_AXFR.to_be_bytes());
+        query.extend(&[0x00, 0x01]);
+        query
+    }
+
+    fn question(name: &str) -> Question {
+        Question {
+            name: name.to_string(),
+            qtype: TYPE_AXFR,
+            qclass: 1,
+        }
+    }
+
+    #[test]
+    fn transfer_is_bracketed_by_the_soa() {
+        let mut config = Config::for_tests("ns.example.com");
+        config.axfr_allow = vec!["192.0.2.0/24".parse().unwrap()];
+        let src = "192.0.2.53:4000".parse().unwrap();
+
+        let query = axfr_query("NS.example.com");
+        let (resolution, rest) =
+            handle_axfr(&query, question("NS.example.com"), src, false, &config);
+        assert!(rest.is_empty());
+        let message = Message::parse(&resolution.response).unwrap();
+        assert_eq!(message.header[3] & 0x0F, 0);
+        assert_ne!(message.header[2] & 0x04, 0);
+        let types: Vec<u16> = message.answers.iter().map(|r| r.rtype).collect();
+        assert_eq!(types, vec![TYPE_SOA, TYPE_NS, TYPE_A, TYPE_SOA]);
+        assert_eq!(message.answers[2].rdata, config.glue_ip.octets());
+    }
+
+    #[test]
+    fn transfer_is_refused_outside_the_acl() {
+        let mut config = Config::for_tests("ns.example.com");
+        config.axfr_allow = vec!["192.0.2.0/24".parse().unwrap()];
+
+        let outside = "198.51.100.1:4000".parse().unwrap();
+        let query = axfr_query("ns.example.com");
+        let refused = handle_axfr(&query, question("ns.example.com"), outside, true, &config).0;
+        assert_eq!(refused.response[3] & 0x0F, RCODE_REFUSED);
+
+        let inside = "192.0.2.1:4000".parse().unwrap();
+        let query = axfr_query("sub.ns.example.com");
+        let refused = handle_axfr(
+            &query,
+            question("sub.ns.example.com"),
+            inside,
+            false,
+            &config,
+        )
+        .0;
+        assert_eq!(refused.response[3] & 0x0F, RCODE_REFUSED);
+
+        config.axfr_require_tsig = true;
+        let query = axfr_query("ns.example.com");
+        let unsigned = handle_axfr(&query, question("ns.example.com"), inside, false, &config).0;
+        assert_eq!(unsigned.response[3] & 0x0F, RCODE_REFUSED);
+        let signed = handle_axfr(&query, question("ns.example.com"), inside, true, &config).0;
+        assert_eq!(signed.response[3] & 0x0F, 0);
+    }
+
+    #[test]
+    fn large_zones_are_split_across_messages() {
+        let mut config = Config::for_tests("ns.example.com");
+        config.axfr_allow = vec!["192.0.2.0/24".parse().unwrap()];
+        let text = "x".repeat(200);
+        let entries: Vec<String> = (0..500)
+            .map(|i| format!("host{i}.ns.example.com TXT {text}"))
+            .collect();
+        let fixed = crate::dns::records::parse_static(&entries.join(";"), &config.glue_name);
+        config.records = crate::dns::records::Records::new(1).with_fixed(fixed.unwrap());
+        let src = "192.0.2.53:4000".parse().unwrap();
+
+        let query = axfr_query("ns.example.com");
+        let (resolution, rest) =
+            handle_axfr(&query, question("ns.example.com"), src, false, &config);
+        assert!(!rest.is_empty());
+        let mut types = Vec::new();
+        for response in std::iter::once(&resolution.response).chain(&rest) {
+            assert!(response.len() <= MAX_MESSAGE_SIZE);
+            let message = Message::parse(response).unwrap();
+            assert_ne!(message.header[2] & 0x04, 0);
+            types.extend(message.answers.iter().map(|r| r.rtype));
+        }
+        assert_eq!(types.len(), 3 + 500 + 1);
+        assert_eq!(types.first(), Some(&TYPE_SOA));
+        assert_eq!(types.last(), Some(&TYPE_SOA));
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::Read;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How long a server cookie is accepted after it was issued (RFC 9018).
+const LIFETIME: u32 = 3600;
+/// How far in the future a cookie's timestamp may be, for clock skew
+/// between servers sharing a secret.
+const CLOCK_SKEW: u32 = 300;
+
+/// What a query's COOKIE option says about the client (RFC 7873 §5.2).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cookie {
+    /// No COOKIE option, or no EDNS at all.
+    Missing,
+    /// A COOKIE option of impossible length, answered with FORMERR.
+    Malformed,
+    /// A client cookie without a server cookie, or with one this server
+    /// can't verify (another secret, expired, another client address).
+    Unverified([u8; 8]),
+    /// A client cookie with a server cookie this server issued to the same
+    /// address within the last hour. The source address can't be spoofed.
+    Valid([u8; 8]),
+}
+
+impl Cookie {
+    /// Classify the data of a query's COOKIE option.
+    pub fn inspect(option: Option<&[u8]>, secret: &[u8; 16], ip: IpAddr, now: u32) -> Self {
+        let Some(data) = option else {
+            return Cookie::Missing;
+        };
+        // 8 bytes of client cookie, optionally followed by 8 to 32 bytes of
+        // server cookie.
+        if data.len() != 8 && !(16..=40).contains(&data.len()) {
+            return Cookie::Malformed;
+        }
+        let mut client = [0; 8];
+        client.copy_from_slice(&data[..8]);
+
+        let server = &data[8..];
+        if server.len() != 16 || server[0] != 1 {
+            return Cookie::Unverified(client);
+        }
+        let issued = u32::from_be_bytes([server[4], server[5], server[6], server[7]]);
+        let age = now.wrapping_sub(issued);
+        let fresh = age <= LIFETIME || issued.wrapping_sub(now) <= CLOCK_SKEW;
+        if fresh && server_cookie(secret, client, ip, issued)[..] == *server {
+            Cookie::Valid(client)
+        } else {
+            Cookie::Unverified(client)
+        }
+    }
+
+    pub fn client(&self) -> Option<[u8; 8]> {
+        match self {
+            Cookie::Unverified(client) | Cookie::Valid(client) => Some(*client),
+            Cookie::Missing | Cookie::Malformed => None,
+        }
+    }
+}
+
+/// The RFC 9018 server cookie for `client` at `ip`, issued at `timestamp`:
+/// version 1, three reserved bytes, the timestamp and a SipHash-2-4 over all
+/// of that plus the client cookie and address. Servers sharing the secret
+/// accept each other's cookies.
+pub fn server_cookie(secret: &[u8; 16], client: [u8; 8], ip: IpAddr, timestamp: u32) -> [u8; 16] {
+    let mut cookie = [0; 16];
+    cookie[0] = 1;
+    cookie[4..8].copy_from_slice(&timestamp.to_be_bytes());
+
+    let mut input = Vec::with_capacity(32);
+    input.extend(client);
+    input.extend(&cookie[..8]);
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => input.extend(v4.octets()),
+        IpAddr::V6(v6) => input.extend(v6.octets()),
+    }
+    cookie[8..].copy_from_slice(&siphash24(secret, &input).to_le_bytes());
+    cookie
+}
+
+/// COOKIE option data for a response: the client cookie and a fresh server
+/// cookie.
+pub fn response_option(secret: &[u8; 16], client: [u8; 8], ip: IpAddr) -> Vec<u8> {
+    let mut data = client.to_vec();
+    data.extend(server_cookie(secret, client, ip, now()));
+    data
+}
+
+/// Seconds since the epoch, truncated to the 32 bits a cookie carries.
+pub fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as u32)
+}
+
+/// Parse `COOKIE_SECRET`: 32 hex digits.
+pub fn parse_secret(hex: &str) -> Option<[u8; 16]> {
+    if hex.len() != 32 || !hex.is_ascii() {
+        return None;
+    }
+    let mut secret = [0; 16];
+    for (i, byte) in secret.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(secret)
+}
+
+/// A secret for this process only, used when `COOKIE_SECRET` is unset.
+/// Cookies then stop validating on restart and differ between replicas,
+/// which costs clients one extra round trip, nothing more.
+pub fn random_secret() -> [u8; 16] {
+    let mut secret = [0; 16];
+    let from_os =
+        File::open("/dev/urandom").and_then(|mut urandom| urandom.read_exact(&mut secret));
+    if from_os.is_err() {
+        // Randomly keyed std hashers are the next best source.
+        for (i, half) in secret.chunks_exact_mut(8).enumerate() {
+            let hash = RandomState::new().hash_one((i, SystemTime::now()));
+            half.copy_from_slice(&hash.to_le_bytes());
+        }
+    }
+    secret
+}
+
+/// SipHash-2-4 with a 128-bit key, as specified for RFC 9018 cookies.
+fn siphash24(key: &[u8; 16], data: &[u8]) -> u64 {
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    let mut blocks = data.chunks_exact(8);
+    for block in &mut blocks {
+        let m = u64::from_le_bytes(block.try_into().unwrap());
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    }
+    let mut last = [0; 8];
+    last[..blocks.remainder().len()].copy_from_slice(blocks.remainder());
+    last[7] = data.len() as u8;
+    let m = u64::from_le_bytes(last);
+    v[3] ^= m;
+    round(&mut v);
+    round(&mut v);
+    v[0] ^= m;
+
+    v[2] ^= 0xFF;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: [u8; 16] = [
+        0xe5, 0xe9, 0x73, 0xe5, 0xa6, 0xb2, 0xa4, 0x3f, 0x48, 0xe7, 0xdc, 0x84, 0x9e, 0x37, 0xbf,
+        0xcf,
+    ];
+    const CLIENT: [u8; 8] = [0x24, 0x64, 0xc4, 0xab, 0xcf, 0x10, 0xc9, 0x57];
+
+    fn ip() -> IpAddr {
+        "198.51.100.100".parse().unwrap()
+    }
+
+    #[test]
+    fn siphash_reference_vectors() {
+        let key: [u8; 16] = core::array::from_fn(|i| i as u8);
+        assert_eq!(siphash24(&key, &[]), 0x726f_db47_dd0e_0e31);
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash24(&key, &data), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn rfc9018_test_vector() {
+        // RFC 9018 Appendix A.1.
+        let cookie = server_cookie(&SECRET, CLIENT, ip(), 1559731985);
+        assert_eq!(
+            cookie,
+            [
+                0x01, 0x00, 0x00, 0x00, 0x5c, 0xf7, 0x9f, 0x11, 0x1f, 0x81, 0x30, 0xc3, 0xee, 0xe2,
+                0x94, 0x80
+            ]
+        );
+    }
+
+    #[test]
+    fn cookies_are_classified() {
+        let now = 1_700_000_000;
+        let mut option = CLIENT.to_vec();
+        assert_eq!(Cookie::inspect(None, &SECRET, ip(), now), Cookie::Missing);
+        assert_eq!(
+            Cookie::inspect(Some(&option), &SECRET, ip(), now),
+            Cookie::Unverified(CLIENT)
+        );
+        assert_eq!(
+            Cookie::inspect(Some(&option[..5]), &SECRET, ip(), now),
+            Cookie::Malformed
+        );
+
+        option.extend(server_cookie(&SECRET, CLIENT, ip(), now - 60));
+        assert_eq!(
+            Cookie::inspect(Some(&option), &SECRET, ip(), now),
+            Cookie::Valid(CLIENT)
+        );
+
+        // Another client address, another secret, or too old.
+        let other: IpAddr = "198.51.100.101".parse().unwrap();
+        assert_eq!(
+            Cookie::inspect(Some(&option), &SECRET, other, now),
+            Cookie::Unverified(CLIENT)
+        );
+        assert_eq!(
+            Cookie::inspect(Some(&option), &[0; 16], ip(), now),
+            Cookie::Unverified(CLIENT)
+        );
+        assert_eq!(
+            Cookie::inspect(Some(&option), &SECRET, ip(), now + LIFETIME),
+            Cookie::Unverified(CLIENT)
+        );
+    }
+
+    #[test]
+    fn secrets_parse() {
+        assert_eq!(
+            parse_secret("e5e973e5a6b2a43f48e7dc849e37bfcf"),
+            Some(SECRET)
+        );
+        assert_eq!(parse_secret("e5e973e5"), None);
+        assert_eq!(parse_secret("z5e973e5a6b2a43f48e7dc849e37bfcf"), None);
+    }
+}
@@ -0,0 +1,255 @@
+use log::debug;
+
+/// RR type of the EDNS(0) OPT pseudo-record (RFC 6891).
+pub const TYPE_OPT: u16 = 41;
+
+/// EDNS option code of the name server identifier (RFC 5001).
+pub const OPTION_NSID: u16 = 3;
+
+/// EDNS option code of DNS Cookies (RFC 7873).
+pub const OPTION_COOKIE: u16 = 10;
+
+/// UDP payload size advertised in responses, the value recommended since
+/// DNS Flag Day 2020 to avoid IP fragmentation.
+pub const UDP_PAYLOAD_SIZE: u16 = 1232;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The contents of an OPT record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_size: u16,
+    /// Upper eight bits of the 12-bit RCODE; the lower four are in the header.
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// The DO bit: the client wants DNSSEC records.
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    /// An OPT record for a response carrying `options`.
+    pub fn response(options: Vec<EdnsOption>) -> Self {
+        Self {
+            udp_size: UDP_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options,
+        }
+    }
+
+    /// Data of the first option with `code`, if present.
+    pub fn option(&self, code: u16) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|option| option.code == code)
+            .map(|option| option.data.as_slice())
+    }
+}
+
+/// Find and decode the OPT record in the additional section of `message`.
+/// Returns `None` if there is none, if the message can't be walked far
+/// enough to find it, or if it is malformed: see [`has_opt`].
+pub fn parse(message: &[u8]) -> Option<Edns> {
+    match opt_records(message).as_slice() {
+        [(true, rr)] => decode_opt(rr),
+        _ => None,
+    }
+}
+
+/// Whether `message` has any OPT record, even one [`parse`] rejects: one
+/// that doesn't decode, isn't owned by the root, or comes with another
+/// (RFC 6891 §6.1.1).
+pub fn has_opt(message: &[u8]) -> bool {
+    !opt_records(message).is_empty()
+}
+
+/// The OPT records in the additional section, starting at their TYPE field,
+/// and whether each is owned by the root.
+fn opt_records(message: &[u8]) -> Vec<(bool, &[u8])> {
+    let mut opts = Vec::new();
+    if message.len() < 12 {
+        return opts;
+    }
+    let count = |i: usize| usize::from(u16::from_be_bytes([message[i], message[i + 1]]));
+    let (qdcount, ancount, nscount, arcount) = (count(4), count(6), count(8), count(10));
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        let Some(end) = skip_name(message, pos) else {
+            return opts;
+        };
+        pos = end + 4;
+    }
+    for _ in 0..ancount + nscount {
+        let Some(end) = skip_record(message, pos) else {
+            return opts;
+        };
+        pos = end;
+    }
+    for _ in 0..arcount {
+        let (Some(name_end), Some(next)) = (skip_name(message, pos), skip_record(message, pos))
+        else {
+            return opts;
+        };
+        let rr = &message[name_end..next];
+        if u16::from_be_bytes([rr[0], rr[1]]) == TYPE_OPT {
+            opts.push((message[pos] == 0, rr));
+        }
+        pos = next;
+    }
+    opts
+}
+
+/// Decode an OPT record starting at its TYPE field.
+fn decode_opt(rr: &[u8]) -> Option<Edns> {
+    let mut options = Vec::new();
+    let mut rdata = &rr[10..];
+    while !rdata.is_empty() {
+        if rdata.len() < 4 {
+            debug!("Truncated EDNS option");
+            return None;
+        }
+        let code = u16::from_be_bytes([rdata[0], rdata[1]]);
+        let len = usize::from(u16::from_be_bytes([rdata[2], rdata[3]]));
+        let data = rdata.get(4..4 + len)?;
+        options.push(EdnsOption {
+            code,
+            data: data.to_vec(),
+        });
+        rdata = &rdata[4 + len..];
+    }
+
+    Some(Edns {
+        udp_size: u16::from_be_bytes([rr[2], rr[3]]),
+        extended_rcode: rr[4],
+        version: rr[5],
+        dnssec_ok: rr[6] & 0x80 != 0,
+        options,
+    })
+}
+
+/// Position just after the (possibly compressed) name starting at `pos`.
+pub fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xC0 == 0xC0 => {
+                message.get(pos + 1)?;
+                return Some(pos + 2);
+            }
+            len if len & 0xC0 == 0 => pos += 1 + usize::from(len),
+            _ => return None,
+        }
+    }
+}
+
+/// Position just after the resource record starting at `pos`.
+pub fn skip_record(message: &[u8], pos: usize) -> Option<usize> {
+    let pos = skip_name(message, pos)?;
+    let header = message.get(pos..pos + 10)?;
+    let rdlength = usize::from(u16::from_be_bytes([header[8], header[9]]));
+    let end = pos + 10 + rdlength;
+    (end <= message.len()).then_some(end)
+}
+
+/// Append `edns` as an OPT record to the additional section of `response`
+/// and bump ARCOUNT. The additional section must be the last thing in the
+/// message, which holds for every response builder.
+pub fn append(response: &mut Vec<u8>, edns: &Edns) {
+    let rdata_len: usize = edns.options.iter().map(|o| 4 + o.data.len()).sum();
+
+    response.push(0); // Root name
+    response.extend(&TYPE_OPT.to_be_bytes());
+    response.extend(&edns.udp_size.to_be_bytes()); // CLASS: UDP payload size
+    response.push(edns.extended_rcode);
+    response.push(edns.version);
+    response.push(if edns.dnssec_ok { 0x80 } else { 0x00 }); // DO + Z
+    response.push(0x00);
+    response.extend(&(rdata_len as u16).to_be_bytes());
+    for option in &edns.options {
+        response.extend(&option.code.to_be_bytes());
+        response.extend(&(option.data.len() as u16).to_be_bytes());
+        response.extend(&option.data);
+    }
+
+    let arcount = u16::from_be_bytes([response[10], response[11]]) + 1;
+    response[10..12].copy_from_slice(&arcount.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::response::encode_domain_name;
+
+    fn query_with_opt(rdata: &[u8]) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 1];
+        query.extend(encode_domain_name("a.ns.example.com"));
+        query.extend(&[0x00, 0x01, 0x00, 0x01]);
+        query.extend(&[0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x80, 0x00]);
+        query.extend(&(rdata.len() as u16).to_be_bytes());
+        query.extend(rdata);
+        query
+    }
+
+    #[test]
+    fn opt_is_parsed() {
+        let query = query_with_opt(&[0x00, 0x0A, 0x00, 0x08, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let edns = parse(&query).unwrap();
+        assert_eq!(edns.udp_size, 4096);
+        assert_eq!(edns.version, 0);
+        assert!(edns.dnssec_ok);
+        assert_eq!(
+            edns.option(OPTION_COOKIE),
+            Some(&[1, 2, 3, 4, 5, 6, 7, 8][..])
+        );
+        assert_eq!(edns.option(3), None);
+    }
+
+    #[test]
+    fn missing_or_broken_opt() {
+        let query = query_with_opt(&[]);
+        let mut without = query[..query.len() - 11].to_vec();
+        without[11] = 0;
+        assert_eq!(parse(&without), None);
+
+        assert!(!has_opt(&without));
+
+        // Option length runs past the end of RDATA.
+        let truncated = query_with_opt(&[0x00, 0x0A, 0x00, 0x09, 1]);
+        assert_eq!(parse(&truncated), None);
+        assert!(has_opt(&truncated));
+
+        // Two OPT records.
+        let mut twice = query.clone();
+        twice.extend_from_slice(&query[query.len() - 11..]);
+        twice[11] = 2;
+        assert_eq!(parse(&twice), None);
+        assert!(has_opt(&twice));
+    }
+
+    #[test]
+    fn appended_opt_round_trips() {
+        let mut response = query_with_opt(&[]);
+        let opt_start = response.len() - 11;
+        response.truncate(opt_start);
+        response[11] = 0;
+
+        let edns = Edns {
+            extended_rcode: 1,
+            ..Edns::response(vec![EdnsOption {
+                code: OPTION_COOKIE,
+                data: vec![9; 24],
+            }])
+        };
+        append(&mut response, &edns);
+        assert_eq!(response[11], 1);
+        assert_eq!(parse(&response), Some(edns));
+    }
+}
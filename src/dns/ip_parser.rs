@@ -2,7 +2,31 @@ use log::debug;
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 
-pub fn interpret_ip(domain: &str) -> Option<(Option<Ipv4Addr>, Option<Ipv6Addr>)> {
+/// The notation an address was written in, as recognised by [`interpret_ip_encoding`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Dot,
+    Dash,
+    Hex,
+    Ipv6,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Dot => "dot",
+            Encoding::Dash => "dash",
+            Encoding::Hex => "hex",
+            Encoding::Ipv6 => "ipv6",
+        }
+    }
+}
+
+/// The address(es) decoded from a name: at most one of each family.
+pub type ParsedIp = (Option<Ipv4Addr>, Option<Ipv6Addr>);
+
+/// Decode the address embedded in `domain`, reporting which notation matched.
+pub fn interpret_ip_encoding(domain: &str) -> Option<(Encoding, ParsedIp)> {
     let parts: Vec<&str> = domain.split('.').collect();
     debug!("Domain parts: {parts:?}");
 
@@ -10,7 +34,7 @@ pub fn interpret_ip(domain: &str) -> Option<(Option<Ipv4Addr>, Option<Ipv6Addr>)
         if part.len() == 8 {
             if let Ok(ip) = parse_hexadecimal_ip(part) {
                 debug!("Parsed hexadecimal IPv4: {ip}");
-                return Some((Some(ip), None));
+                return Some((Encoding::Hex, (Some(ip), None)));
             }
         }
     }
@@ -20,7 +44,7 @@ pub fn interpret_ip(domain: &str) -> Option<(Option<Ipv4Addr>, Option<Ipv6Addr>)
             let potential_ip: String = parts[i..i + 4].join(".");
             if let Ok(ip) = Ipv4Addr::from_str(&potential_ip) {
                 debug!("Parsed dotted decimal IPv4: {ip}");
-                return Some((Some(ip), None));
+                return Some((Encoding::Dot, (Some(ip), None)));
             }
         }
     }
@@ -28,14 +52,14 @@ pub fn interpret_ip(domain: &str) -> Option<(Option<Ipv4Addr>, Option<Ipv6Addr>)
     for part in &parts {
         if let Some(ipv6) = parse_hyphenated_ipv6(part) {
             debug!("Parsed hyphenated IPv6: {ipv6}");
-            return Some((None, Some(ipv6)));
+            return Some((Encoding::Ipv6, (None, Some(ipv6))));
         }
     }
 
     for part in &parts {
-        if let Some(ip) = parse_hyphenated_ip(part) {
+        if let Some((encoding, ip)) = parse_hyphenated_ip(part) {
             debug!("Parsed hyphenated IPv4: {ip}");
-            return Some((Some(ip), None));
+            return Some((encoding, (Some(ip), None)));
         }
     }
 
@@ -49,27 +73,27 @@ pub fn parse_hyphenated_ipv6(s: &str) -> Option<Ipv6Addr> {
     Ipv6Addr::from_str(&s).ok()
 }
 
-pub fn parse_hyphenated_ip(s: &str) -> Option<Ipv4Addr> {
+pub fn parse_hyphenated_ip(s: &str) -> Option<(Encoding, Ipv4Addr)> {
     let parts: Vec<&str> = s.split('-').collect();
     debug!("Hyphenated IP parts: {parts:?}");
     if parts.len() == 4 && parts.iter().all(|&p| p.parse::<u8>().is_ok()) {
         let ip_str: String = parts.join(".");
         if let Ok(ip) = Ipv4Addr::from_str(&ip_str) {
-            return Some(ip);
+            return Some((Encoding::Dash, ip));
         }
     } else if parts.len() > 4 {
         for i in 0..=parts.len() - 4 {
             if parts[i..i + 4].iter().all(|&p| p.parse::<u8>().is_ok()) {
                 let ip_str = parts[i..i + 4].join(".");
                 if let Ok(ip) = Ipv4Addr::from_str(&ip_str) {
-                    return Some(ip);
+                    return Some((Encoding::Dash, ip));
                 }
             }
         }
     }
     for part in parts.iter().filter(|&&p| p.len() == 8) {
         if let Ok(ip) = parse_hexadecimal_ip(part) {
-            return Some(ip);
+            return Some((Encoding::Hex, ip));
         }
     }
     None
@@ -98,6 +122,10 @@ pub fn parse_hexadecimal_ip(s: &str) -> Result<Ipv4Addr, ()> {
 mod tests {
     use super::*;
 
+    fn interpret_ip(domain: &str) -> Option<ParsedIp> {
+        interpret_ip_encoding(domain).map(|(_, ip)| ip)
+    }
+
     #[test]
     fn test_dot_notation() {
         let cases = [
@@ -160,6 +188,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_encoding_reported() {
+        let cases = [
+            ("app.10.8.0.1.example.com", Encoding::Dot),
+            ("app-116-203-255-68.example.com", Encoding::Dash),
+            ("0a000803.example.com", Encoding::Hex),
+            ("app-c0a801fc.example.com", Encoding::Hex),
+            ("2a04-4e42-200--201.example.com", Encoding::Ipv6),
+        ];
+
+        for (input, expected) in cases {
+            let (encoding, _) = interpret_ip_encoding(input).unwrap();
+            assert_eq!(encoding, expected, "wrong encoding for {input}");
+        }
+    }
+
     #[test]
     fn test_invalid_inputs() {
         let invalid_cases = [
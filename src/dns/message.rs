@@ -0,0 +1,257 @@
+use super::edns::skip_name;
+use super::response::encode_domain_name;
+
+/// A resource record with its RDATA decompressed, so it can be moved to
+/// another message or put in canonical form for signing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Owner name without the trailing dot; the root is the empty string.
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+}
+
+/// A decoded message. The question section is kept as raw bytes: the
+/// response builders echo it verbatim and nothing needs to change it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub header: [u8; 12],
+    pub question: Vec<u8>,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+    pub additional: Vec<Record>,
+}
+
+impl Message {
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        let header: [u8; 12] = message.get(..12)?.try_into().ok()?;
+        let count = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+
+        let mut pos = 12;
+        for _ in 0..count(4) {
+            pos = skip_name(message, pos)? + 4;
+        }
+        let question = message.get(12..pos)?.to_vec();
+
+        let mut sections = [Vec::new(), Vec::new(), Vec::new()];
+        for (section, count) in sections.iter_mut().zip([count(6), count(8), count(10)]) {
+            for _ in 0..count {
+                let (record, next) = read_record(message, pos)?;
+                section.push(record);
+                pos = next;
+            }
+        }
+        let [answers, authority, additional] = sections;
+
+        Some(Self {
+            header,
+            question,
+            answers,
+            authority,
+            additional,
+        })
+    }
+
+    /// Encode the message with updated section counts. Owner names equal to
+    /// the question name are compressed to a pointer to it; everything else
+    /// is written out in full.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(512);
+        out.extend(&self.header[..6]);
+        for section in [&self.answers, &self.authority, &self.additional] {
+            out.extend(&(section.len() as u16).to_be_bytes());
+        }
+        out.extend(&self.question);
+
+        let qname = read_name(&self.question, 0).map(|(name, _)| name);
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authority)
+            .chain(&self.additional)
+        {
+            if qname.as_deref() == Some(record.name.as_str()) {
+                out.extend(&[0xC0, 0x0C]);
+            } else {
+                out.extend(encode_name(&record.name));
+            }
+            out.extend(&record.rtype.to_be_bytes());
+            out.extend(&record.class.to_be_bytes());
+            out.extend(&record.ttl.to_be_bytes());
+            out.extend(&(record.rdata.len() as u16).to_be_bytes());
+            out.extend(&record.rdata);
+        }
+        out
+    }
+}
+
+/// Encode a name as stored in [`Record::name`], handling the root.
+pub fn encode_name(name: &str) -> Vec<u8> {
+    if name.is_empty() {
+        vec![0]
+    } else {
+        encode_domain_name(name)
+    }
+}
+
+/// Read a possibly compressed name at `pos`. Returns the name and the
+/// position just after it in the original message.
+pub fn read_name(message: &[u8], pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut cursor = pos;
+    let mut end = None;
+    // Each pointer must go strictly backwards, which rules out loops.
+    let mut limit = pos;
+    loop {
+        let len = *message.get(cursor)?;
+        match len {
+            0 => {
+                end.get_or_insert(cursor + 1);
+                break;
+            }
+            len if len & 0xC0 == 0xC0 => {
+                let target =
+                    usize::from(u16::from_be_bytes([len & 0x3F, *message.get(cursor + 1)?]));
+                if target >= limit {
+                    return None;
+                }
+                end.get_or_insert(cursor + 2);
+                limit = target;
+                cursor = target;
+            }
+            len if len & 0xC0 == 0 => {
+                let label = message.get(cursor + 1..cursor + 1 + usize::from(len))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                cursor += 1 + usize::from(len);
+            }
+            _ => return None,
+        }
+    }
+    Some((labels.join("."), end?))
+}
+
+fn read_record(message: &[u8], pos: usize) -> Option<(Record, usize)> {
+    let (name, pos) = read_name(message, pos)?;
+    let fixed = message.get(pos..pos + 10)?;
+    let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let class = u16::from_be_bytes([fixed[2], fixed[3]]);
+    let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+    let rdlength = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+    let start = pos + 10;
+    let end = start + rdlength;
+    message.get(start..end)?;
+
+    let rdata = decompress_rdata(message, rtype, start, end)?;
+    Some((
+        Record {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata,
+        },
+        end,
+    ))
+}
+
+/// Offset of the first embedded domain name in RDATA of `rtype`, and how
+/// many names follow back to back, for the types whose RDATA may be
+/// compressed or must be lowercased when signing.
+fn rdata_names(rtype: u16) -> Option<(usize, usize)> {
+    match rtype {
+        2 | 5 | 12 => Some((0, 1)), // NS, CNAME, PTR
+        6 => Some((0, 2)),          // SOA: MNAME, RNAME
+        15 => Some((2, 1)),         // MX: preference, exchange
+        33 => Some((6, 1)),         // SRV: priority, weight, port, target
+        _ => None,
+    }
+}
+
+fn decompress_rdata(message: &[u8], rtype: u16, start: usize, end: usize) -> Option<Vec<u8>> {
+    // Empty RDATA is how UPDATE deletes RRsets, whatever the type.
+    let Some((offset, names)) = rdata_names(rtype).filter(|_| start < end) else {
+        return Some(message[start..end].to_vec());
+    };
+    let mut rdata = message.get(start..start + offset)?.to_vec();
+    let mut pos = start + offset;
+    for _ in 0..names {
+        let (name, next) = read_name(message, pos)?;
+        rdata.extend(encode_name(&name));
+        pos = next;
+    }
+    if pos > end {
+        return None;
+    }
+    rdata.extend(&message[pos..end]);
+    Some(rdata)
+}
+
+/// RDATA in the canonical form of RFC 4034 §6.2: embedded names lowercased.
+/// `rdata` must already be decompressed.
+pub fn canonical_rdata(rtype: u16, rdata: &[u8]) -> Vec<u8> {
+    let mut canonical = rdata.to_vec();
+    let Some((offset, names)) = rdata_names(rtype) else {
+        return canonical;
+    };
+    let mut pos = offset;
+    for _ in 0..names {
+        while let Some(&len) = canonical.get(pos) {
+            if len == 0 {
+                pos += 1;
+                break;
+            }
+            let label_end = (pos + 1 + usize::from(len)).min(canonical.len());
+            canonical[pos + 1..label_end].make_ascii_lowercase();
+            pos = label_end;
+        }
+    }
+    canonical
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_names_are_read() {
+        let mut message = vec![0; 12];
+        message.extend(b"\x03www\x07Example\x03com\x00");
+        message.extend(b"\x04mail\xC0\x10");
+        assert_eq!(
+            read_name(&message, 12),
+            Some(("www.Example.com".to_string(), 29))
+        );
+        assert_eq!(
+            read_name(&message, 29),
+            Some(("mail.Example.com".to_string(), 36))
+        );
+
+        // A pointer to itself.
+        let mut looped = vec![0; 12];
+        looped.extend(b"\xC0\x0C");
+        assert_eq!(read_name(&looped, 12), None);
+    }
+
+    #[test]
+    fn message_round_trips_with_decompressed_rdata() {
+        let mut query = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        query.extend(b"\x02NS\x07example\x03com\x00\x00\x02\x00\x01");
+        // NS RDATA pointing back into the question name.
+        query.extend(b"\xC0\x0C\x00\x02\x00\x01\x00\x00\x0E\x10\x00\x06\x03ns1\xC0\x0F");
+
+        let message = Message::parse(&query).unwrap();
+        assert_eq!(message.answers.len(), 1);
+        let ns = &message.answers[0];
+        assert_eq!(ns.name, "NS.example.com");
+        assert_eq!(ns.rdata, encode_domain_name("ns1.example.com"));
+        assert_eq!(
+            canonical_rdata(2, &encode_domain_name("NS1.Example.com")),
+            encode_domain_name("ns1.example.com")
+        );
+
+        let encoded = message.encode();
+        assert_eq!(Message::parse(&encoded), Some(message));
+    }
+}
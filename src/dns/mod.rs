@@ -1,3 +1,28 @@
+pub mod axfr;
+pub mod cookie;
+pub mod dnssec;
+pub mod edns;
 pub mod ip_parser;
+pub mod message;
 pub mod query;
+pub mod records;
 pub mod response;
+pub mod services;
+pub mod tsig;
+pub mod update;
+
+/// The listener a query arrived on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
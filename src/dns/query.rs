@@ -1,66 +1,225 @@
-use super::ip_parser::interpret_ip;
+use super::axfr::{handle_axfr, TYPE_AXFR};
+use super::cookie::{self, Cookie};
+use super::dnssec::{self, DNSKEY_TTL, TYPE_DNSKEY};
+use super::edns::{self, Edns, EdnsOption, OPTION_COOKIE, OPTION_NSID, UDP_PAYLOAD_SIZE};
+use super::ip_parser::{interpret_ip_encoding, Encoding};
+use super::message::{read_name, Message, Record};
+use super::records::{self, TYPE_A, TYPE_AAAA, TYPE_CAA, TYPE_CNAME, TYPE_NS, TYPE_SOA, TYPE_TXT};
 use super::response::{
-    build_refused_response, build_response, build_soa_response, build_txt_response, SoaParams,
+    build_answer_response, build_caa_response, build_cname_response, build_error_response,
+    build_refused_response, build_response, build_soa_response, build_truncated_response,
+    build_txt_response, soa_rdata, SoaParams, ADDRESS_TTL, SOA_TTL, TXT_TTL,
 };
+use super::services;
+use super::tsig;
+use super::update::{self, handle_update, OPCODE_UPDATE};
+use crate::acme;
 use crate::config::Config;
-use log::{debug, info};
+use crate::rrl::{Action, ResponseRateLimiter};
+use log::{debug, info, warn};
 use std::io::Result as IoResult;
 use std::net::{SocketAddr, UdpSocket};
 use std::str::from_utf8;
 
+/// The question section of a query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// Which branch of the decision tree produced a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Malformed,
+    OutOfZone,
+    Glue,
+    Version,
+    Synthesized(Encoding),
+    Intermediate,
+    /// The zone's DNSKEY RRset at the apex.
+    Dnskey,
+    /// FORMERR or BADVERS for an OPT record we can't work with.
+    BadEdns,
+    /// BADCOOKIE sent to a rate-limited client instead of an answer.
+    BadCookie,
+    /// ANY answered with HINFO or refused, per `ANY_MODE`.
+    Any,
+    /// AXFR answered or refused.
+    Transfer,
+    /// NOTAUTH for a TSIG that didn't verify.
+    BadTsig,
+    /// A dynamic update, applied or not.
+    Update,
+    /// Static records, configured or added by dynamic updates.
+    Static,
+    /// Pending ACME DNS-01 challenge tokens.
+    Challenge,
+    /// The apex CAA records of `CAA_RECORDS`.
+    Caa,
+    /// A CHAOS-class server identification query, answered or refused.
+    Chaos,
+}
+
+/// How ANY queries are answered (`ANY_MODE`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnyMode {
+    /// The first RRset the name has, e.g. only A for a dual-stack name.
+    Minimal,
+    /// A synthesized `HINFO "RFC8482" ""` record.
+    Hinfo,
+    Refused,
+    NotImp,
+}
+
+impl AnyMode {
+    /// Parse the `ANY_MODE` value.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "minimal" => Some(AnyMode::Minimal),
+            "hinfo" => Some(AnyMode::Hinfo),
+            "refused" => Some(AnyMode::Refused),
+            "notimp" => Some(AnyMode::NotImp),
+            _ => None,
+        }
+    }
+}
+
+const TYPE_HINFO: u16 = 13;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_CH: u16 = 3;
+
+/// Most names a CNAME chain is followed through in one answer.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// TTL of the synthesized HINFO answer to ANY, as suggested by RFC 8482.
+const HINFO_TTL: u32 = 3600;
+
+const RCODE_FORMERR: u16 = 1;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+const RCODE_BADVERS: u16 = 16;
+const RCODE_BADCOOKIE: u16 = 23;
+
+/// A response together with what was decided while building it, so callers
+/// can account for it (metrics, logging) after sending.
+pub struct Resolution {
+    pub response: Vec<u8>,
+    pub question: Option<Question>,
+    pub outcome: Outcome,
+    /// Presentation-format data of what was answered, e.g. `["10.0.0.1"]`.
+    pub answers: Vec<String>,
+}
+
+impl Resolution {
+    /// The full RCODE, including the upper bits carried in the OPT record.
+    pub fn rcode(&self) -> Option<u16> {
+        let low = u16::from(self.response.get(3)? & 0x0F);
+        let high = edns::parse(&self.response).map_or(0, |edns| u16::from(edns.extended_rcode));
+        Some(high << 4 | low)
+    }
+}
+
+/// Size of the buffer UDP queries are received into: the largest datagram,
+/// so that queries bigger than the payload size we advertise, like signed
+/// updates, are read whole rather than cut short.
+pub const UDP_RECEIVE_SIZE: usize = 65_535;
+
+/// Resolve a UDP query and send the response, subject to response rate
+/// limiting. On [`Action::Slip`] the returned resolution carries the
+/// truncated response that was actually sent; on [`Action::Drop`] nothing
+/// was sent.
 pub fn handle_query(
     query: &[u8],
     socket: &UdpSocket,
     src: SocketAddr,
     config: &Config,
-) -> IoResult<()> {
-    if let Some(domain) = parse_query(query) {
-        debug!("Parsed domain: {domain}");
-        debug!("GLUE_NAME: {}", config.glue_name);
+    rrl: &ResponseRateLimiter,
+) -> IoResult<(Resolution, Action)> {
+    let mut resolution = handle_query_internal(query, src, config);
+    if resolution.response.is_empty() {
+        return Ok((resolution, Action::Send));
+    }
+    let any = resolution
+        .question
+        .as_ref()
+        .is_some_and(|question| question.qtype == TYPE_ANY);
+    if any && config.any_udp_truncate {
+        info!("Client [{src}] sent ANY over UDP - truncating");
+        resolution.response = truncated_response(query, src, config);
+        resolution.answers.clear();
+    }
 
-        if !is_in_zone(&domain, &config.glue_name) && !is_version_query(&domain) {
-            info!("Client [{src}] refused [{domain}] (out of zone)");
-            let response = build_refused_response(query);
-            socket.send_to(&response, src)?;
-            return Ok(());
+    let exempt = config.cookie_rate_limit_exempt
+        && matches!(query_cookie(query, src, config), Cookie::Valid(_));
+    let action = if exempt {
+        Action::Send
+    } else {
+        rrl.check(src.ip(), &resolution, &config.glue_name)
+    };
+    match action {
+        Action::Send => {}
+        Action::Slip => {
+            info!("Client [{src}] response rate limited (slipped)");
+            resolution.response = truncated_response(query, src, config);
+            resolution.answers.clear();
         }
-
-        if domain.eq_ignore_ascii_case(&config.glue_name) {
-            info!(
-                "Client [{}] resolved [{}] to [{}]",
-                src, domain, config.glue_ip
-            );
-            let response = build_response(query, Some((&config.glue_name, config.glue_ip)), None);
-            socket.send_to(&response, src)?;
-        } else if is_version_query(&domain) {
-            info!("Client [{src}] requested version TXT record");
-            let nameandversion = format!("RustyAlias v{}", config.version);
-            let response = build_txt_response(query, &nameandversion);
-            socket.send_to(&response, src)?;
-        } else if let Some(ip) = interpret_ip(&domain) {
-            info!("Client [{src}] resolved [{domain}] to [{ip:?}]");
-            let response = build_response(query, None, Some(ip));
-            socket.send_to(&response, src)?;
-        } else {
-            info!("Client [{src}] query for intermediate subdomain [{domain}] - returning SOA");
-            let soa_params = SoaParams {
-                soa_name: &config.soa_name,
-                hostmaster: &config.hostmaster,
-                serial: config.serial,
-                refresh: config.refresh,
-                retry: config.retry,
-                expire: config.expire,
-                minimum: config.minimum,
-            };
-            let response = build_soa_response(query, &soa_params);
-            socket.send_to(&response, src)?;
+        Action::Drop => {
+            info!("Client [{src}] response rate limited (dropped)");
+            return Ok((resolution, action));
         }
-    } else {
-        debug!("Failed to parse query: {query:?}");
     }
-    Ok(())
+    let limit = udp_payload_limit(query);
+    if resolution.response.len() > limit {
+        info!(
+            "Client [{src}] response of {} bytes truncated (limit {limit})",
+            resolution.response.len()
+        );
+        resolution.response = truncated_response(query, src, config);
+        resolution.answers.clear();
+    }
+    socket.send_to(&resolution.response, src)?;
+    Ok((resolution, action))
 }
 
+/// A truncated response, telling the client to retry over TCP. An EDNS
+/// query still gets an OPT record, with a server cookie for its client
+/// cookie, so the retry can present it.
+fn truncated_response(query: &[u8], src: SocketAddr, config: &Config) -> Vec<u8> {
+    let mut response = build_truncated_response(query);
+    if let Some(edns) = edns::parse(query) {
+        append_opt(
+            &mut response,
+            0,
+            edns.dnssec_ok && config.dnssec.is_some(),
+            query_cookie(query, src, config),
+            edns.option(OPTION_NSID).is_some(),
+            src,
+            config,
+        );
+    }
+    response
+}
+
+/// Largest UDP response the client accepts: its EDNS payload size, but
+/// never less than the classic 512 bytes or more than we advertise.
+fn udp_payload_limit(query: &[u8]) -> usize {
+    let size = edns::parse(query).map_or(512, |edns| edns.udp_size);
+    usize::from(size.clamp(512, UDP_PAYLOAD_SIZE))
+}
+
+/// The CHAOS TXT names that identify the server (RFC 4892), and whether
+/// each one asks for the version rather than the server's identity.
+const CHAOS_NAMES: [(&str, bool); 4] = [
+    ("version.bind", true),
+    ("version.server", true),
+    ("id.server", false),
+    ("hostname.bind", false),
+];
+
 fn is_version_query(domain: &str) -> bool {
     domain.eq_ignore_ascii_case("version")
         || domain.eq_ignore_ascii_case("ver")
@@ -90,7 +249,7 @@ pub fn is_in_zone(domain: &str, zone: &str) -> bool {
     domain[split_at..].eq_ignore_ascii_case(zone)
 }
 
-pub fn parse_query(query: &[u8]) -> Option<String> {
+pub fn parse_question(query: &[u8]) -> Option<Question> {
     if query.len() < 12 {
         debug!("Query too short: {}", query.len());
         return None;
@@ -125,54 +284,755 @@ pub fn parse_query(query: &[u8]) -> Option<String> {
         return None;
     }
 
-    Some(domain)
+    // Null terminator + QTYPE(2) + QCLASS(2); the response builders echo
+    // these bytes back, so a query missing them can't be answered.
+    if pos + 5 > query.len() {
+        debug!("Question section truncated");
+        return None;
+    }
+
+    Some(Question {
+        name: domain,
+        qtype: u16::from_be_bytes([query[pos + 1], query[pos + 2]]),
+        qclass: u16::from_be_bytes([query[pos + 3], query[pos + 4]]),
+    })
+}
+
+pub fn qtype_name(qtype: u16) -> String {
+    match qtype {
+        1 => "A".to_string(),
+        2 => "NS".to_string(),
+        5 => "CNAME".to_string(),
+        6 => "SOA".to_string(),
+        12 => "PTR".to_string(),
+        13 => "HINFO".to_string(),
+        15 => "MX".to_string(),
+        16 => "TXT".to_string(),
+        28 => "AAAA".to_string(),
+        33 => "SRV".to_string(),
+        43 => "DS".to_string(),
+        46 => "RRSIG".to_string(),
+        47 => "NSEC".to_string(),
+        48 => "DNSKEY".to_string(),
+        64 => "SVCB".to_string(),
+        65 => "HTTPS".to_string(),
+        252 => "AXFR".to_string(),
+        255 => "ANY".to_string(),
+        257 => "CAA".to_string(),
+        other => format!("TYPE{other}"),
+    }
+}
+
+/// The type number for a mnemonic as printed by [`qtype_name`], or in
+/// RFC 3597 `TYPEn` form.
+pub fn qtype_number(name: &str) -> Option<u16> {
+    let upper = name.to_ascii_uppercase();
+    if let Some(number) = upper.strip_prefix("TYPE") {
+        return number.parse().ok();
+    }
+    (1..=257).find(|&qtype| qtype_name(qtype) == upper)
+}
+
+pub fn rcode_name(rcode: u16) -> String {
+    match rcode {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        6 => "YXDOMAIN".to_string(),
+        7 => "YXRRSET".to_string(),
+        8 => "NXRRSET".to_string(),
+        9 => "NOTAUTH".to_string(),
+        10 => "NOTZONE".to_string(),
+        16 => "BADVERS".to_string(),
+        17 => "BADKEY".to_string(),
+        18 => "BADTIME".to_string(),
+        23 => "BADCOOKIE".to_string(),
+        other => format!("RCODE{other}"),
+    }
+}
+
+/// The COOKIE a query carries, checked against this server's secret.
+pub fn query_cookie(query: &[u8], src: SocketAddr, config: &Config) -> Cookie {
+    let Some(edns) = edns::parse(query) else {
+        return Cookie::Missing;
+    };
+    Cookie::inspect(
+        edns.option(OPTION_COOKIE),
+        &config.cookie_secret,
+        src.ip(),
+        cookie::now(),
+    )
 }
 
-pub fn handle_query_internal(query: &[u8], src: SocketAddr, config: &Config) -> IoResult<Vec<u8>> {
-    if let Some(domain) = parse_query(query) {
-        debug!("Parsed domain: {domain}");
-        debug!("GLUE_NAME: {}", config.glue_name);
+/// BADCOOKIE with a fresh server cookie, sent to a rate-limited client
+/// instead of silently dropping it: a real client retries with the cookie
+/// and is then exempt. `None` if the query carries no client cookie.
+pub fn handle_badcookie(query: &[u8], src: SocketAddr, config: &Config) -> Option<Resolution> {
+    let question = parse_question(query)?;
+    let cookie = query_cookie(query, src, config);
+    cookie.client()?;
+    info!("Client [{src}] rate limited, sent BADCOOKIE");
+
+    let mut response = build_error_response(query, RCODE_BADCOOKIE as u8);
+    append_opt(
+        &mut response,
+        RCODE_BADCOOKIE,
+        false,
+        cookie,
+        false,
+        src,
+        config,
+    );
+    Some(Resolution {
+        response,
+        question: Some(question),
+        outcome: Outcome::BadCookie,
+        answers: Vec::new(),
+    })
+}
 
-        if !is_in_zone(&domain, &config.glue_name) && !is_version_query(&domain) {
-            info!("Client [{src}] refused [{domain}] (out of zone)");
-            return Ok(build_refused_response(query));
+/// Resolve a query received over TCP, where zone transfers are allowed.
+/// Returns the resolution and, for a transfer that takes several messages,
+/// the ones that follow its response.
+pub fn handle_tcp_query(
+    query: &[u8],
+    src: SocketAddr,
+    config: &Config,
+) -> (Resolution, Vec<Vec<u8>>) {
+    match parse_question(query) {
+        Some(question) if question.qtype == TYPE_AXFR => {
+            with_tsig(query, question, src, config, |question, signed| {
+                handle_axfr(query, question, src, signed, config)
+            })
         }
+        _ => (handle_query_internal(query, src, config), Vec::new()),
+    }
+}
 
-        let response = if domain.eq_ignore_ascii_case(&config.glue_name) {
+/// Run `handler` for a request that may carry a TSIG record, telling it
+/// whether the request was signed. A TSIG that doesn't verify is answered
+/// with NOTAUTH instead; a valid one signs the response, and any messages
+/// the handler returns after it, with the same key.
+fn with_tsig(
+    query: &[u8],
+    question: Question,
+    src: SocketAddr,
+    config: &Config,
+    handler: impl FnOnce(Question, bool) -> (Resolution, Vec<Vec<u8>>),
+) -> (Resolution, Vec<Vec<u8>>) {
+    let now = tsig::now();
+    match tsig::verify(query, &config.tsig_keys, now) {
+        Ok(None) => handler(question, false),
+        Ok(Some(signed)) => {
+            debug!(
+                "Client [{src}] signed with TSIG key [{}]",
+                signed.key_name()
+            );
+            let (mut resolution, mut rest) = handler(question, true);
+            let mut mac = signed.sign(&mut resolution.response, now);
+            for message in &mut rest {
+                mac = signed.sign_next(message, &mac, now);
+            }
+            (resolution, rest)
+        }
+        Err(rejection) => {
             info!(
-                "Client [{}] resolved [{}] to [{}]",
-                src, domain, config.glue_ip
+                "Client [{src}] sent a bad TSIG ({})",
+                rcode_name(rejection.error)
             );
-            build_response(query, Some((&config.glue_name, config.glue_ip)), None)
-        } else if is_version_query(&domain) {
-            info!("Client [{src}] requested version TXT record");
-            build_txt_response(query, &config.version)
-        } else if let Some(ip) = interpret_ip(&domain) {
-            info!("Client [{src}] resolved [{domain}] to [{ip:?}]");
-            build_response(query, None, Some(ip))
-        } else {
-            info!("Client [{src}] query for intermediate subdomain [{domain}] - returning SOA");
-            let soa_params = SoaParams {
-                soa_name: &config.soa_name,
-                hostmaster: &config.hostmaster,
-                serial: config.serial,
-                refresh: config.refresh,
-                retry: config.retry,
-                expire: config.expire,
-                minimum: config.minimum,
+            let resolution = Resolution {
+                response: rejection.response(query, now),
+                question: Some(question),
+                outcome: Outcome::BadTsig,
+                answers: Vec::new(),
             };
-            build_soa_response(query, &soa_params)
+            (resolution, Vec::new())
+        }
+    }
+}
+
+/// Resolve a query. If it has an OPT record, the response gets one too,
+/// carrying a fresh server cookie for clients that sent a client cookie.
+pub fn handle_query_internal(query: &[u8], src: SocketAddr, config: &Config) -> Resolution {
+    let Some(question) = parse_question(query) else {
+        debug!("Failed to parse query: {query:?}");
+        return Resolution {
+            response: Vec::new(),
+            question: None,
+            outcome: Outcome::Malformed,
+            answers: Vec::new(),
         };
-        Ok(response)
+    };
+    if update::opcode(query) == OPCODE_UPDATE {
+        return with_tsig(query, question, src, config, |zone, signed| {
+            (handle_update(query, zone, src, signed, config), Vec::new())
+        })
+        .0;
+    }
+    let Some(edns) = edns::parse(query) else {
+        if !edns::has_opt(query) {
+            return resolve(query, question, src, config);
+        }
+        // RFC 6891 §6.1.1: without a usable OPT record there is none to
+        // answer with either.
+        info!("Client [{src}] sent a malformed OPT record");
+        return Resolution {
+            response: build_error_response(query, RCODE_FORMERR as u8),
+            question: Some(question),
+            outcome: Outcome::BadEdns,
+            answers: Vec::new(),
+        };
+    };
+
+    let cookie = Cookie::inspect(
+        edns.option(OPTION_COOKIE),
+        &config.cookie_secret,
+        src.ip(),
+        cookie::now(),
+    );
+    let nsid = edns.option(OPTION_NSID).is_some();
+    let rcode = if edns.version > 0 {
+        info!(
+            "Client [{src}] sent unsupported EDNS version {}",
+            edns.version
+        );
+        RCODE_BADVERS
+    } else if cookie == Cookie::Malformed {
+        info!("Client [{src}] sent a malformed COOKIE option");
+        RCODE_FORMERR
     } else {
-        debug!("Failed to parse query: {query:?}");
-        Ok(Vec::new())
+        let mut resolution = resolve(query, question, src, config);
+        // Zone keys don't sign CHAOS answers.
+        let dnssec_ok =
+            edns.dnssec_ok && config.dnssec.is_some() && resolution.outcome != Outcome::Chaos;
+        if let Some(key) = config.dnssec.as_ref().filter(|_| dnssec_ok) {
+            if let Some(denial) = compact_denial(query, &resolution, config) {
+                resolution.response = denial;
+                resolution.answers.clear();
+            }
+            match key.sign_response(&resolution.response) {
+                Some(signed) => resolution.response = signed,
+                // Unsigned data would fail validation just the same.
+                None => {
+                    warn!("Could not sign response: {:?}", resolution.response);
+                    resolution.response = build_error_response(query, RCODE_SERVFAIL as u8);
+                    resolution.answers.clear();
+                }
+            }
+        }
+        append_opt(
+            &mut resolution.response,
+            0,
+            dnssec_ok,
+            cookie,
+            nsid,
+            src,
+            config,
+        );
+        return resolution;
+    };
+
+    let mut response = build_error_response(query, rcode as u8);
+    append_opt(&mut response, rcode, false, cookie, nsid, src, config);
+    Resolution {
+        response,
+        question: Some(question),
+        outcome: Outcome::BadEdns,
+        answers: Vec::new(),
+    }
+}
+
+/// Append the response's OPT record, with a server cookie for a client
+/// cookie and, if `nsid` was asked for, the `SERVER_ID` (RFC 5001).
+fn append_opt(
+    response: &mut Vec<u8>,
+    rcode: u16,
+    dnssec_ok: bool,
+    cookie: Cookie,
+    nsid: bool,
+    src: SocketAddr,
+    config: &Config,
+) {
+    let nsid = config.server_id.as_ref().filter(|_| nsid);
+    let options = nsid
+        .map(|id| EdnsOption {
+            code: OPTION_NSID,
+            data: id.as_bytes().to_vec(),
+        })
+        .into_iter()
+        .chain(cookie.client().map(|client| EdnsOption {
+            code: OPTION_COOKIE,
+            data: cookie::response_option(&config.cookie_secret, client, src.ip()),
+        }))
+        .collect();
+    let opt = Edns {
+        extended_rcode: (rcode >> 4) as u8,
+        dnssec_ok,
+        ..Edns::response(options)
+    };
+    edns::append(response, &opt);
+}
+
+/// Turn a negative answer into a compact denial of existence (RFC 9824)
+/// that validates: names with no records get an NSEC with NXNAME, and
+/// types a name doesn't have a NODATA response whose NSEC lists the ones
+/// it does. `None` if the response isn't negative.
+fn compact_denial(query: &[u8], resolution: &Resolution, config: &Config) -> Option<Vec<u8>> {
+    let question = resolution.question.as_ref()?;
+    match resolution.outcome {
+        Outcome::Malformed
+        | Outcome::OutOfZone
+        | Outcome::BadEdns
+        | Outcome::BadCookie
+        | Outcome::Transfer
+        | Outcome::BadTsig
+        | Outcome::Update
+        | Outcome::Chaos => return None,
+        _ => {}
+    }
+    // Encoded names answer their address whatever the type asked for, so
+    // what counts is whether the answer has the type.
+    let answered = Message::parse(&resolution.response)?
+        .answers
+        .iter()
+        .any(|record| {
+            question.qtype == TYPE_ANY || [question.qtype, TYPE_CNAME].contains(&record.rtype)
+        });
+    if !matches!(resolution.rcode()?, 0 | 3) || answered {
+        return None;
+    }
+    let types = types_at(&question.name, config);
+    if let Some(types) = &types {
+        if types.contains(&question.qtype) || question.qtype == TYPE_ANY {
+            return None;
+        }
+    }
+
+    let mut message = Message::parse(&build_soa_response(query, &soa_params(config)))?;
+    // The negative TTL is the lower of the SOA's own TTL and its MINIMUM.
+    let ttl = config.minimum.min(SOA_TTL);
+    message.authority.push(dnssec::compact_denial(
+        &question.name,
+        types.as_deref(),
+        ttl,
+    ));
+    Some(message.encode())
+}
+
+/// The types `name` has records of, for the NSEC bitmap of a denial.
+/// `None` if it doesn't exist: no records and none below it either, since
+/// an empty non-terminal gets NODATA (RFC 8020).
+fn types_at(name: &str, config: &Config) -> Option<Vec<u16>> {
+    let mut types: Vec<u16> = config
+        .records
+        .at(name)
+        .into_iter()
+        .map(|record| record.rtype)
+        .collect();
+    if name.eq_ignore_ascii_case(&config.glue_name) {
+        types.extend([TYPE_SOA, TYPE_NS]);
+        if config.dnssec.is_some() {
+            types.push(TYPE_DNSKEY);
+        }
+        if !config.records.caa().is_empty() {
+            types.push(TYPE_CAA);
+        }
+    }
+    if challenge_answer(name, TYPE_TXT, config).is_some()
+        || (config.server_version.is_some() && is_version_query(name))
+    {
+        types.push(TYPE_TXT);
+    }
+    types.extend(
+        [TYPE_A, TYPE_AAAA]
+            .into_iter()
+            .filter(|&rtype| synthesized_address(name, rtype, config).is_some()),
+    );
+    types.extend(services::types_at(name, config));
+    types.sort_unstable();
+    types.dedup();
+    let exists = !types.is_empty()
+        || config.records.has_below(name)
+        || services::is_empty_non_terminal(name, config);
+    exists.then_some(types)
+}
+
+pub fn soa_params(config: &Config) -> SoaParams<'_> {
+    SoaParams {
+        zone: &config.glue_name,
+        soa_name: &config.soa_name,
+        hostmaster: &config.hostmaster,
+        serial: config.records.serial(),
+        refresh: config.refresh,
+        retry: config.retry,
+        expire: config.expire,
+        minimum: config.minimum,
+    }
+}
+
+/// Static records at `domain` answering `qtype`: its CNAME if it has one,
+/// else its RRset of `qtype`. Returns the type answered with the records.
+fn static_answer(domain: &str, qtype: u16, config: &Config) -> Option<(u16, Vec<Record>)> {
+    let at_name = config.records.at(domain);
+    let rtype = [TYPE_CNAME, qtype]
+        .into_iter()
+        .find(|&rtype| at_name.iter().any(|record| record.rtype == rtype))?;
+    let records = at_name.into_iter().filter(|r| r.rtype == rtype).collect();
+    Some((rtype, records))
+}
+
+/// Follow the CNAME from `owner` to `target` through the zone, so the answer
+/// carries the whole chain: further static CNAMEs, then the static `qtype`
+/// records or the synthesized address at its end. Stops at a name outside
+/// the zone, on a loop or after `MAX_CNAME_CHAIN` names, leaving the rest
+/// to the resolver.
+fn chase_cname(owner: &str, target: &str, qtype: u16, config: &Config) -> Vec<Record> {
+    let mut visited = vec![owner.to_ascii_lowercase()];
+    let mut chased = Vec::new();
+    let mut name = target.to_ascii_lowercase();
+    while is_in_zone(&name, &config.glue_name)
+        && !visited.contains(&name)
+        && visited.len() <= MAX_CNAME_CHAIN
+    {
+        visited.push(name.clone());
+        match static_answer(&name, qtype, config) {
+            Some((TYPE_CNAME, records)) => {
+                let Some((next, _)) = read_name(&records[0].rdata, 0) else {
+                    break;
+                };
+                chased.extend(records);
+                name = next.to_ascii_lowercase();
+            }
+            Some((_, records)) => {
+                chased.extend(records);
+                break;
+            }
+            None => {
+                chased.extend(synthesized_address(&name, qtype, config));
+                break;
+            }
+        }
+    }
+    chased
+}
+
+/// The A or AAAA record the synthesized namespace (or the apex glue) has at
+/// `name` for `qtype`, if any.
+fn synthesized_address(name: &str, qtype: u16, config: &Config) -> Option<Record> {
+    let (v4, v6) = if name.eq_ignore_ascii_case(&config.glue_name) {
+        (Some(config.glue_ip), None)
+    } else {
+        interpret_ip_encoding(name)?.1
+    };
+    let rdata = match qtype {
+        TYPE_A => v4?.octets().to_vec(),
+        TYPE_AAAA => v6?.octets().to_vec(),
+        _ => return None,
+    };
+    Some(Record {
+        name: name.to_string(),
+        rtype: qtype,
+        class: 1,
+        ttl: ADDRESS_TTL,
+        rdata,
+    })
+}
+
+/// TXT values pending at an `_acme-challenge` name, if any.
+fn challenge_answer(domain: &str, qtype: u16, config: &Config) -> Option<Vec<String>> {
+    if qtype != TYPE_TXT || !acme::is_challenge_name(domain) {
+        return None;
+    }
+    let values = config.challenges.get(domain);
+    (!values.is_empty()).then_some(values)
+}
+
+/// The decision tree proper, independent of EDNS.
+fn resolve(query: &[u8], question: Question, src: SocketAddr, config: &Config) -> Resolution {
+    let domain = question.name.as_str();
+    debug!("Parsed domain: {domain}");
+    debug!("GLUE_NAME: {}", config.glue_name);
+
+    if question.qclass == CLASS_CH {
+        return resolve_chaos(query, question, src, config);
+    }
+    let version = config.server_version.is_some() && is_version_query(domain);
+    if !is_in_zone(domain, &config.glue_name) && !version {
+        info!("Client [{src}] refused [{domain}] (out of zone)");
+        return Resolution {
+            response: build_refused_response(query),
+            question: Some(question),
+            outcome: Outcome::OutOfZone,
+            answers: Vec::new(),
+        };
+    }
+
+    if question.qtype == TYPE_AXFR {
+        info!("Client [{src}] sent AXFR for [{domain}] over UDP");
+        return Resolution {
+            response: build_error_response(query, RCODE_NOTIMP as u8),
+            question: Some(question),
+            outcome: Outcome::Transfer,
+            answers: Vec::new(),
+        };
+    }
+
+    if question.qtype == TYPE_ANY {
+        resolve_any(query, question, src, config)
+    } else {
+        answer(query, question, src, config)
+    }
+}
+
+/// Server identification in the CHAOS class: `version.bind` and
+/// `version.server` answer `SERVER_VERSION`, `id.server` and
+/// `hostname.bind` answer `SERVER_ID`. Other names, and hidden values, are
+/// refused.
+fn resolve_chaos(query: &[u8], question: Question, src: SocketAddr, config: &Config) -> Resolution {
+    let value = CHAOS_NAMES
+        .iter()
+        .find(|(name, _)| question.name.eq_ignore_ascii_case(name))
+        .and_then(|&(_, version)| {
+            if version {
+                config.server_version.as_ref()
+            } else {
+                config.server_id.as_ref()
+            }
+        });
+    let (response, answers) = match value {
+        Some(value) if [TYPE_TXT, TYPE_ANY].contains(&question.qtype) => {
+            info!("Client [{src}] requested [{}] (CHAOS)", question.name);
+            let values = std::slice::from_ref(value);
+            (build_txt_response(query, TXT_TTL, values), values.to_vec())
+        }
+        Some(_) => (build_answer_response(query, TYPE_TXT, 0, &[]), Vec::new()),
+        None => {
+            info!("Client [{src}] refused [{}] (CHAOS)", question.name);
+            (build_refused_response(query), Vec::new())
+        }
+    };
+    Resolution {
+        response,
+        question: Some(question),
+        outcome: Outcome::Chaos,
+        answers,
+    }
+}
+
+/// ANY per RFC 8482: never the whole node, to keep it useless for
+/// amplification.
+fn resolve_any(query: &[u8], question: Question, src: SocketAddr, config: &Config) -> Resolution {
+    let domain = question.name.clone();
+    let rcode = match config.any_mode {
+        AnyMode::Refused => Some(RCODE_REFUSED),
+        AnyMode::NotImp => Some(RCODE_NOTIMP),
+        AnyMode::Minimal | AnyMode::Hinfo => None,
+    };
+    if let Some(rcode) = rcode {
+        info!(
+            "Client [{src}] sent ANY for [{domain}] - returning {}",
+            rcode_name(rcode)
+        );
+        return Resolution {
+            response: build_error_response(query, rcode as u8),
+            question: Some(question),
+            outcome: Outcome::Any,
+            answers: Vec::new(),
+        };
+    }
+
+    let mut resolution = answer(query, question, src, config);
+    if resolution.outcome == Outcome::Intermediate {
+        // Nothing exists here; the SOA answer stands.
+        return resolution;
+    }
+    if config.any_mode == AnyMode::Hinfo {
+        info!("Client [{src}] sent ANY for [{domain}] - returning HINFO");
+        let mut hinfo = b"\x07RFC8482".to_vec();
+        hinfo.push(0); // Empty OS string
+        resolution.response = build_answer_response(query, TYPE_HINFO, HINFO_TTL, &[hinfo]);
+        resolution.outcome = Outcome::Any;
+        resolution.answers = vec!["\"RFC8482\" \"\"".to_string()];
+    } else if let Some(mut message) = Message::parse(&resolution.response) {
+        // Keep the first RRset only, e.g. A but not AAAA.
+        if let Some(first) = message.answers.first().map(|record| record.rtype) {
+            message.answers.retain(|record| record.rtype == first);
+            resolution.answers.truncate(message.answers.len());
+            resolution.response = message.encode();
+        }
+    }
+    resolution
+}
+
+/// The answer for an in-zone name.
+fn answer(query: &[u8], question: Question, src: SocketAddr, config: &Config) -> Resolution {
+    let domain = question.name.as_str();
+
+    let dnskey = config.dnssec.as_ref().filter(|_| {
+        question.qtype == TYPE_DNSKEY && domain.eq_ignore_ascii_case(&config.glue_name)
+    });
+    let (response, outcome, answers) = if let Some(key) = dnskey {
+        info!("Client [{src}] requested DNSKEY");
+        (
+            build_answer_response(query, TYPE_DNSKEY, DNSKEY_TTL, &[key.dnskey_rdata()]),
+            Outcome::Dnskey,
+            vec![key.dnskey_text()],
+        )
+    } else if let Some(values) = challenge_answer(domain, question.qtype, config) {
+        info!("Client [{src}] requested ACME challenge [{domain}]");
+        (
+            build_txt_response(query, acme::TTL, &values),
+            Outcome::Challenge,
+            values,
+        )
+    } else if let Some((rtype, records)) = static_answer(domain, question.qtype, config) {
+        info!("Client [{src}] resolved [{domain}] from static records");
+        let chase = rtype == TYPE_CNAME && ![TYPE_CNAME, TYPE_ANY].contains(&question.qtype);
+        let target = read_name(&records[0].rdata, 0).filter(|_| chase);
+        let (response, records) = match target {
+            Some((target, _)) => {
+                let chased = chase_cname(domain, &target, question.qtype, config);
+                let response = build_cname_response(query, records[0].ttl, &target, &chased);
+                (response, records.into_iter().chain(chased).collect())
+            }
+            None => {
+                let rdatas: Vec<Vec<u8>> = records.iter().map(|r| r.rdata.clone()).collect();
+                let response = build_answer_response(query, rtype, records[0].ttl, &rdatas);
+                (response, records)
+            }
+        };
+        (
+            response,
+            Outcome::Static,
+            records
+                .iter()
+                .map(|r| records::rdata_text(r.rtype, &r.rdata))
+                .collect(),
+        )
+    } else if question.qtype == TYPE_CAA
+        && domain.eq_ignore_ascii_case(&config.glue_name)
+        && !config.records.caa().is_empty()
+    {
+        info!("Client [{src}] requested CAA records");
+        let caa = config.records.caa();
+        (
+            build_caa_response(query, &caa),
+            Outcome::Caa,
+            caa.iter()
+                .map(|caa| records::rdata_text(TYPE_CAA, &caa.rdata()))
+                .collect(),
+        )
+    } else if domain.eq_ignore_ascii_case(&config.glue_name) {
+        match question.qtype {
+            TYPE_A | TYPE_NS | TYPE_ANY => {
+                info!(
+                    "Client [{}] resolved [{}] to [{}]",
+                    src, domain, config.glue_ip
+                );
+                (
+                    build_response(query, Some((&config.glue_name, config.glue_ip)), None),
+                    Outcome::Glue,
+                    vec![config.glue_ip.to_string()],
+                )
+            }
+            TYPE_SOA => {
+                info!("Client [{src}] requested SOA record");
+                (
+                    build_answer_response(
+                        query,
+                        TYPE_SOA,
+                        SOA_TTL,
+                        &[soa_rdata(&soa_params(config))],
+                    ),
+                    Outcome::Glue,
+                    vec![config.soa_name.clone()],
+                )
+            }
+            qtype => {
+                info!(
+                    "Client [{src}] requested {} at the apex, which has none",
+                    qtype_name(qtype)
+                );
+                (
+                    build_soa_response(query, &soa_params(config)),
+                    Outcome::Glue,
+                    vec![],
+                )
+            }
+        }
+    } else if let Some(version) = config
+        .server_version
+        .as_ref()
+        .filter(|_| is_version_query(domain))
+    {
+        info!("Client [{src}] requested version TXT record");
+        (
+            build_txt_response(query, TXT_TTL, std::slice::from_ref(version)),
+            Outcome::Version,
+            vec![version.clone()],
+        )
+    } else if let Some((response, encoding)) =
+        services::answer(query, domain, question.qtype, config)
+    {
+        info!(
+            "Client [{src}] resolved [{domain}] to a synthesized {}",
+            qtype_name(question.qtype)
+        );
+        let answers = Message::parse(&response)
+            .map(|message| {
+                message
+                    .answers
+                    .iter()
+                    .map(|r| records::rdata_text(r.rtype, &r.rdata))
+                    .collect()
+            })
+            .unwrap_or_default();
+        (response, Outcome::Synthesized(encoding), answers)
+    } else if let Some((encoding, _)) =
+        interpret_ip_encoding(domain).filter(|_| question.qtype == TYPE_CAA)
+    {
+        // NODATA rather than the address, so that CAs climb the tree to the
+        // apex's CAA records (RFC 8659 §3).
+        info!("Client [{src}] requested CAA for [{domain}] - returning SOA");
+        (
+            build_soa_response(query, &soa_params(config)),
+            Outcome::Synthesized(encoding),
+            Vec::new(),
+        )
+    } else if let Some((encoding, ip)) = interpret_ip_encoding(domain) {
+        info!("Client [{src}] resolved [{domain}] to [{ip:?}]");
+        (
+            build_response(query, None, Some(ip)),
+            Outcome::Synthesized(encoding),
+            ip.0.iter()
+                .map(|v4| v4.to_string())
+                .chain(ip.1.iter().map(|v6| v6.to_string()))
+                .collect(),
+        )
+    } else {
+        info!("Client [{src}] query for intermediate subdomain [{domain}] - returning SOA");
+        (
+            build_soa_response(query, &soa_params(config)),
+            Outcome::Intermediate,
+            Vec::new(),
+        )
+    };
+
+    Resolution {
+        response,
+        question: Some(question),
+        outcome,
+        answers,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dns::dnssec::{Algorithm, SigningKey, TYPE_NSEC, TYPE_RRSIG};
 
     #[test]
     fn in_zone_exact_match() {
@@ -221,4 +1081,545 @@ mod tests {
         assert!(!is_version_query("version.bind"));
         assert!(!is_version_query("verify"));
     }
+
+    #[test]
+    fn question_parsed_with_type_and_class() {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        query.extend(b"\x03app\x02ns\x04addr\x02se\x00");
+        query.extend(&[0x00, 0x1C, 0x00, 0x01]);
+
+        let question = parse_question(&query).unwrap();
+        assert_eq!(question.name, "app.ns.addr.se");
+        assert_eq!(question.qtype, 28);
+        assert_eq!(question.qclass, 1);
+
+        // Dropping QCLASS leaves a question the builders can't echo back.
+        assert_eq!(parse_question(&query[..query.len() - 2]), None);
+    }
+
+    fn edns_query(cookie: &[u8], version: u8) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 1];
+        query.extend(b"\x0810-0-0-1\x02ns\x07example\x03com\x00");
+        query.extend(&[0x00, 0x01, 0x00, 0x01]);
+        query.extend(&[0x00, 0x00, 0x29, 0x04, 0xD0, 0x00, version, 0x00, 0x00]);
+        query.extend(&(cookie.len() as u16 + 4).to_be_bytes());
+        query.extend(&OPTION_COOKIE.to_be_bytes());
+        query.extend(&(cookie.len() as u16).to_be_bytes());
+        query.extend(cookie);
+        query
+    }
+
+    fn src() -> SocketAddr {
+        "192.0.2.1:5353".parse().unwrap()
+    }
+
+    #[test]
+    fn server_cookie_is_issued_and_accepted() {
+        let config = Config::for_tests("ns.example.com");
+        let client = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        let first = handle_query_internal(&edns_query(&client, 0), src(), &config);
+        assert_eq!(first.rcode(), Some(0));
+        assert_eq!(first.answers, vec!["10.0.0.1"]);
+        let cookie = edns::parse(&first.response)
+            .unwrap()
+            .option(OPTION_COOKIE)
+            .unwrap()
+            .to_vec();
+        assert_eq!(cookie.len(), 24);
+        assert_eq!(&cookie[..8], &client);
+
+        let second = edns_query(&cookie, 0);
+        assert_eq!(query_cookie(&second, src(), &config), Cookie::Valid(client));
+        let elsewhere = "192.0.2.2:5353".parse().unwrap();
+        assert_eq!(
+            query_cookie(&second, elsewhere, &config),
+            Cookie::Unverified(client)
+        );
+    }
+
+    #[test]
+    fn bad_edns_is_rejected() {
+        let config = Config::for_tests("ns.example.com");
+
+        let malformed = handle_query_internal(&edns_query(&[1, 2, 3], 0), src(), &config);
+        assert_eq!(malformed.outcome, Outcome::BadEdns);
+        assert_eq!(malformed.rcode(), Some(RCODE_FORMERR));
+
+        // An option running past the end of the OPT record.
+        let mut broken = edns_query(&[1; 8], 0);
+        let end = broken.len();
+        broken[end - 9] += 1;
+        let broken = handle_query_internal(&broken, src(), &config);
+        assert_eq!(broken.outcome, Outcome::BadEdns);
+        assert_eq!(broken.rcode(), Some(RCODE_FORMERR));
+        assert!(edns::parse(&broken.response).is_none());
+
+        let future = handle_query_internal(&edns_query(&[1; 8], 1), src(), &config);
+        assert_eq!(future.rcode(), Some(RCODE_BADVERS));
+        assert_eq!(rcode_name(future.rcode().unwrap()), "BADVERS");
+    }
+
+    #[test]
+    fn badcookie_needs_a_client_cookie() {
+        let config = Config::for_tests("ns.example.com");
+        let query = edns_query(&[1; 8], 0);
+        let badcookie = handle_badcookie(&query, src(), &config).unwrap();
+        assert_eq!(badcookie.rcode(), Some(RCODE_BADCOOKIE));
+        assert!(badcookie.response.len() <= query.len() + 16);
+        assert_eq!(badcookie.response[3] & 0x0F, 7);
+        assert!(edns::parse(&badcookie.response)
+            .unwrap()
+            .option(OPTION_COOKIE)
+            .is_some());
+
+        let mut plain = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        plain.extend(b"\x01a\x00\x00\x01\x00\x01");
+        assert!(handle_badcookie(&plain, src(), &config).is_none());
+    }
+
+    /// A query with an OPT record that has the DO bit set.
+    fn dnssec_query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 1];
+        query.extend(crate::dns::response::encode_domain_name(name));
+        query.extend(&qtype.to_be_bytes());
+        query.extend(&[0x00, 0x01]);
+        query.extend(&[
+            0x00, 0x00, 0x29, 0x04, 0xD0, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00,
+        ]);
+        query
+    }
+
+    #[test]
+    fn dnssec_ok_queries_are_signed() {
+        let path =
+            std::env::temp_dir().join(format!("rustyalias-query-{}.key", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut config = Config::for_tests("ns.example.com");
+        config.dnssec = Some(
+            SigningKey::load_or_generate(&path, Algorithm::Ed25519, &config.glue_name).unwrap(),
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let signed =
+            handle_query_internal(&dnssec_query("10-0-0-1.ns.example.com", 1), src(), &config);
+        let message = Message::parse(&signed.response).unwrap();
+        let types: Vec<u16> = message.answers.iter().map(|r| r.rtype).collect();
+        assert_eq!(types, vec![1, TYPE_RRSIG]);
+        assert!(edns::parse(&signed.response).unwrap().dnssec_ok);
+
+        let dnskey =
+            handle_query_internal(&dnssec_query("ns.example.com", TYPE_DNSKEY), src(), &config);
+        assert_eq!(dnskey.outcome, Outcome::Dnskey);
+        let message = Message::parse(&dnskey.response).unwrap();
+        assert_eq!(
+            message.answers[0].rdata,
+            config.dnssec.as_ref().unwrap().dnskey_rdata()
+        );
+        assert_eq!(message.answers[1].rtype, TYPE_RRSIG);
+
+        // Undecodable names are denied with NXNAME, missing types with
+        // a NODATA NSEC, both next to the SOA.
+        for (name, qtype, bitmap_end) in [
+            ("nothing.ns.example.com", 1, 0x80),
+            ("10-0-0-1.ns.example.com", 16, 0x03),
+        ] {
+            let denied = handle_query_internal(&dnssec_query(name, qtype), src(), &config);
+            assert!(denied.answers.is_empty());
+            let message = Message::parse(&denied.response).unwrap();
+            assert!(message.answers.is_empty());
+            let types: Vec<u16> = message.authority.iter().map(|r| r.rtype).collect();
+            assert_eq!(types, vec![6, TYPE_RRSIG, TYPE_NSEC, TYPE_RRSIG]);
+            assert_eq!(message.authority[2].rdata.last(), Some(&bitmap_end));
+        }
+
+        // The apex has no TXT records: NODATA with the SOA at the apex and
+        // an NSEC listing what it does have.
+        let apex = handle_query_internal(&dnssec_query("ns.example.com", 16), src(), &config);
+        let message = Message::parse(&apex.response).unwrap();
+        assert!(message.answers.is_empty());
+        assert_eq!(message.authority[0].rtype, 6);
+        assert_eq!(message.authority[0].name, "ns.example.com");
+        let nsec = &message.authority[2];
+        assert_eq!(nsec.rtype, TYPE_NSEC);
+        assert_eq!(nsec.name, "ns.example.com");
+        // A, NS, SOA; RRSIG, NSEC, DNSKEY.
+        assert!(nsec.rdata.ends_with(&[0, 7, 0x62, 0, 0, 0, 0, 0x03, 0x80]));
+
+        // Empty non-terminals, the parents of static records and of
+        // synthesized services, exist: their bitmap ends with RRSIG and
+        // NSEC, not NXNAME.
+        let fixed = records::parse_static("www.sub.ns.example.com A 192.0.2.1", &config.glue_name);
+        config.records = records::Records::new(1).with_fixed(fixed.unwrap());
+        config.synthesize_types = vec![records::TYPE_SRV];
+        for name in ["sub.ns.example.com", "_tcp.10-0-0-1.ns.example.com"] {
+            let empty = handle_query_internal(&dnssec_query(name, 16), src(), &config);
+            let message = Message::parse(&empty.response).unwrap();
+            assert!(message.answers.is_empty());
+            let nsec = &message.authority[2];
+            assert_eq!(nsec.rtype, TYPE_NSEC);
+            assert_eq!(nsec.rdata.last(), Some(&0x03), "{name}");
+        }
+
+        // So are static names asked for a type they don't have.
+        let fixed = records::parse_static("www.ns.example.com A 192.0.2.1", &config.glue_name);
+        config.records = records::Records::new(1).with_fixed(fixed.unwrap());
+        let missing =
+            handle_query_internal(&dnssec_query("www.ns.example.com", 16), src(), &config);
+        let message = Message::parse(&missing.response).unwrap();
+        let types: Vec<u16> = message.authority.iter().map(|r| r.rtype).collect();
+        assert_eq!(types, vec![6, TYPE_RRSIG, TYPE_NSEC, TYPE_RRSIG]);
+        assert_eq!(message.authority[0].name, "ns.example.com");
+
+        // Without the DO bit nothing is signed.
+        let mut plain = dnssec_query("10-0-0-1.ns.example.com", 1);
+        let do_byte = plain.len() - 4;
+        plain[do_byte] = 0;
+        let unsigned = handle_query_internal(&plain, src(), &config);
+        assert_eq!(Message::parse(&unsigned.response).unwrap().answers.len(), 1);
+    }
+
+    #[test]
+    fn large_signed_updates_are_received_over_udp() {
+        let mut config = Config::for_tests("ns.example.com");
+        config.update_allow = vec!["127.0.0.0/8".parse().unwrap()];
+        config.records = records::Records::new(1);
+        let keys = "hmac-sha256:test-key:MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+        config.tsig_keys = tsig::parse_keys(keys).unwrap();
+
+        let mut update = vec![0x43, 0x21, 0x28, 0x00, 0, 1, 0, 0, 0, 1, 0, 0];
+        update.extend(crate::dns::response::encode_domain_name("ns.example.com"));
+        update.extend(&[0x00, 0x06, 0x00, 0x01]);
+        let rdata = crate::dns::response::txt_rdata(&"x".repeat(600));
+        update.extend(crate::dns::response::encode_domain_name(
+            "txt.ns.example.com",
+        ));
+        update.extend(&TYPE_TXT.to_be_bytes());
+        update.extend(&[0x00, 0x01, 0x00, 0x00, 0x01, 0x2C]);
+        update.extend(&(rdata.len() as u16).to_be_bytes());
+        update.extend(rdata);
+        config.tsig_keys[0].sign_request(&mut update, tsig::now());
+        assert!(update.len() > 512);
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .send_to(&update, server.local_addr().unwrap())
+            .unwrap();
+        let mut buf = vec![0; UDP_RECEIVE_SIZE];
+        let (amt, src) = server.recv_from(&mut buf).unwrap();
+        let disabled = crate::rrl::Limits {
+            answers: 0,
+            nxdomains: 0,
+            refused: 0,
+            errors: 0,
+        };
+        let rrl = ResponseRateLimiter::new(disabled, 0, 24, 56);
+        handle_query(&buf[..amt], &server, src, &config, &rrl).unwrap();
+
+        let mut response = [0; 512];
+        let len = client.recv(&mut response).unwrap();
+        let message = Message::parse(&response[..len]).unwrap();
+        assert_eq!(message.header[3] & 0x0F, 0);
+        assert_eq!(message.additional.last().unwrap().rtype, tsig::TYPE_TSIG);
+        assert_eq!(config.records.at("txt.ns.example.com").len(), 1);
+    }
+
+    #[test]
+    fn truncated_responses_keep_the_opt_record() {
+        let mut config = Config::for_tests("ns.example.com");
+        config.any_udp_truncate = true;
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let one_answer = crate::rrl::Limits {
+            answers: 1,
+            nxdomains: 0,
+            refused: 0,
+            errors: 0,
+        };
+        let rrl = ResponseRateLimiter::new(one_answer, 1, 24, 56);
+
+        let mut any = edns_query(&[1; 8], 0);
+        any[37..39].copy_from_slice(&TYPE_ANY.to_be_bytes());
+        // ANY is truncated, and the answer after the one RRL allows slipped.
+        let a = edns_query(&[1; 8], 0);
+        for (query, action) in [
+            (any, Action::Send),
+            (a.clone(), Action::Send),
+            (a, Action::Slip),
+        ] {
+            client
+                .send_to(&query, server.local_addr().unwrap())
+                .unwrap();
+            let mut buf = vec![0; UDP_RECEIVE_SIZE];
+            let (amt, src) = server.recv_from(&mut buf).unwrap();
+            let (_, sent) = handle_query(&buf[..amt], &server, src, &config, &rrl).unwrap();
+            assert_eq!(sent, action);
+
+            let mut response = [0; 512];
+            let len = client.recv(&mut response).unwrap();
+            let truncated = response[2] & 0x02 != 0;
+            assert_eq!(
+                truncated,
+                query[37..39] == TYPE_ANY.to_be_bytes() || action == Action::Slip
+            );
+            let edns = edns::parse(&response[..len]).unwrap();
+            let cookie = edns.option(OPTION_COOKIE).unwrap();
+            assert_eq!((&cookie[..8], cookie.len()), (&[1; 8][..], 24));
+        }
+    }
+
+    fn query_for(name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        query.extend(crate::dns::response::encode_domain_name(name));
+        query.extend(&qtype.to_be_bytes());
+        query.extend(&[0x00, 0x01]);
+        query
+    }
+
+    #[test]
+    fn any_is_minimized_or_refused() {
+        let mut config = Config::for_tests("ns.example.com");
+        let any = query_for("1.2.3.4.ns.example.com", TYPE_ANY);
+
+        let minimal = handle_query_internal(&any, src(), &config);
+        let message = Message::parse(&minimal.response).unwrap();
+        let types: Vec<u16> = message.answers.iter().map(|r| r.rtype).collect();
+        assert_eq!(types, vec![1]);
+        assert_eq!(minimal.answers, vec!["1.2.3.4"]);
+
+        config.any_mode = AnyMode::Hinfo;
+        let hinfo = handle_query_internal(&any, src(), &config);
+        assert_eq!(hinfo.outcome, Outcome::Any);
+        let message = Message::parse(&hinfo.response).unwrap();
+        assert_eq!(message.answers[0].rtype, TYPE_HINFO);
+        assert_eq!(message.answers[0].rdata, b"\x07RFC8482\x00");
+
+        config.any_mode = AnyMode::NotImp;
+        assert_eq!(
+            handle_query_internal(&any, src(), &config).rcode(),
+            Some(RCODE_NOTIMP)
+        );
+        config.any_mode = AnyMode::Refused;
+        assert_eq!(
+            handle_query_internal(&any, src(), &config).rcode(),
+            Some(RCODE_REFUSED)
+        );
+    }
+
+    #[test]
+    fn static_records_answer_before_synthesis() {
+        let mut config = Config::for_tests("ns.example.com");
+        config.records = records::Records::new(1);
+        config
+            .records
+            .update(|records| {
+                records.push(records::parse_record("10-0-0-1.ns.example.com 60 TXT hi")?);
+                records.push(records::parse_record(
+                    "www.ns.example.com 60 CNAME a.example.org",
+                )?);
+                Ok::<_, String>(true)
+            })
+            .unwrap();
+
+        let txt = handle_query_internal(&query_for("10-0-0-1.ns.example.com", 16), src(), &config);
+        assert_eq!(txt.outcome, Outcome::Static);
+        assert_eq!(txt.answers, vec!["\"hi\""]);
+        let a = handle_query_internal(&query_for("10-0-0-1.ns.example.com", 1), src(), &config);
+        assert_eq!(a.answers, vec!["10.0.0.1"]);
+        let cname = handle_query_internal(&query_for("WWW.ns.example.com", 28), src(), &config);
+        assert_eq!(cname.answers, vec!["a.example.org."]);
+    }
+
+    #[test]
+    fn acme_challenges_are_served_as_txt() {
+        let config = Config::for_tests("ns.example.com");
+        let name = "_acme-challenge.app.ns.example.com";
+        let query = query_for(name, TYPE_TXT);
+
+        let missing = handle_query_internal(&query, src(), &config);
+        assert_eq!(missing.outcome, Outcome::Intermediate);
+
+        config.challenges.present(name, "token-1");
+        config.challenges.present(name, "token-2");
+        let txt = handle_query_internal(&query, src(), &config);
+        assert_eq!(txt.outcome, Outcome::Challenge);
+        assert_eq!(txt.answers, vec!["token-1", "token-2"]);
+        let message = Message::parse(&txt.response).unwrap();
+        assert_eq!(message.answers[1].rdata, b"\x07token-2");
+        assert_eq!(message.answers[1].ttl, acme::TTL);
+
+        let a = handle_query_internal(&query_for(name, 1), src(), &config);
+        assert_eq!(a.outcome, Outcome::Intermediate);
+    }
+
+    #[test]
+    fn configured_records_take_precedence() {
+        let mut config = Config::for_tests("ns.example.com");
+        let fixed = records::parse_static(
+            "@ MX 10 mail.example.com.; 10-0-0-1.ns.example.com A 192.0.2.1; \
+             lb.ns.example.com CNAME lb.example.net.",
+            &config.glue_name,
+        )
+        .unwrap();
+        config.records = records::Records::new(1).with_fixed(fixed);
+
+        let mx = handle_query_internal(&query_for("ns.example.com", 15), src(), &config);
+        assert_eq!(mx.outcome, Outcome::Static);
+        assert_eq!(mx.answers, vec!["10 mail.example.com."]);
+        let glue = handle_query_internal(&query_for("ns.example.com", 1), src(), &config);
+        assert_eq!(glue.outcome, Outcome::Glue);
+
+        // A static A replaces the synthesized one, other types still fall
+        // through to synthesis.
+        let a = handle_query_internal(&query_for("10-0-0-1.ns.example.com", 1), src(), &config);
+        assert_eq!(a.answers, vec!["192.0.2.1"]);
+        let aaaa = handle_query_internal(&query_for("10-0-0-1.ns.example.com", 28), src(), &config);
+        assert_eq!(aaaa.outcome, Outcome::Synthesized(Encoding::Dash));
+
+        let cname = handle_query_internal(&query_for("lb.ns.example.com", 16), src(), &config);
+        assert_eq!(cname.answers, vec!["lb.example.net."]);
+        let message = Message::parse(&cname.response).unwrap();
+        assert_eq!(message.answers[0].rtype, TYPE_CNAME);
+    }
+
+    #[test]
+    fn caa_is_served_at_the_apex_and_denied_below() {
+        let mut config = Config::for_tests("ns.example.com");
+        config.records = records::Records::new(1).with_caa(
+            records::parse_caa_records(
+                "0 issue \"letsencrypt.org\"; 0 iodef \"mailto:security@example.com\"",
+            )
+            .unwrap(),
+        );
+
+        let apex = handle_query_internal(&query_for("ns.example.com", TYPE_CAA), src(), &config);
+        assert_eq!(apex.outcome, Outcome::Caa);
+        assert_eq!(
+            apex.answers,
+            vec![
+                "0 issue \"letsencrypt.org\"",
+                "0 iodef \"mailto:security@example.com\""
+            ]
+        );
+
+        // Synthesized names have no CAA of their own, so the CA climbs to
+        // the apex.
+        let name = "app-10-0-0-1.ns.example.com";
+        let below = handle_query_internal(&query_for(name, TYPE_CAA), src(), &config);
+        let message = Message::parse(&below.response).unwrap();
+        assert!(message.answers.is_empty());
+        assert_eq!(message.authority[0].rtype, 6);
+        let a = handle_query_internal(&query_for(name, TYPE_A), src(), &config);
+        assert_eq!(a.answers, vec!["10.0.0.1"]);
+
+        // A static CAA at the apex takes precedence.
+        let fixed = records::parse_static("@ CAA 0 issue \"pki.goog\"", &config.glue_name);
+        config.records = records::Records::new(1).with_fixed(fixed.unwrap());
+        let apex = handle_query_internal(&query_for("ns.example.com", TYPE_CAA), src(), &config);
+        assert_eq!(apex.outcome, Outcome::Static);
+        assert_eq!(apex.answers, vec!["0 issue \"pki.goog\""]);
+    }
+
+    #[test]
+    fn chaos_queries_identify_the_server() {
+        let mut config = Config::for_tests("ns.example.com");
+        config.server_version = Some("RustyAlias".to_string());
+        config.server_id = Some("replica-1".to_string());
+        let chaos = |name: &str, qtype: u16, config: &Config| {
+            let mut query = query_for(name, qtype);
+            let class = query.len() - 1;
+            query[class] = CLASS_CH as u8;
+            handle_query_internal(&query, src(), config)
+        };
+
+        let version = chaos("VERSION.bind", TYPE_TXT, &config);
+        assert_eq!(version.outcome, Outcome::Chaos);
+        assert_eq!(version.answers, vec!["RustyAlias"]);
+        let message = Message::parse(&version.response).unwrap();
+        assert_eq!(message.answers[0].class, CLASS_CH);
+        assert_eq!(
+            chaos("hostname.bind", TYPE_TXT, &config).answers,
+            vec!["replica-1"]
+        );
+        assert!(chaos("id.server", TYPE_A, &config).answers.is_empty());
+        assert_eq!(
+            chaos("authors.bind", TYPE_TXT, &config).rcode(),
+            Some(RCODE_REFUSED)
+        );
+        // The version.bind name means nothing in class IN.
+        let internet = handle_query_internal(&query_for("version.bind", TYPE_TXT), src(), &config);
+        assert_eq!(internet.outcome, Outcome::OutOfZone);
+
+        // NSID is only sent when asked for.
+        let mut query = query_for("10-0-0-1.ns.example.com", TYPE_A);
+        query[11] = 1; // ARCOUNT
+        query.extend(&[
+            0x00, 0x00, 0x29, 0x04, 0xD0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04,
+        ]);
+        query.extend(&[0x00, 0x03, 0x00, 0x00]); // NSID, empty
+        let response = handle_query_internal(&query, src(), &config).response;
+        let nsid = edns::parse(&response).unwrap();
+        assert_eq!(nsid.option(OPTION_NSID), Some(&b"replica-1"[..]));
+        let response = handle_query_internal(&edns_query(&[0; 8], 0), src(), &config).response;
+        assert_eq!(edns::parse(&response).unwrap().option(OPTION_NSID), None);
+
+        config.server_version = None;
+        config.server_id = None;
+        assert_eq!(
+            chaos("version.bind", TYPE_TXT, &config).rcode(),
+            Some(RCODE_REFUSED)
+        );
+        assert_eq!(
+            chaos("id.server", TYPE_TXT, &config).rcode(),
+            Some(RCODE_REFUSED)
+        );
+        let hidden = handle_query_internal(&query_for("version", TYPE_TXT), src(), &config);
+        assert_eq!(hidden.outcome, Outcome::OutOfZone);
+        let response = handle_query_internal(&query, src(), &config).response;
+        assert_eq!(edns::parse(&response).unwrap().option(OPTION_NSID), None);
+    }
+
+    #[test]
+    fn cname_aliases_are_chased_through_the_zone() {
+        let mut config = Config::for_tests("lab.example.com");
+        let fixed = records::parse_static(
+            "db.lab.example.com 300 CNAME 10-0-0-5.lab.example.com.; \
+             primary.lab.example.com CNAME db.lab.example.com.; \
+             ping.lab.example.com CNAME pong.lab.example.com.; \
+             pong.lab.example.com CNAME ping.lab.example.com.",
+            &config.glue_name,
+        )
+        .unwrap();
+        config.records = records::Records::new(1).with_fixed(fixed);
+
+        let db = handle_query_internal(&query_for("db.lab.example.com", 1), src(), &config);
+        assert_eq!(db.answers, vec!["10-0-0-5.lab.example.com.", "10.0.0.5"]);
+        let message = Message::parse(&db.response).unwrap();
+        assert_eq!(message.answers[0].ttl, 300);
+        assert_eq!(message.answers[1].name, "10-0-0-5.lab.example.com");
+        assert_eq!(message.answers[1].ttl, ADDRESS_TTL);
+
+        let chain = handle_query_internal(&query_for("primary.lab.example.com", 1), src(), &config);
+        assert_eq!(
+            chain.answers,
+            vec![
+                "db.lab.example.com.",
+                "10-0-0-5.lab.example.com.",
+                "10.0.0.5"
+            ]
+        );
+
+        // No AAAA at the end of the chain: just the CNAME.
+        let aaaa = handle_query_internal(&query_for("db.lab.example.com", 28), src(), &config);
+        assert_eq!(aaaa.answers, vec!["10-0-0-5.lab.example.com."]);
+        let cname = handle_query_internal(&query_for("db.lab.example.com", 5), src(), &config);
+        assert_eq!(cname.answers, vec!["10-0-0-5.lab.example.com."]);
+
+        let looped = handle_query_internal(&query_for("ping.lab.example.com", 1), src(), &config);
+        assert_eq!(
+            looped.answers,
+            vec!["pong.lab.example.com.", "ping.lab.example.com."]
+        );
+    }
 }
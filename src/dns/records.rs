@@ -0,0 +1,850 @@
+use super::message::{encode_name, read_name, Record};
+use super::query::{is_in_zone, qtype_name};
+use super::response::{txt_rdata, Caa};
+use log::warn;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_SVCB: u16 = 64;
+pub const TYPE_HTTPS: u16 = 65;
+pub const TYPE_CAA: u16 = 257;
+
+/// Types with a presentation format here. Others can still be given in
+/// the RFC 3597 form, `TYPE99 \# 2 abcd`.
+pub const STATIC_TYPES: [u16; 8] = [
+    TYPE_A, TYPE_AAAA, TYPE_TXT, TYPE_CNAME, TYPE_MX, TYPE_SRV, TYPE_CAA, TYPE_PTR,
+];
+
+/// Types the server owns or that only make sense in transit: the apex SOA
+/// and NS, DNSSEC records, OPT, TSIG and the meta types.
+const RESERVED_TYPES: [u16; 12] = [2, 6, 41, 43, 46, 47, 48, 50, 250, 251, 252, 255];
+
+const CLASS_IN: u16 = 1;
+
+/// Static records served on top of the synthesized namespace, and the SOA
+/// serial that changes with them. Cheap to clone; every clone shares the
+/// same records.
+///
+/// There are two layers: fixed records from the configuration, and records
+/// changed at runtime by dynamic updates. A name with fixed records only
+/// ever answers with those; runtime records at the same name are ignored.
+/// The apex CAA records of `CAA_RECORDS` are kept here as well, so that
+/// replacing them bumps the serial like any other change.
+///
+/// With a file, every runtime change is written back to it, one record per
+/// line in zone file presentation format after a `$SERIAL` line.
+#[derive(Clone)]
+pub struct Records {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+struct State {
+    serial: u32,
+    fixed: Vec<Record>,
+    caa: Vec<Caa>,
+    records: Vec<Record>,
+}
+
+impl Records {
+    /// Records kept in memory only, starting empty at `serial`.
+    pub fn new(serial: u32) -> Self {
+        Self::with_state(None, serial, Vec::new())
+    }
+
+    /// Load records from `path`, or start empty if it doesn't exist yet.
+    /// The serial is the higher of `serial` and the one saved in the file,
+    /// so raising `SERIAL` still takes effect.
+    pub fn load(path: &Path, serial: u32) -> io::Result<Self> {
+        let (saved_serial, records) = read(path)?;
+        Ok(Self::with_state(
+            Some(path.to_path_buf()),
+            serial.max(saved_serial),
+            records,
+        ))
+    }
+
+    /// Replace the records with the file's contents, e.g. after editing it
+    /// by hand. If they differ, the serial moves past both the current and
+    /// the saved one. Returns the number of records loaded.
+    pub fn reload(&self) -> io::Result<usize> {
+        let Some(path) = &self.inner.path else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "records are not backed by a file",
+            ));
+        };
+        let (saved_serial, records) = read(path)?;
+        let mut state = self.state();
+        if state.records != records {
+            state.serial = saved_serial.max(state.serial.wrapping_add(1));
+            state.records = records;
+        }
+        Ok(state.records.len())
+    }
+
+    /// Serve `fixed` records ahead of the runtime ones. Meant to be chained
+    /// onto [`Records::new`] or [`Records::load`].
+    pub fn with_fixed(self, fixed: Vec<Record>) -> Self {
+        self.state().fixed = fixed;
+        self
+    }
+
+    /// Serve `caa` at the apex, unless other records there have CAA data.
+    pub fn with_caa(self, caa: Vec<Caa>) -> Self {
+        self.state().caa = caa;
+        self
+    }
+
+    /// Replace the fixed and CAA records, e.g. after the configuration is
+    /// reloaded. If they differ, the serial is bumped. Returns whether they
+    /// did.
+    pub fn replace_fixed(&self, fixed: Vec<Record>, caa: Vec<Caa>) -> bool {
+        let mut state = self.state();
+        if state.fixed == fixed && state.caa == caa {
+            return false;
+        }
+        state.fixed = fixed;
+        state.caa = caa;
+        self.bump(&mut state);
+        true
+    }
+
+    fn with_state(path: Option<PathBuf>, serial: u32, records: Vec<Record>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                path,
+                state: Mutex::new(State {
+                    serial,
+                    fixed: Vec::new(),
+                    caa: Vec::new(),
+                    records,
+                }),
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        match self.inner.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// The file the records are saved to, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.inner.path.as_deref()
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.state().serial
+    }
+
+    /// All records served: the fixed ones, then the runtime ones in the
+    /// order they were added.
+    pub fn all(&self) -> Vec<Record> {
+        let state = self.state();
+        let shadowed = |record: &Record| state.fixed.iter().any(|f| f.name == record.name);
+        let mut all = state.fixed.clone();
+        all.extend(
+            state
+                .records
+                .iter()
+                .filter(|record| !shadowed(record))
+                .cloned(),
+        );
+        all
+    }
+
+    /// The apex CAA records of `CAA_RECORDS`.
+    pub fn caa(&self) -> Vec<Caa> {
+        self.state().caa.clone()
+    }
+
+    /// Records owned by `name` (case-insensitive), of any type.
+    pub fn at(&self, name: &str) -> Vec<Record> {
+        let name = name.trim_end_matches('.');
+        let at_name = |records: &[Record]| -> Vec<Record> {
+            records
+                .iter()
+                .filter(|record| record.name.eq_ignore_ascii_case(name))
+                .cloned()
+                .collect()
+        };
+        let state = self.state();
+        let fixed = at_name(&state.fixed);
+        if !fixed.is_empty() {
+            return fixed;
+        }
+        at_name(&state.records)
+    }
+
+    /// Whether any record is owned by a name below `name`.
+    pub fn has_below(&self, name: &str) -> bool {
+        let suffix = format!(".{}", name.trim_end_matches('.').to_ascii_lowercase());
+        let state = self.state();
+        state
+            .fixed
+            .iter()
+            .chain(&state.records)
+            .any(|record| record.name.to_ascii_lowercase().ends_with(&suffix))
+    }
+
+    /// Apply `change` to a copy of the runtime records. If it returns `Ok(true)`
+    /// the copy replaces the records, the serial is bumped and the file is
+    /// rewritten; otherwise nothing changes. Changes are serialized, so
+    /// `change` sees the records no other update is modifying.
+    pub fn update<E>(
+        &self,
+        change: impl FnOnce(&mut Vec<Record>) -> Result<bool, E>,
+    ) -> Result<bool, E> {
+        let mut state = self.state();
+        let mut records = state.records.clone();
+        if !change(&mut records)? {
+            return Ok(false);
+        }
+        state.records = records;
+        self.bump(&mut state);
+        Ok(true)
+    }
+
+    /// Bump the serial after a change and save the records.
+    fn bump(&self, state: &mut State) {
+        // RFC 1982 serial arithmetic wraps.
+        state.serial = state.serial.wrapping_add(1);
+        if let Some(path) = &self.inner.path {
+            if let Err(e) = save(path, state) {
+                warn!("Failed to save records to {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+/// The serial and records saved in `path`; nothing if it doesn't exist.
+fn read(path: &Path) -> io::Result<(u32, Vec<Record>)> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut serial = 0;
+    let mut records = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        if let Some(value) = line.strip_prefix("$SERIAL") {
+            serial = value
+                .trim()
+                .parse()
+                .map_err(|_| invalid(format!("line {}: invalid $SERIAL", number + 1)))?;
+            continue;
+        }
+        let record =
+            parse_record(line).map_err(|e| invalid(format!("line {}: {e}", number + 1)))?;
+        records.push(record);
+    }
+    Ok((serial, records))
+}
+
+/// Write to a temporary file and rename it over `path`, so a crash never
+/// leaves a half-written file behind.
+fn save(path: &Path, state: &State) -> io::Result<()> {
+    let mut contents = format!("$SERIAL {}\n", state.serial);
+    for record in &state.records {
+        contents.push_str(&format_record(record));
+        contents.push('\n');
+    }
+    let temporary = path.with_extension("tmp");
+    let mut file = fs::File::create(&temporary)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+/// Parse a record in presentation format: `name [ttl] [IN] type data`,
+/// with an absolute owner name (the trailing dot is optional).
+pub fn parse_record(line: &str) -> Result<Record, String> {
+    let mut fields = line.split_whitespace();
+    let name = fields.next().ok_or("missing name")?;
+    let mut field = fields.next().ok_or("missing type")?;
+    let mut ttl = 3600;
+    if let Ok(value) = field.parse() {
+        ttl = value;
+        field = fields.next().ok_or("missing type")?;
+    }
+    if field.eq_ignore_ascii_case("IN") {
+        field = fields.next().ok_or("missing type")?;
+    }
+    let rtype = type_number(field).ok_or_else(|| format!("unsupported type {field:?}"))?;
+
+    // The data is the rest of the line after the type, verbatim, since TXT
+    // strings may contain spaces.
+    let type_end = field.as_ptr() as usize - line.as_ptr() as usize + field.len();
+    let data = line[type_end..].trim();
+    let rdata = parse_rdata(rtype, data)?;
+    Ok(Record {
+        name: name.trim_end_matches('.').to_ascii_lowercase(),
+        rtype,
+        class: CLASS_IN,
+        ttl,
+        rdata,
+    })
+}
+
+/// Parse the fixed records of `STATIC_RECORDS`: records in presentation
+/// format separated by newlines or `;`, with `@` as the owner standing for
+/// `origin`. Every name must be in the zone, and a name with a CNAME can't
+/// have other records.
+pub fn parse_static(value: &str, origin: &str) -> Result<Vec<Record>, String> {
+    let mut records: Vec<Record> = Vec::new();
+    for entry in split_entries(value) {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        add_static(&mut records, entry, origin).map_err(|e| format!("{entry}: {e}"))?;
+    }
+    Ok(records)
+}
+
+/// Add the `TXT_RECORDS` value to the fixed records: `<name> <text>`
+/// entries separated by newlines or `;`, e.g. `@ "v=spf1 -all"`. The text
+/// is a word or quoted strings of any length, and a name can have several.
+pub fn add_txt_records(records: &mut Vec<Record>, value: &str, origin: &str) -> Result<(), String> {
+    for entry in split_entries(value) {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let (name, text) = entry.split_once(char::is_whitespace).unwrap_or((entry, ""));
+        add_static(
+            records,
+            &format!("{name} TXT {}", text.trim_start()),
+            origin,
+        )
+        .map_err(|e| format!("{entry}: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Parse `line`, with `@` standing for `origin`, and add it to `records`.
+fn add_static(records: &mut Vec<Record>, line: &str, origin: &str) -> Result<(), String> {
+    let record = match line.strip_prefix('@') {
+        Some(rest) if rest.starts_with(char::is_whitespace) => {
+            parse_record(&format!("{origin}{rest}"))?
+        }
+        _ => parse_record(line)?,
+    };
+    if !is_in_zone(&record.name, origin) {
+        return Err("not in the zone".to_string());
+    }
+    if let Some(conflict) = apex_conflict(&record, origin) {
+        return Err(conflict.to_string());
+    }
+    let conflict = records.iter().any(|other| {
+        other.name == record.name && (other.rtype == TYPE_CNAME || record.rtype == TYPE_CNAME)
+    });
+    if conflict {
+        return Err("a CNAME can't coexist with other records".to_string());
+    }
+    records.push(record);
+    Ok(())
+}
+
+/// Why `record` can't be added, if it is at the apex `origin` and clashes
+/// with what the server serves there itself: the apex always has its SOA
+/// and NS, so it can't have a CNAME, and its A record is the glue. Types
+/// without a presentation format are kept off the apex altogether.
+pub fn apex_conflict(record: &Record, origin: &str) -> Option<&'static str> {
+    if !record
+        .name
+        .eq_ignore_ascii_case(origin.trim_end_matches('.'))
+    {
+        return None;
+    }
+    match record.rtype {
+        TYPE_CNAME => Some("a CNAME can't be at the apex"),
+        TYPE_A => Some("the apex A record is the glue address"),
+        rtype if !STATIC_TYPES.contains(&rtype) => Some("unsupported type at the apex"),
+        _ => None,
+    }
+}
+
+/// Parse the `CAA_RECORDS` value: CAA data such as `0 issue "ca.example"`,
+/// separated by newlines or `;`.
+pub fn parse_caa_records(value: &str) -> Result<Vec<Caa>, String> {
+    split_entries(value)
+        .into_iter()
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| parse_caa(entry).ok_or_else(|| format!("{entry}: invalid CAA record")))
+        .collect()
+}
+
+/// Split on newlines and on `;` outside of quoted strings.
+fn split_entries(value: &str) -> Vec<&str> {
+    let mut entries = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                entries.push(&value[start..i]);
+                start = i + 1;
+            }
+            '\n' => {
+                entries.push(&value[start..i]);
+                start = i + 1;
+                quoted = false;
+            }
+            _ => {}
+        }
+    }
+    entries.push(&value[start..]);
+    entries
+}
+
+pub fn format_record(record: &Record) -> String {
+    format!(
+        "{}. {} IN {} {}",
+        record.name,
+        record.ttl,
+        qtype_name(record.rtype),
+        rdata_text(record.rtype, &record.rdata)
+    )
+}
+
+fn type_number(name: &str) -> Option<u16> {
+    let rtype = match name.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("TYPE") => name[4..].parse().ok()?,
+        _ => STATIC_TYPES
+            .into_iter()
+            .find(|&rtype| qtype_name(rtype).eq_ignore_ascii_case(name))?,
+    };
+    (!RESERVED_TYPES.contains(&rtype)).then_some(rtype)
+}
+
+fn parse_rdata(rtype: u16, data: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("invalid {} data {data:?}", qtype_name(rtype));
+    if let Some(generic) = data.strip_prefix("\\#") {
+        return parse_generic(generic).ok_or_else(invalid);
+    }
+    let fields: Vec<&str> = data.split_whitespace().collect();
+    match rtype {
+        TYPE_A => Ok(data
+            .parse::<Ipv4Addr>()
+            .map_err(|_| invalid())?
+            .octets()
+            .to_vec()),
+        TYPE_AAAA => Ok(data
+            .parse::<Ipv6Addr>()
+            .map_err(|_| invalid())?
+            .octets()
+            .to_vec()),
+        TYPE_CNAME | TYPE_PTR => match fields[..] {
+            [target] => parse_name(target).ok_or_else(invalid),
+            _ => Err(invalid()),
+        },
+        TYPE_MX => match fields[..] {
+            [preference, exchange] => {
+                let mut rdata = preference
+                    .parse::<u16>()
+                    .map_err(|_| invalid())?
+                    .to_be_bytes()
+                    .to_vec();
+                rdata.extend(parse_name(exchange).ok_or_else(invalid)?);
+                Ok(rdata)
+            }
+            _ => Err(invalid()),
+        },
+        TYPE_SRV => match fields[..] {
+            [priority, weight, port, target] => {
+                let mut rdata = Vec::new();
+                for number in [priority, weight, port] {
+                    let number = number.parse::<u16>().map_err(|_| invalid())?;
+                    rdata.extend(number.to_be_bytes());
+                }
+                rdata.extend(parse_name(target).ok_or_else(invalid)?);
+                Ok(rdata)
+            }
+            _ => Err(invalid()),
+        },
+        TYPE_CAA => parse_caa(data).map(|caa| caa.rdata()).ok_or_else(invalid),
+        TYPE_TXT => parse_txt(data).ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+/// An absolute domain name in RDATA; `.` is the root (e.g. a null MX).
+fn parse_name(name: &str) -> Option<Vec<u8>> {
+    let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+    let labels_valid =
+        name.is_empty() || name.split('.').all(|label| (1..=63).contains(&label.len()));
+    (labels_valid && name.len() <= 253).then(|| encode_name(&name))
+}
+
+/// `flags tag value`, e.g. `0 issue "letsencrypt.org"` (RFC 8659).
+fn parse_caa(data: &str) -> Option<Caa> {
+    let (flags, rest) = data.split_once(char::is_whitespace)?;
+    let (tag, value) = rest.trim_start().split_once(char::is_whitespace)?;
+    let valid_tag = (1..=15).contains(&tag.len()) && tag.bytes().all(|b| b.is_ascii_alphanumeric());
+    if !valid_tag {
+        return None;
+    }
+    let value = value.trim();
+    let value = match value.strip_prefix('"') {
+        Some(quoted) => {
+            let mut unquoted = String::new();
+            let mut chars = quoted.chars();
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => unquoted.push(chars.next()?),
+                    c => unquoted.push(c),
+                }
+            }
+            if !chars.as_str().trim().is_empty() {
+                return None;
+            }
+            unquoted
+        }
+        None if !value.contains(char::is_whitespace) => value.to_string(),
+        None => return None,
+    };
+    Some(Caa {
+        flags: flags.parse().ok()?,
+        tag: tag.to_ascii_lowercase(),
+        value,
+    })
+}
+
+/// The RFC 3597 form after `\#`: the length, then the data in hex.
+fn parse_generic(data: &str) -> Option<Vec<u8>> {
+    let mut fields = data.split_whitespace();
+    let len: usize = fields.next()?.parse().ok()?;
+    let digits: String = fields.collect();
+    if digits.len() != len * 2 {
+        return None;
+    }
+    (0..len)
+        .map(|i| u8::from_str_radix(digits.get(i * 2..i * 2 + 2)?, 16).ok())
+        .collect()
+}
+
+/// Presentation format of RDATA, e.g. `192.0.2.1` or `"v=spf1 -all"`.
+pub fn rdata_text(rtype: u16, rdata: &[u8]) -> String {
+    match rtype {
+        TYPE_A => <[u8; 4]>::try_from(rdata)
+            .map_or_else(|_| hex(rdata), |octets| Ipv4Addr::from(octets).to_string()),
+        TYPE_AAAA => <[u8; 16]>::try_from(rdata)
+            .map_or_else(|_| hex(rdata), |octets| Ipv6Addr::from(octets).to_string()),
+        TYPE_CNAME | TYPE_PTR => name_text(rdata, 0).unwrap_or_else(|| hex(rdata)),
+        TYPE_MX if rdata.len() > 2 => name_text(rdata, 2).map_or_else(
+            || hex(rdata),
+            |exchange| format!("{} {exchange}", u16::from_be_bytes([rdata[0], rdata[1]])),
+        ),
+        TYPE_SRV if rdata.len() > 6 => name_text(rdata, 6).map_or_else(
+            || hex(rdata),
+            |target| {
+                let number = |i: usize| u16::from_be_bytes([rdata[i], rdata[i + 1]]);
+                format!("{} {} {} {target}", number(0), number(2), number(4))
+            },
+        ),
+        TYPE_SVCB | TYPE_HTTPS => svcb_text(rdata).unwrap_or_else(|| hex(rdata)),
+        TYPE_CAA if rdata.len() >= 2 && rdata.len() >= 2 + usize::from(rdata[1]) => {
+            let (tag, value) = rdata[2..].split_at(usize::from(rdata[1]));
+            format!(
+                "{} {} {}",
+                rdata[0],
+                String::from_utf8_lossy(tag),
+                quote(value)
+            )
+        }
+        TYPE_TXT => {
+            let mut strings = Vec::new();
+            let mut rest = rdata;
+            while let Some((&len, tail)) = rest.split_first() {
+                let (string, tail) = tail.split_at(usize::from(len).min(tail.len()));
+                strings.push(quote(string));
+                rest = tail;
+            }
+            strings.join(" ")
+        }
+        _ => hex(rdata),
+    }
+}
+
+/// SVCB and HTTPS RDATA, e.g. `1 . alpn=h2 ipv4hint=192.0.2.1`. Keys
+/// without a presentation format here are shown as `keyN` in hex.
+fn svcb_text(rdata: &[u8]) -> Option<String> {
+    let priority = u16::from_be_bytes([*rdata.first()?, *rdata.get(1)?]);
+    let (target, mut pos) = read_name(rdata, 2)?;
+    let mut text = format!("{priority} {target}.");
+    if target.is_empty() {
+        text = format!("{priority} .");
+    }
+    while pos < rdata.len() {
+        let key = u16::from_be_bytes([rdata[pos], *rdata.get(pos + 1)?]);
+        let len = usize::from(u16::from_be_bytes([
+            *rdata.get(pos + 2)?,
+            *rdata.get(pos + 3)?,
+        ]));
+        let value = rdata.get(pos + 4..pos + 4 + len)?;
+        pos += 4 + len;
+        let param = match key {
+            1 => {
+                let mut ids = Vec::new();
+                let mut rest = value;
+                while let Some((&len, tail)) = rest.split_first() {
+                    let id = tail.get(..usize::from(len))?;
+                    ids.push(String::from_utf8_lossy(id).into_owned());
+                    rest = &tail[usize::from(len)..];
+                }
+                format!("alpn={}", ids.join(","))
+            }
+            3 => format!(
+                "port={}",
+                u16::from_be_bytes(<[u8; 2]>::try_from(value).ok()?)
+            ),
+            4 if len % 4 == 0 => {
+                let hints: Vec<String> = value
+                    .chunks(4)
+                    .map(|octets| {
+                        Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).to_string()
+                    })
+                    .collect();
+                format!("ipv4hint={}", hints.join(","))
+            }
+            6 if len % 16 == 0 => {
+                let hints: Vec<String> = value
+                    .chunks(16)
+                    .map(|octets| Ipv6Addr::from(<[u8; 16]>::try_from(octets).unwrap()).to_string())
+                    .collect();
+                format!("ipv6hint={}", hints.join(","))
+            }
+            _ => {
+                let digits: String = value.iter().map(|byte| format!("{byte:02x}")).collect();
+                format!("key{key}={digits}")
+            }
+        };
+        text.push(' ');
+        text.push_str(&param);
+    }
+    Some(text)
+}
+
+/// The name at `pos` that ends the RDATA, with a trailing dot.
+fn name_text(rdata: &[u8], pos: usize) -> Option<String> {
+    let (name, end) = read_name(rdata, pos)?;
+    (end == rdata.len()).then(|| name + ".")
+}
+
+/// TXT data: one or more quoted strings, or a single unquoted word. Strings
+/// longer than 255 bytes are split into as many character-strings as they
+/// need; only RDATA longer than 65535 bytes is rejected.
+fn parse_txt(data: &str) -> Option<Vec<u8>> {
+    let mut rdata = Vec::new();
+    let mut chars = data.trim().chars().peekable();
+    if chars.peek() != Some(&'"') {
+        if data.is_empty() || data.contains(char::is_whitespace) {
+            return None;
+        }
+        return Some(txt_rdata(data));
+    }
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => string.push(chars.next()?),
+                        c => string.push(c),
+                    }
+                }
+                rdata.extend(txt_rdata(&string));
+            }
+            c if c.is_whitespace() => {}
+            _ => return None,
+        }
+    }
+    (rdata.len() <= usize::from(u16::MAX)).then_some(rdata)
+}
+
+fn quote(string: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for c in String::from_utf8_lossy(string).chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// RFC 3597 generic RDATA, for anything without a presentation format here.
+fn hex(rdata: &[u8]) -> String {
+    let digits: String = rdata.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("\\# {} {digits}", rdata.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip_through_presentation_format() {
+        for line in [
+            "www.ns.example.com. 300 IN A 192.0.2.1",
+            "www.ns.example.com. 300 IN AAAA 2001:db8::1",
+            "alias.ns.example.com. 60 IN CNAME www.ns.example.com.",
+            "txt.ns.example.com. 3600 IN TXT \"v=spf1 -all\" \"say \\\"hi\\\"\"",
+            "ns.example.com. 3600 IN MX 10 mail.example.com.",
+            "ns.example.com. 3600 IN MX 0 .",
+            "_sip._tcp.ns.example.com. 3600 IN SRV 10 5 5060 sip.example.com.",
+            "ns.example.com. 3600 IN CAA 0 issue \"letsencrypt.org\"",
+            "1.0.0.10.ns.example.com. 3600 IN PTR host.example.com.",
+            "x.ns.example.com. 3600 IN TYPE99 \\# 2 abcd",
+        ] {
+            assert_eq!(format_record(&parse_record(line).unwrap()), line);
+        }
+
+        let short = parse_record("Short.NS.example.com TXT hello").unwrap();
+        assert_eq!(short.name, "short.ns.example.com");
+        assert_eq!(short.ttl, 3600);
+        assert_eq!(short.rdata, b"\x05hello");
+
+        assert!(parse_record("a.example.com 60 IN NS ns.example.com").is_err());
+        assert!(parse_record("a.example.com 60 IN TYPE6 \\# 0").is_err());
+        assert!(parse_record("a.example.com 60 IN MX mail.example.com").is_err());
+        assert!(parse_record("a.example.com 60 IN TYPE99 \\# 2 ab").is_err());
+        assert!(parse_record("a.example.com 60 IN A 2001:db8::1").is_err());
+
+        // Long strings are split, since each is at most 255 bytes.
+        let long = parse_record(&format!("a.example.com TXT \"{}\"", "x".repeat(300))).unwrap();
+        assert_eq!(long.rdata.len(), 302);
+        assert_eq!((long.rdata[0], long.rdata[256]), (255, 45));
+    }
+
+    #[test]
+    fn updates_bump_the_serial_and_persist() {
+        let path = std::env::temp_dir().join(format!("rustyalias-records-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let records = Records::load(&path, 7).unwrap();
+        let changed = records.update(|records| {
+            records.push(parse_record("www.ns.example.com 60 A 192.0.2.1")?);
+            Ok::<_, String>(true)
+        });
+        assert_eq!(changed, Ok(true));
+        assert_eq!(records.serial(), 8);
+        assert_eq!(records.update(|_| Ok::<_, String>(false)), Ok(false));
+        assert_eq!(records.serial(), 8);
+
+        let reloaded = Records::load(&path, 1).unwrap();
+        assert_eq!(reloaded.serial(), 8);
+        assert_eq!(reloaded.at("WWW.ns.example.com."), records.all());
+        assert_eq!(Records::load(&path, 100).unwrap().serial(), 100);
+
+        fs::write(&path, "$SERIAL 3\nwww.ns.example.com A 192.0.2.2\n").unwrap();
+        assert_eq!(records.reload().unwrap(), 1);
+        assert_eq!(records.serial(), 9);
+        assert_eq!(records.at("www.ns.example.com")[0].rdata, [192, 0, 2, 2]);
+        assert_eq!(records.reload().unwrap(), 1);
+        assert_eq!(records.serial(), 9);
+        assert!(Records::new(1).reload().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fixed_records_shadow_runtime_ones() {
+        let fixed = parse_static(
+            "@ MX 10 mail.example.com.; www.ns.example.com. 60 CNAME lb.example.net.\n\
+             txt.ns.example.com TXT \"a;b\"",
+            "ns.example.com",
+        )
+        .unwrap();
+        assert_eq!(fixed.len(), 3);
+        assert_eq!(fixed[0].name, "ns.example.com");
+        assert_eq!(fixed[2].rdata, b"\x03a;b");
+
+        let records = Records::new(1).with_fixed(fixed);
+        records
+            .update(|records| {
+                records.push(parse_record("www.ns.example.com A 192.0.2.1")?);
+                records.push(parse_record("app.ns.example.com A 192.0.2.2")?);
+                Ok::<_, String>(true)
+            })
+            .unwrap();
+        let www = records.at("WWW.ns.example.com");
+        assert_eq!(www.len(), 1);
+        assert_eq!(www[0].rtype, TYPE_CNAME);
+        assert_eq!(records.at("app.ns.example.com").len(), 1);
+        assert_eq!(records.all().len(), 4);
+
+        assert!(parse_static("www.example.org A 192.0.2.1", "ns.example.com").is_err());
+        assert!(parse_static(
+            "www.ns.example.com CNAME a.example.org; www.ns.example.com TXT hi",
+            "ns.example.com"
+        )
+        .is_err());
+        assert_eq!(parse_static("", "ns.example.com"), Ok(Vec::new()));
+
+        // The apex keeps its SOA, NS and glue.
+        for apex in ["@ CNAME lb.example.net.", "NS.example.com. A 192.0.2.1"] {
+            assert!(parse_static(apex, "ns.example.com").is_err());
+        }
+    }
+
+    #[test]
+    fn txt_records_are_added_by_name() {
+        let mut records =
+            parse_static("www.ns.example.com CNAME lb.example.net.", "ns.example.com").unwrap();
+        let dkim = format!("\"v=DKIM1; k=rsa; p={}\"", "A".repeat(400));
+        add_txt_records(
+            &mut records,
+            &format!(
+                "@ \"v=spf1 -all\"; @ google-site-verification=abc\n\
+                 default._domainkey.ns.example.com {dkim}"
+            ),
+            "ns.example.com",
+        )
+        .unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(rdata_text(TYPE_TXT, &records[1].rdata), "\"v=spf1 -all\"");
+        assert_eq!(records[2].name, "ns.example.com");
+        assert_eq!(records[3].rdata.len(), 2 + "v=DKIM1; k=rsa; p=".len() + 400);
+
+        let error = add_txt_records(&mut records, "www.ns.example.com hi", "ns.example.com");
+        assert_eq!(
+            error,
+            Err("www.ns.example.com hi: a CNAME can't coexist with other records".to_string())
+        );
+        assert!(add_txt_records(&mut records, "@", "ns.example.com").is_err());
+    }
+}
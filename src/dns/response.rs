@@ -1,6 +1,10 @@
+use super::message::{encode_name, Record};
 use log::debug;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
+/// TTL of synthesized A and AAAA records.
+pub const ADDRESS_TTL: u32 = 60;
+
 pub fn build_response(
     query: &[u8],
     glue: Option<(&str, Ipv4Addr)>,
@@ -71,7 +75,12 @@ pub fn build_response(
     response
 }
 
+/// TTL of the SOA record in negative responses.
+pub const SOA_TTL: u32 = 3600;
+
 pub struct SoaParams<'a> {
+    /// Owner of the SOA record: the zone apex.
+    pub zone: &'a str,
     pub soa_name: &'a str,
     pub hostmaster: &'a str,
     pub serial: u32,
@@ -94,11 +103,20 @@ pub fn build_soa_response(query: &[u8], params: &SoaParams) -> Vec<u8> {
     response.extend(&query[12..question_end]); // Original Question
 
     // SOA Record
-    response.extend(&[0xc0, 0x0c]); // Name pointer to the original question
+    response.extend(encode_domain_name(params.zone)); // Name: the zone apex
     response.extend(&[0x00, 0x06]); // Type: SOA
     response.extend(&[0x00, 0x01]); // Class: IN
-    response.extend(&[0x00, 0x00, 0x0e, 0x10]); // TTL: 3600 seconds
+    response.extend(&SOA_TTL.to_be_bytes()); // TTL
+
+    let rdata = soa_rdata(params);
+    response.extend(&(rdata.len() as u16).to_be_bytes()); // RDLENGTH
+    response.extend(rdata);
 
+    debug!("Built SOA response: {response:?}");
+    response
+}
+
+pub fn soa_rdata(params: &SoaParams) -> Vec<u8> {
     let mut rdata = Vec::new();
     rdata.extend(encode_domain_name(params.soa_name));
     rdata.extend(encode_domain_name(params.hostmaster));
@@ -107,12 +125,7 @@ pub fn build_soa_response(query: &[u8], params: &SoaParams) -> Vec<u8> {
     rdata.extend(&params.retry.to_be_bytes());
     rdata.extend(&params.expire.to_be_bytes());
     rdata.extend(&params.minimum.to_be_bytes());
-
-    response.extend(&(rdata.len() as u16).to_be_bytes()); // RDLENGTH
-    response.extend(rdata);
-
-    debug!("Built SOA response: {response:?}");
-    response
+    rdata
 }
 
 pub fn encode_domain_name(name: &str) -> Vec<u8> {
@@ -126,6 +139,14 @@ pub fn encode_domain_name(name: &str) -> Vec<u8> {
 }
 
 pub fn build_refused_response(query: &[u8]) -> Vec<u8> {
+    let response = build_error_response(query, 5);
+    debug!("Built REFUSED response: {response:?}");
+    response
+}
+
+/// Header and echoed question with `rcode` and no records. Only the low four
+/// bits of `rcode` fit in the header; extended RCODEs also need an OPT record.
+pub fn build_error_response(query: &[u8], rcode: u8) -> Vec<u8> {
     let mut response: Vec<u8> = Vec::with_capacity(query.len());
 
     response.extend(&query[0..2]); // ID
@@ -133,8 +154,8 @@ pub fn build_refused_response(query: &[u8]) -> Vec<u8> {
     // Flags byte 1: QR=1 (response), OPCODE=0, AA=0, TC=0, RD=echoed from query.
     let rd = query.get(2).copied().unwrap_or(0) & 0x01;
     response.push(0x80 | rd);
-    // Flags byte 2: RA=0, Z=0, RCODE=5 (REFUSED).
-    response.push(0x05);
+    // Flags byte 2: RA=0, Z=0, RCODE.
+    response.push(rcode & 0x0F);
 
     response.extend(&query[4..6]); // QDCOUNT
     response.extend(&[0x00, 0x00]); // ANCOUNT
@@ -151,33 +172,175 @@ pub fn build_refused_response(query: &[u8]) -> Vec<u8> {
         }
     }
 
-    debug!("Built REFUSED response: {response:?}");
     response
 }
 
-pub fn build_txt_response(query: &[u8], txt_data: &str) -> Vec<u8> {
+/// Header and question only, with TC=1, telling the client to retry over
+/// TCP. Used instead of a real answer when response rate limiting "slips".
+pub fn build_truncated_response(query: &[u8]) -> Vec<u8> {
+    let mut response = build_refused_response(query);
+
+    // Flags byte 1: QR=1, AA=1, TC=1, RD echoed. Flags byte 2: RCODE=0.
+    response[2] |= 0x06;
+    response[3] = 0x00;
+
+    debug!("Built truncated response: {response:?}");
+    response
+}
+
+/// An authoritative answer with one RRset of `rtype` at the question name,
+/// one record per entry of `rdatas`.
+pub fn build_answer_response(query: &[u8], rtype: u16, ttl: u32, rdatas: &[Vec<u8>]) -> Vec<u8> {
     let mut response: Vec<u8> = Vec::with_capacity(512);
     response.extend(&query[0..2]); // ID
     response.extend(&[0x81, 0x80]); // Flags: response, authoritative
     response.extend(&query[4..6]); // QDCOUNT
-    response.extend(&[0x00, 0x01]); // ANCOUNT
+    response.extend(&(rdatas.len() as u16).to_be_bytes()); // ANCOUNT
     response.extend(&[0x00, 0x00]); // NSCOUNT
     response.extend(&[0x00, 0x00]); // ARCOUNT
 
     let question_end = 12 + query[12..].iter().position(|&x| x == 0).unwrap() + 5;
     response.extend(&query[12..question_end]); // Original question
 
-    // TXT Record
+    for rdata in rdatas {
+        response.extend(&[0xC0, 0x0C]); // Pointer to the domain name
+        response.extend(&rtype.to_be_bytes());
+        response.extend(&[0x00, 0x01]); // Class IN
+        response.extend(&ttl.to_be_bytes());
+        response.extend(&(rdata.len() as u16).to_be_bytes()); // RDLENGTH
+        response.extend(rdata);
+    }
+
+    debug!("Built answer response: {response:?}");
+    response
+}
+
+/// An authoritative answer with a CNAME from the question name to `target`,
+/// followed by `chased`: the records found by following it, each with its
+/// own owner name (further CNAMEs, then the RRset asked for, if any).
+pub fn build_cname_response(query: &[u8], ttl: u32, target: &str, chased: &[Record]) -> Vec<u8> {
+    let mut response: Vec<u8> = Vec::with_capacity(512);
+    response.extend(&query[0..2]); // ID
+    response.extend(&[0x81, 0x80]); // Flags: response, authoritative
+    response.extend(&query[4..6]); // QDCOUNT
+    response.extend(&(1 + chased.len() as u16).to_be_bytes()); // ANCOUNT
+    response.extend(&[0x00, 0x00]); // NSCOUNT
+    response.extend(&[0x00, 0x00]); // ARCOUNT
+
+    let question_end = 12 + query[12..].iter().position(|&x| x == 0).unwrap() + 5;
+    response.extend(&query[12..question_end]); // Original question
+
+    let target = encode_name(target);
     response.extend(&[0xC0, 0x0C]); // Pointer to the domain name
-    response.extend(&[0x00, 0x10]); // Type TXT
+    response.extend(&[0x00, 0x05]); // Type CNAME
     response.extend(&[0x00, 0x01]); // Class IN
-    response.extend(&[0x00, 0x00, 0x00, 0x3C]); // TTL: 60 seconds
+    response.extend(&ttl.to_be_bytes());
+    response.extend(&(target.len() as u16).to_be_bytes()); // RDLENGTH
+    response.extend(target);
+
+    for record in chased {
+        response.extend(encode_name(&record.name));
+        response.extend(&record.rtype.to_be_bytes());
+        response.extend(&record.class.to_be_bytes());
+        response.extend(&record.ttl.to_be_bytes());
+        response.extend(&(record.rdata.len() as u16).to_be_bytes()); // RDLENGTH
+        response.extend(&record.rdata);
+    }
+
+    debug!("Built CNAME response: {response:?}");
+    response
+}
+
+/// TTL of the apex CAA records.
+pub const CAA_TTL: u32 = 3600;
+
+/// A CAA record (RFC 8659), e.g. `0 issue "letsencrypt.org"`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Caa {
+    pub flags: u8,
+    pub tag: String,
+    pub value: String,
+}
+
+impl Caa {
+    pub fn rdata(&self) -> Vec<u8> {
+        let mut rdata = vec![self.flags, self.tag.len() as u8];
+        rdata.extend(self.tag.as_bytes());
+        rdata.extend(self.value.as_bytes());
+        rdata
+    }
+}
+
+/// An authoritative answer with the CAA RRset `records` at the question
+/// name.
+pub fn build_caa_response(query: &[u8], records: &[Caa]) -> Vec<u8> {
+    let mut response: Vec<u8> = Vec::with_capacity(512);
+    response.extend(&query[0..2]); // ID
+    response.extend(&[0x81, 0x80]); // Flags: response, authoritative
+    response.extend(&query[4..6]); // QDCOUNT
+    response.extend(&(records.len() as u16).to_be_bytes()); // ANCOUNT
+    response.extend(&[0x00, 0x00]); // NSCOUNT
+    response.extend(&[0x00, 0x00]); // ARCOUNT
+
+    let question_end = 12 + query[12..].iter().position(|&x| x == 0).unwrap() + 5;
+    response.extend(&query[12..question_end]); // Original question
+
+    for caa in records {
+        let rdata = caa.rdata();
+        response.extend(&[0xC0, 0x0C]); // Pointer to the domain name
+        response.extend(&[0x01, 0x01]); // Type CAA
+        response.extend(&[0x00, 0x01]); // Class IN
+        response.extend(&CAA_TTL.to_be_bytes());
+        response.extend(&(rdata.len() as u16).to_be_bytes()); // RDLENGTH
+        response.extend(rdata);
+    }
+
+    debug!("Built CAA response: {response:?}");
+    response
+}
+
+/// TTL of the version and server identification TXT records.
+pub const TXT_TTL: u32 = 60;
 
-    // TXT data format: <length><string>
-    let txt_bytes = txt_data.as_bytes();
-    response.extend(&(txt_bytes.len() as u16 + 1).to_be_bytes()); // RDLENGTH
-    response.push(txt_bytes.len() as u8); // Length of the string
-    response.extend(txt_bytes); // The actual string
+/// TXT RDATA holding `value`, split into as many character-strings as its
+/// length needs, since each is at most 255 bytes (RFC 1035 §3.3.14).
+pub fn txt_rdata(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    if bytes.is_empty() {
+        return vec![0];
+    }
+    let mut rdata = Vec::with_capacity(bytes.len() + bytes.len() / 255 + 1);
+    for string in bytes.chunks(255) {
+        rdata.push(string.len() as u8);
+        rdata.extend(string);
+    }
+    rdata
+}
+
+/// An authoritative answer with one TXT record per entry of `values`, in
+/// the class of the question (IN, or CH for server identification).
+pub fn build_txt_response(query: &[u8], ttl: u32, values: &[String]) -> Vec<u8> {
+    let mut response: Vec<u8> = Vec::with_capacity(512);
+    response.extend(&query[0..2]); // ID
+    response.extend(&[0x81, 0x80]); // Flags: response, authoritative
+    response.extend(&query[4..6]); // QDCOUNT
+    response.extend(&(values.len() as u16).to_be_bytes()); // ANCOUNT
+    response.extend(&[0x00, 0x00]); // NSCOUNT
+    response.extend(&[0x00, 0x00]); // ARCOUNT
+
+    let question_end = 12 + query[12..].iter().position(|&x| x == 0).unwrap() + 5;
+    response.extend(&query[12..question_end]); // Original question
+
+    for value in values {
+        // TXT data format: <length><string>, repeated
+        let rdata = txt_rdata(value);
+        response.extend(&[0xC0, 0x0C]); // Pointer to the domain name
+        response.extend(&[0x00, 0x10]); // Type TXT
+        response.extend(&query[question_end - 2..question_end]); // Class, as asked
+        response.extend(&ttl.to_be_bytes());
+        response.extend(&(rdata.len() as u16).to_be_bytes()); // RDLENGTH
+        response.extend(rdata);
+    }
 
     debug!("Built TXT response: {response:?}");
     response
@@ -0,0 +1,318 @@
+use super::ip_parser::{interpret_ip_encoding, Encoding};
+use super::message::{encode_name, Message, Record};
+use super::query::qtype_number;
+use super::records::{TYPE_A, TYPE_AAAA, TYPE_HTTPS, TYPE_MX, TYPE_SRV, TYPE_SVCB};
+use super::response::{build_answer_response, ADDRESS_TTL};
+use crate::config::Config;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Types that can be synthesized for encoded names (`SYNTHESIZE_TYPES`).
+const SYNTHESIZABLE: [u16; 4] = [TYPE_MX, TYPE_SRV, TYPE_SVCB, TYPE_HTTPS];
+
+/// Preference of the synthesized MX record.
+const MX_PREFERENCE: u16 = 10;
+
+/// SvcParamKeys (RFC 9460 §14.3.2).
+const KEY_ALPN: u16 = 1;
+const KEY_PORT: u16 = 3;
+const KEY_IPV4HINT: u16 = 4;
+const KEY_IPV6HINT: u16 = 6;
+
+/// Ports of the services an SRV name can use instead of a port number,
+/// e.g. `_imaps._tcp` rather than `_993._tcp`.
+const SERVICES: [(&str, u16); 16] = [
+    ("smtp", 25),
+    ("http", 80),
+    ("kerberos", 88),
+    ("pop3", 110),
+    ("imap", 143),
+    ("ldap", 389),
+    ("https", 443),
+    ("submissions", 465),
+    ("submission", 587),
+    ("ldaps", 636),
+    ("imaps", 993),
+    ("pop3s", 995),
+    ("sip", 5060),
+    ("sips", 5061),
+    ("xmpp-client", 5222),
+    ("xmpp-server", 5269),
+];
+
+/// Parse the `SYNTHESIZE_TYPES` value, e.g. `MX,SRV,HTTPS`.
+pub fn parse_types(value: &str) -> Result<Vec<u16>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            qtype_number(name)
+                .filter(|rtype| SYNTHESIZABLE.contains(rtype))
+                .ok_or_else(|| format!("can't synthesize {name:?}"))
+        })
+        .collect()
+}
+
+/// Parse the `SYNTHESIZE_ALPN` value, e.g. `h2,http/1.1`. Protocol ids are
+/// at most 255 bytes, the most their length byte can count.
+pub fn parse_alpn(value: &str) -> Result<Vec<String>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            if id.len() > 255 {
+                return Err(format!("protocol id longer than 255 bytes: {id:?}"));
+            }
+            Ok(id.to_string())
+        })
+        .collect()
+}
+
+/// Answer `qtype` at an encoded name with a synthesized service record,
+/// if that type is enabled:
+///
+/// - MX at `<host>`: the host itself, preference 10.
+/// - SRV at `_<service>._<proto>.<host>`: the host, on the port given as a
+///   number (`_8080._tcp`) or a well-known service name (`_imaps._tcp`).
+/// - HTTPS and SVCB at `<host>`, or at `_<port>._<scheme>.<host>` for
+///   another port: the host with the `SYNTHESIZE_ALPN` protocols and its
+///   address as a hint.
+///
+/// The host's address records go in the additional section, so clients
+/// don't need another query. Returns the response and how the host was
+/// encoded.
+pub fn answer(
+    query: &[u8],
+    name: &str,
+    qtype: u16,
+    config: &Config,
+) -> Option<(Vec<u8>, Encoding)> {
+    let Synthesis {
+        rdata,
+        host,
+        encoding,
+        addresses: (v4, v6),
+    } = synthesize(name, qtype, config)?;
+
+    let mut message = Message::parse(&build_answer_response(query, qtype, ADDRESS_TTL, &[rdata]))?;
+    let address = |rtype: u16, rdata: Vec<u8>| Record {
+        name: host.to_ascii_lowercase(),
+        rtype,
+        class: 1,
+        ttl: ADDRESS_TTL,
+        rdata,
+    };
+    message
+        .additional
+        .extend(v4.map(|v4| address(TYPE_A, v4.octets().to_vec())));
+    message
+        .additional
+        .extend(v6.map(|v6| address(TYPE_AAAA, v6.octets().to_vec())));
+    Some((message.encode(), encoding))
+}
+
+/// The service types synthesized at `name`, for denials of existence.
+pub fn types_at(name: &str, config: &Config) -> Vec<u16> {
+    config
+        .synthesize_types
+        .iter()
+        .copied()
+        .filter(|&qtype| synthesize(name, qtype, config).is_some())
+        .collect()
+}
+
+/// Whether `name` is the `_<proto>.<host>` parent of synthesized service
+/// names, which exists without records of its own.
+pub fn is_empty_non_terminal(name: &str, config: &Config) -> bool {
+    let Some((proto, host)) = name.split_once('.') else {
+        return false;
+    };
+    proto.len() > 1
+        && proto.starts_with('_')
+        && !host.starts_with('_')
+        && config
+            .synthesize_types
+            .iter()
+            .any(|qtype| [TYPE_SRV, TYPE_SVCB, TYPE_HTTPS].contains(qtype))
+        && interpret_ip_encoding(host).is_some()
+}
+
+/// A synthesized service record and the host it points at.
+struct Synthesis<'a> {
+    rdata: Vec<u8>,
+    host: &'a str,
+    encoding: Encoding,
+    addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+}
+
+fn synthesize<'a>(name: &'a str, qtype: u16, config: &Config) -> Option<Synthesis<'a>> {
+    if !config.synthesize_types.contains(&qtype) {
+        return None;
+    }
+    let (port, host) = match split_service(name) {
+        Some((service, host)) => (Some(port(service, qtype)?), host),
+        None => (None, name),
+    };
+    if host.starts_with('_') {
+        return None;
+    }
+    let (encoding, (v4, v6)) = interpret_ip_encoding(host)?;
+
+    let rdata = match (qtype, port) {
+        (TYPE_MX, None) => {
+            let mut rdata = MX_PREFERENCE.to_be_bytes().to_vec();
+            rdata.extend(encode_name(host));
+            rdata
+        }
+        (TYPE_SRV, Some(port)) => {
+            let mut rdata = vec![0, 0, 0, 0]; // Priority, weight
+            rdata.extend(port.to_be_bytes());
+            rdata.extend(encode_name(host));
+            rdata
+        }
+        (TYPE_HTTPS | TYPE_SVCB, port) => {
+            // ServiceMode, targeting the owner itself unless a port prefix
+            // sets it apart.
+            let mut rdata = 1u16.to_be_bytes().to_vec();
+            rdata.extend(encode_name(if port.is_some() { host } else { "" }));
+            let alpn: Vec<u8> = config
+                .synthesize_alpn
+                .iter()
+                .flat_map(|id| std::iter::once(id.len() as u8).chain(id.bytes()))
+                .collect();
+            if !alpn.is_empty() {
+                push_param(&mut rdata, KEY_ALPN, &alpn);
+            }
+            if let Some(port) = port {
+                push_param(&mut rdata, KEY_PORT, &port.to_be_bytes());
+            }
+            if let Some(v4) = v4 {
+                push_param(&mut rdata, KEY_IPV4HINT, &v4.octets());
+            }
+            if let Some(v6) = v6 {
+                push_param(&mut rdata, KEY_IPV6HINT, &v6.octets());
+            }
+            rdata
+        }
+        _ => return None,
+    };
+    Some(Synthesis {
+        rdata,
+        host,
+        encoding,
+        addresses: (v4, v6),
+    })
+}
+
+/// `_<service>._<proto>.<host>` split into the service, without its
+/// underscore, and the host.
+fn split_service(name: &str) -> Option<(&str, &str)> {
+    let (service, rest) = name.split_once('.')?;
+    let (proto, host) = rest.split_once('.')?;
+    proto.strip_prefix('_').filter(|proto| !proto.is_empty())?;
+    Some((service.strip_prefix('_')?, host))
+}
+
+/// The port a service label stands for. HTTPS and SVCB names take a port
+/// number only (RFC 9460 §2.3).
+fn port(service: &str, qtype: u16) -> Option<u16> {
+    if let Ok(port) = service.parse() {
+        return Some(port);
+    }
+    let named = SERVICES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(service))
+        .map(|&(_, port)| port);
+    named.filter(|_| qtype == TYPE_SRV)
+}
+
+fn push_param(rdata: &mut Vec<u8>, key: u16, value: &[u8]) {
+    rdata.extend(key.to_be_bytes());
+    rdata.extend((value.len() as u16).to_be_bytes());
+    rdata.extend(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::records::rdata_text;
+    use crate::dns::response::encode_domain_name;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        query.extend(encode_domain_name(name));
+        query.extend(&qtype.to_be_bytes());
+        query.extend(&[0x00, 0x01]);
+        query
+    }
+
+    fn config() -> Config {
+        let mut config = Config::for_tests("ns.example.com");
+        config.synthesize_types = SYNTHESIZABLE.to_vec();
+        config.synthesize_alpn = vec!["h2".to_string(), "http/1.1".to_string()];
+        config
+    }
+
+    fn synthesize(name: &str, qtype: u16, config: &Config) -> Option<Message> {
+        let (response, _) = answer(&query(name, qtype), name, qtype, config)?;
+        Message::parse(&response)
+    }
+
+    #[test]
+    fn mx_and_srv_point_at_the_host() {
+        let config = config();
+        let mx = synthesize("mail.10-0-0-1.ns.example.com", TYPE_MX, &config).unwrap();
+        assert_eq!(
+            rdata_text(TYPE_MX, &mx.answers[0].rdata),
+            "10 mail.10-0-0-1.ns.example.com."
+        );
+        assert_eq!(mx.additional[0].name, "mail.10-0-0-1.ns.example.com");
+        assert_eq!(mx.additional[0].rdata, [10, 0, 0, 1]);
+
+        for (name, port) in [
+            ("_8080._tcp.app-10-0-0-1.ns.example.com", 8080),
+            ("_IMAPS._tcp.app-10-0-0-1.ns.example.com", 993),
+        ] {
+            let srv = synthesize(name, TYPE_SRV, &config).unwrap();
+            assert_eq!(
+                rdata_text(TYPE_SRV, &srv.answers[0].rdata),
+                format!("0 0 {port} app-10-0-0-1.ns.example.com.")
+            );
+        }
+        let unknown = "_nope._tcp.app-10-0-0-1.ns.example.com";
+        assert!(synthesize(unknown, TYPE_SRV, &config).is_none());
+        assert!(synthesize("app-10-0-0-1.ns.example.com", TYPE_SRV, &config).is_none());
+        assert!(synthesize("_8080._tcp.ns.example.com", TYPE_SRV, &config).is_none());
+    }
+
+    #[test]
+    fn https_carries_alpn_and_hints() {
+        let config = config();
+        let https = synthesize("app-10-0-0-1.ns.example.com", TYPE_HTTPS, &config).unwrap();
+        assert_eq!(
+            rdata_text(TYPE_HTTPS, &https.answers[0].rdata),
+            "1 . alpn=h2,http/1.1 ipv4hint=10.0.0.1"
+        );
+        let svcb = synthesize(
+            "_8443._https.app-10-0-0-1.ns.example.com",
+            TYPE_SVCB,
+            &config,
+        );
+        assert_eq!(
+            rdata_text(TYPE_SVCB, &svcb.unwrap().answers[0].rdata),
+            "1 app-10-0-0-1.ns.example.com. alpn=h2,http/1.1 port=8443 ipv4hint=10.0.0.1"
+        );
+
+        let mut disabled = config;
+        disabled.synthesize_types = vec![TYPE_MX];
+        assert!(synthesize("app-10-0-0-1.ns.example.com", TYPE_HTTPS, &disabled).is_none());
+        assert_eq!(
+            parse_types("mx, srv,HTTPS"),
+            Ok(vec![TYPE_MX, TYPE_SRV, TYPE_HTTPS])
+        );
+        assert!(parse_types("A").is_err());
+        assert_eq!(parse_alpn("h3, h2,"), Ok(vec!["h3".into(), "h2".into()]));
+        assert!(parse_alpn(&"x".repeat(256)).is_err());
+    }
+}
@@ -0,0 +1,616 @@
+use super::edns::{skip_name, skip_record};
+use super::message::{encode_name, read_name};
+use crate::base64;
+use ring::hmac;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// RR type of the TSIG meta-record (RFC 8945).
+pub const TYPE_TSIG: u16 = 250;
+const CLASS_ANY: u16 = 255;
+
+/// Permitted clock difference, in seconds, the value RFC 8945 recommends.
+const FUDGE: u16 = 300;
+
+pub const RCODE_NOTAUTH: u8 = 9;
+const RCODE_FORMERR: u8 = 1;
+
+/// TSIG error codes, carried in the TSIG record of the response.
+pub const BADSIG: u16 = 16;
+pub const BADKEY: u16 = 17;
+pub const BADTIME: u16 = 18;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl Algorithm {
+    /// Parse an algorithm name, with or without the trailing dot.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim_end_matches('.').to_ascii_lowercase().as_str() {
+            "hmac-sha256" => Some(Algorithm::HmacSha256),
+            "hmac-sha512" => Some(Algorithm::HmacSha512),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::HmacSha256 => "hmac-sha256",
+            Algorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn hmac(self) -> hmac::Algorithm {
+        match self {
+            Algorithm::HmacSha256 => hmac::HMAC_SHA256,
+            Algorithm::HmacSha512 => hmac::HMAC_SHA512,
+        }
+    }
+}
+
+/// A shared TSIG key.
+#[derive(Clone, Debug)]
+pub struct Key {
+    /// Key name without the trailing dot, lowercase.
+    pub name: String,
+    pub algorithm: Algorithm,
+    secret: Arc<hmac::Key>,
+}
+
+impl Key {
+    pub fn new(name: &str, algorithm: Algorithm, secret: &[u8]) -> Self {
+        Self {
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            algorithm,
+            secret: Arc::new(hmac::Key::new(algorithm.hmac(), secret)),
+        }
+    }
+
+    /// Sign `request` as a client would and append the TSIG record.
+    #[cfg(test)]
+    pub fn sign_request(&self, request: &mut Vec<u8>, now: u64) {
+        let mut data = request.clone();
+        data.extend(variables(
+            &self.name,
+            self.algorithm.name(),
+            now,
+            FUDGE,
+            0,
+            &[],
+        ));
+        let mac = hmac::sign(&self.secret, &data);
+        let original_id = u16::from_be_bytes([request[0], request[1]]);
+        let rdata = tsig_rdata(
+            self.algorithm.name(),
+            now,
+            mac.as_ref(),
+            original_id,
+            0,
+            &[],
+        );
+        push_record(request, &self.name, &rdata);
+    }
+}
+
+/// Parse `TSIG_KEYS`: comma-separated `algorithm:name:secret` entries in the
+/// format of `dig -y`, with the secret in base64.
+pub fn parse_keys(s: &str) -> Result<Vec<Key>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(3, ':');
+            let (Some(algorithm), Some(name), Some(secret)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(format!("expected algorithm:name:secret, got {entry:?}"));
+            };
+            let algorithm = Algorithm::parse(algorithm)
+                .ok_or_else(|| format!("unsupported algorithm {algorithm:?}"))?;
+            let secret =
+                base64::decode(secret).ok_or_else(|| format!("invalid secret for {name:?}"))?;
+            Ok(Key::new(name, algorithm, &secret))
+        })
+        .collect()
+}
+
+/// A request whose TSIG verified. The response must be signed with the
+/// same key, chained to the request's MAC.
+#[derive(Debug)]
+pub struct Signed {
+    key: Key,
+    mac: Vec<u8>,
+    original_id: u16,
+}
+
+impl Signed {
+    pub fn key_name(&self) -> &str {
+        &self.key.name
+    }
+
+    /// Append a TSIG record signing `response` to it. Returns its MAC, to
+    /// which later messages of the same response are chained.
+    pub fn sign(&self, response: &mut Vec<u8>, now: u64) -> Vec<u8> {
+        append(
+            response,
+            &self.key,
+            &self.mac,
+            self.original_id,
+            now,
+            0,
+            &[],
+        )
+    }
+
+    /// Sign a later message of a response sent as several, such as a zone
+    /// transfer: the MAC covers the one before's MAC, the message and only
+    /// the timers (RFC 8945 §5.3.1). Returns its MAC.
+    pub fn sign_next(&self, message: &mut Vec<u8>, prior_mac: &[u8], now: u64) -> Vec<u8> {
+        let mut data = (prior_mac.len() as u16).to_be_bytes().to_vec();
+        data.extend(prior_mac);
+        data.extend(message.iter());
+        data.extend(&now.to_be_bytes()[2..]);
+        data.extend(&FUDGE.to_be_bytes());
+        let mac = hmac::sign(&self.key.secret, &data).as_ref().to_vec();
+
+        let rdata = tsig_rdata(
+            self.key.algorithm.name(),
+            now,
+            &mac,
+            self.original_id,
+            0,
+            &[],
+        );
+        push_record(message, &self.key.name, &rdata);
+        mac
+    }
+}
+
+/// Why a request's TSIG didn't verify.
+#[derive(Debug)]
+pub struct Rejection {
+    /// The TSIG error, or FORMERR for a TSIG record that can't be read or
+    /// isn't the last record (RFC 8945 §5.2), which is answered without one.
+    pub error: u16,
+    key_name: String,
+    algorithm: String,
+    original_id: u16,
+    /// BADTIME responses are signed (RFC 8945 §5.2.3); BADKEY and BADSIG
+    /// ones can't be.
+    signed: Option<(Key, Vec<u8>)>,
+}
+
+impl Rejection {
+    fn formerr() -> Self {
+        Self {
+            error: u16::from(RCODE_FORMERR),
+            key_name: String::new(),
+            algorithm: String::new(),
+            original_id: 0,
+            signed: None,
+        }
+    }
+
+    /// NOTAUTH for `query`, with a TSIG record carrying the error, or
+    /// FORMERR.
+    pub fn response(&self, query: &[u8], now: u64) -> Vec<u8> {
+        if self.error == u16::from(RCODE_FORMERR) {
+            return super::response::build_error_response(query, RCODE_FORMERR);
+        }
+        let mut response = super::response::build_error_response(query, RCODE_NOTAUTH);
+        match &self.signed {
+            Some((key, mac)) => {
+                // The server's time, so the client can see the skew.
+                let other = (now & 0xFFFF_FFFF_FFFF).to_be_bytes()[2..].to_vec();
+                append(
+                    &mut response,
+                    key,
+                    mac,
+                    self.original_id,
+                    now,
+                    self.error,
+                    &other,
+                );
+            }
+            None => {
+                let rdata =
+                    tsig_rdata(&self.algorithm, now, &[], self.original_id, self.error, &[]);
+                push_record(&mut response, &self.key_name, &rdata);
+            }
+        }
+        response
+    }
+}
+
+/// Verify the TSIG record of `message`, which must be the last record of
+/// the additional section. `Ok(None)` if the message isn't signed.
+///
+/// Only full-length MACs are accepted; truncated ones fail with BADSIG.
+pub fn verify(message: &[u8], keys: &[Key], now: u64) -> Result<Option<Signed>, Rejection> {
+    let Some(start) = find_tsig(message).map_err(|()| Rejection::formerr())? else {
+        return Ok(None);
+    };
+    let Some((name, rdata)) = tsig_record(message, start) else {
+        return Err(Rejection::formerr());
+    };
+    let Some(fields) = Fields::parse(&rdata) else {
+        return Err(Rejection::formerr());
+    };
+
+    let mut rejection = Rejection {
+        error: BADKEY,
+        key_name: name.to_ascii_lowercase(),
+        algorithm: fields.algorithm.clone(),
+        original_id: fields.original_id,
+        signed: None,
+    };
+    let algorithm = Algorithm::parse(&fields.algorithm);
+    let Some(key) = keys
+        .iter()
+        .find(|key| Some(key.algorithm) == algorithm && key.name.eq_ignore_ascii_case(&name))
+    else {
+        return Err(rejection);
+    };
+
+    let mut data = message[..start].to_vec();
+    data[..2].copy_from_slice(&fields.original_id.to_be_bytes());
+    let arcount = u16::from_be_bytes([data[10], data[11]]) - 1;
+    data[10..12].copy_from_slice(&arcount.to_be_bytes());
+    data.extend(variables(
+        &key.name,
+        key.algorithm.name(),
+        fields.time_signed,
+        fields.fudge,
+        fields.error,
+        &fields.other,
+    ));
+    if hmac::verify(&key.secret, &data, &fields.mac).is_err() {
+        rejection.error = BADSIG;
+        return Err(rejection);
+    }
+
+    if now.abs_diff(fields.time_signed) > u64::from(fields.fudge) {
+        rejection.error = BADTIME;
+        rejection.signed = Some((key.clone(), fields.mac));
+        return Err(rejection);
+    }
+
+    Ok(Some(Signed {
+        key: key.clone(),
+        mac: fields.mac,
+        original_id: fields.original_id,
+    }))
+}
+
+/// Seconds since the epoch, for [`verify`] and signing.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// The TSIG RDATA fields.
+struct Fields {
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl Fields {
+    fn parse(rdata: &[u8]) -> Option<Self> {
+        let (algorithm, pos) = read_name(rdata, 0)?;
+        let fixed = rdata.get(pos..pos + 10)?;
+        let mut time = [0; 8];
+        time[2..].copy_from_slice(&fixed[..6]);
+        let mac_len = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+        let pos = pos + 10;
+        let mac = rdata.get(pos..pos + mac_len)?.to_vec();
+        let rest = rdata.get(pos + mac_len..pos + mac_len + 6)?;
+        let other_len = usize::from(u16::from_be_bytes([rest[4], rest[5]]));
+        let other_start = pos + mac_len + 6;
+        let other = rdata.get(other_start..other_start + other_len)?.to_vec();
+        Some(Self {
+            algorithm,
+            time_signed: u64::from_be_bytes(time),
+            fudge: u16::from_be_bytes([fixed[6], fixed[7]]),
+            mac,
+            original_id: u16::from_be_bytes([rest[0], rest[1]]),
+            error: u16::from_be_bytes([rest[2], rest[3]]),
+            other,
+        })
+    }
+}
+
+/// Start of the TSIG record of `message`, if it has one. `Err` if a TSIG
+/// record isn't the last record of the additional section.
+fn find_tsig(message: &[u8]) -> Result<Option<usize>, ()> {
+    let Some(header) = message.get(..12) else {
+        return Ok(None);
+    };
+    let count = |i: usize| usize::from(u16::from_be_bytes([header[i], header[i + 1]]));
+    let records = count(6) + count(8) + count(10);
+    let mut pos = 12;
+    for _ in 0..count(4) {
+        let Some(end) = skip_name(message, pos) else {
+            return Ok(None);
+        };
+        pos = end + 4;
+    }
+    for i in 0..records {
+        let rtype = skip_name(message, pos).and_then(|end| message.get(end..end + 2));
+        if rtype == Some(&TYPE_TSIG.to_be_bytes()[..]) {
+            let last = i == records - 1 && count(10) > 0;
+            return last.then_some(Some(pos)).ok_or(());
+        }
+        let Some(end) = skip_record(message, pos) else {
+            return Ok(None);
+        };
+        pos = end;
+    }
+    Ok(None)
+}
+
+/// Owner name and RDATA of the record at `start`, if it is a TSIG record.
+fn tsig_record(message: &[u8], start: usize) -> Option<(String, Vec<u8>)> {
+    let (name, pos) = read_name(message, start)?;
+    let fixed = message.get(pos..pos + 10)?;
+    if u16::from_be_bytes([fixed[0], fixed[1]]) != TYPE_TSIG {
+        return None;
+    }
+    let end = skip_record(message, start)?;
+    Some((name, message[pos + 10..end].to_vec()))
+}
+
+/// The TSIG variables that are covered by the MAC (RFC 8945 §4.3.3).
+fn variables(
+    key_name: &str,
+    algorithm: &str,
+    time: u64,
+    fudge: u16,
+    error: u16,
+    other: &[u8],
+) -> Vec<u8> {
+    let mut data = encode_name(&key_name.to_ascii_lowercase());
+    data.extend(&CLASS_ANY.to_be_bytes());
+    data.extend(&0u32.to_be_bytes()); // TTL
+    data.extend(encode_name(&algorithm.to_ascii_lowercase()));
+    data.extend(&time.to_be_bytes()[2..]);
+    data.extend(&fudge.to_be_bytes());
+    data.extend(&error.to_be_bytes());
+    data.extend(&(other.len() as u16).to_be_bytes());
+    data.extend(other);
+    data
+}
+
+fn tsig_rdata(
+    algorithm: &str,
+    time: u64,
+    mac: &[u8],
+    original_id: u16,
+    error: u16,
+    other: &[u8],
+) -> Vec<u8> {
+    let mut rdata = encode_name(algorithm);
+    rdata.extend(&time.to_be_bytes()[2..]);
+    rdata.extend(&FUDGE.to_be_bytes());
+    rdata.extend(&(mac.len() as u16).to_be_bytes());
+    rdata.extend(mac);
+    rdata.extend(&original_id.to_be_bytes());
+    rdata.extend(&error.to_be_bytes());
+    rdata.extend(&(other.len() as u16).to_be_bytes());
+    rdata.extend(other);
+    rdata
+}
+
+/// Sign `response` as the answer to a request with `request_mac` and
+/// append the TSIG record. Returns the MAC.
+fn append(
+    response: &mut Vec<u8>,
+    key: &Key,
+    request_mac: &[u8],
+    original_id: u16,
+    now: u64,
+    error: u16,
+    other: &[u8],
+) -> Vec<u8> {
+    let mut data = (request_mac.len() as u16).to_be_bytes().to_vec();
+    data.extend(request_mac);
+    data.extend(response.iter());
+    data.extend(variables(
+        &key.name,
+        key.algorithm.name(),
+        now,
+        FUDGE,
+        error,
+        other,
+    ));
+    let mac = hmac::sign(&key.secret, &data);
+
+    let rdata = tsig_rdata(
+        key.algorithm.name(),
+        now,
+        mac.as_ref(),
+        original_id,
+        error,
+        other,
+    );
+    push_record(response, &key.name, &rdata);
+    mac.as_ref().to_vec()
+}
+
+/// Append a TSIG record and bump ARCOUNT.
+fn push_record(response: &mut Vec<u8>, key_name: &str, rdata: &[u8]) {
+    response.extend(encode_name(key_name));
+    response.extend(&TYPE_TSIG.to_be_bytes());
+    response.extend(&CLASS_ANY.to_be_bytes());
+    response.extend(&0u32.to_be_bytes()); // TTL
+    response.extend(&(rdata.len() as u16).to_be_bytes());
+    response.extend(rdata);
+
+    let arcount = u16::from_be_bytes([response[10], response[11]]) + 1;
+    response[10..12].copy_from_slice(&arcount.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::message::Message;
+
+    const TIME: u64 = 1_700_000_000;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn keys() -> Vec<Key> {
+        parse_keys(
+            "hmac-sha256:test-key:MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=,\
+             hmac-sha512:test-key:MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=",
+        )
+        .unwrap()
+    }
+
+    /// An AXFR request for ns.example.com signed at `TIME` with `mac`,
+    /// computed independently with Python's `hmac` module.
+    fn signed_request(algorithm: &str, mac: &[u8]) -> Vec<u8> {
+        let mut request = vec![0x12, 0x34, 0x00, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        request.extend(encode_name("ns.example.com"));
+        request.extend(&[0x00, 0xFC, 0x00, 0x01]);
+        let rdata = tsig_rdata(algorithm, TIME, mac, 0x1234, 0, &[]);
+        push_record(&mut request, "test-key", &rdata);
+        request
+    }
+
+    fn sha256_request() -> Vec<u8> {
+        signed_request(
+            "hmac-sha256",
+            &hex("214443edb1af0b910226b1f97ac8214627599e1dffa925e46b7c3060fc7fbc2e"),
+        )
+    }
+
+    #[test]
+    fn known_good_macs_verify() {
+        assert!(verify(&sha256_request(), &keys(), TIME).unwrap().is_some());
+
+        let sha512 = signed_request(
+            "hmac-sha512",
+            &hex(
+                "8ceedcf2215900b55407f31fcd9aba3e6c80f24032569cefbadca6eb157870b5\
+                  6cd60594f3489c4cd01e28019db170fe7a59f58bbd31553140acac02aaa4332c",
+            ),
+        );
+        let signed = verify(&sha512, &keys(), TIME + 10).unwrap().unwrap();
+        assert_eq!(signed.key_name(), "test-key");
+
+        let mut unsigned = sha256_request();
+        unsigned.truncate(unsigned.len() - 61);
+        unsigned[11] = 0;
+        assert!(verify(&unsigned, &keys(), TIME).unwrap().is_none());
+    }
+
+    #[test]
+    fn failures_are_reported() {
+        let mut tampered = sha256_request();
+        tampered[2] = 0x01; // RD
+        let error = |result: Result<Option<Signed>, Rejection>| result.err().map(|r| r.error);
+        assert_eq!(error(verify(&tampered, &keys(), TIME)), Some(BADSIG));
+        assert_eq!(error(verify(&sha256_request(), &[], TIME)), Some(BADKEY));
+
+        let late = verify(&sha256_request(), &keys(), TIME + 301)
+            .err()
+            .unwrap();
+        assert_eq!(late.error, BADTIME);
+        let query = &sha256_request()[..32];
+        let response = late.response(query, TIME + 301);
+        assert_eq!(response[3] & 0x0F, RCODE_NOTAUTH);
+        let tsig = Message::parse(&response).unwrap().additional.pop().unwrap();
+        assert_eq!(tsig.rtype, TYPE_TSIG);
+        let fields = Fields::parse(&tsig.rdata).unwrap();
+        assert_eq!(fields.error, BADTIME);
+        assert_eq!(fields.mac.len(), 32);
+        assert_eq!(fields.other, (TIME + 301).to_be_bytes()[2..]);
+    }
+
+    #[test]
+    fn responses_are_chained_to_the_request() {
+        let request = sha256_request();
+        let signed = verify(&request, &keys(), TIME).unwrap().unwrap();
+        let mut response = vec![0x12, 0x34, 0x84, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        response.extend(&request[12..32]);
+        let unsigned = response.clone();
+        signed.sign(&mut response, TIME + 1);
+
+        let tsig = Message::parse(&response).unwrap().additional.pop().unwrap();
+        let fields = Fields::parse(&tsig.rdata).unwrap();
+        let mut data = vec![0, 32];
+        data.extend(hex(
+            "214443edb1af0b910226b1f97ac8214627599e1dffa925e46b7c3060fc7fbc2e",
+        ));
+        data.extend(unsigned);
+        data.extend(variables("test-key", "hmac-sha256", TIME + 1, 300, 0, &[]));
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"0123456789abcdef0123456789abcdef");
+        assert!(hmac::verify(&key, &data, &fields.mac).is_ok());
+    }
+
+    #[test]
+    fn later_messages_are_chained_to_the_one_before() {
+        let signed = verify(&sha256_request(), &keys(), TIME).unwrap().unwrap();
+        let mut first = vec![0x12, 0x34, 0x84, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut second = first.clone();
+        let unsigned = second.clone();
+        let first_mac = signed.sign(&mut first, TIME + 1);
+        let second_mac = signed.sign_next(&mut second, &first_mac, TIME + 2);
+
+        let tsig = Message::parse(&second).unwrap().additional.pop().unwrap();
+        let fields = Fields::parse(&tsig.rdata).unwrap();
+        assert_eq!(fields.mac, second_mac);
+        assert_eq!(fields.original_id, 0x1234);
+        let mut data = vec![0, 32];
+        data.extend(first_mac);
+        data.extend(unsigned);
+        data.extend(&(TIME + 2).to_be_bytes()[2..]);
+        data.extend(&300u16.to_be_bytes());
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"0123456789abcdef0123456789abcdef");
+        assert!(hmac::verify(&key, &data, &fields.mac).is_ok());
+    }
+
+    #[test]
+    fn malformed_and_misplaced_tsigs_are_formerr() {
+        let mut request = sha256_request();
+        // The TSIG record followed by another one.
+        request[11] += 1;
+        request.extend(&[0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let misplaced = verify(&request, &keys(), TIME).unwrap_err();
+        assert_eq!(misplaced.error, u16::from(RCODE_FORMERR));
+        let response = misplaced.response(&request, TIME);
+        assert_eq!(response[3] & 0x0F, RCODE_FORMERR);
+        assert_eq!(&response[6..12], &[0; 6]);
+
+        // RDATA that ends in the middle of the MAC.
+        let mut truncated = vec![0x12, 0x34, 0x00, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        truncated.extend(encode_name("ns.example.com"));
+        truncated.extend(&[0x00, 0xFC, 0x00, 0x01]);
+        let rdata = tsig_rdata("hmac-sha256", TIME, &[1; 32], 0x1234, 0, &[]);
+        push_record(&mut truncated, "test-key", &rdata[..30]);
+        let malformed = verify(&truncated, &keys(), TIME).unwrap_err();
+        assert_eq!(malformed.error, u16::from(RCODE_FORMERR));
+    }
+
+    #[test]
+    fn keys_are_parsed() {
+        assert_eq!(keys()[1].algorithm, Algorithm::HmacSha512);
+        assert!(parse_keys("hmac-md5:old:c2VjcmV0").is_err());
+        assert!(parse_keys("hmac-sha256:nosecret").is_err());
+        assert!(parse_keys("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,364 @@
+use super::message::{read_name, Message, Record};
+use super::query::{is_in_zone, Outcome, Question, Resolution};
+use super::records::{apex_conflict, TYPE_A, TYPE_AAAA, TYPE_CNAME, TYPE_TXT};
+use super::response::build_error_response;
+use crate::config::Config;
+use log::info;
+use std::net::SocketAddr;
+
+/// The UPDATE opcode (RFC 2136).
+pub const OPCODE_UPDATE: u8 = 5;
+
+const TYPE_SOA: u16 = 6;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+
+const RCODE_FORMERR: u8 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_REFUSED: u8 = 5;
+const RCODE_YXDOMAIN: u8 = 6;
+const RCODE_YXRRSET: u8 = 7;
+const RCODE_NXRRSET: u8 = 8;
+const RCODE_NOTAUTH: u8 = 9;
+const RCODE_NOTZONE: u8 = 10;
+
+/// The OPCODE field of a message header.
+pub fn opcode(message: &[u8]) -> u8 {
+    message.get(2).map_or(0, |flags| flags >> 3 & 0x0F)
+}
+
+/// Apply an UPDATE to the static records (RFC 2136). The zone section
+/// must name the zone; prerequisites are checked and all updates applied
+/// together, or nothing changes. Only A, AAAA, TXT and CNAME records can be
+/// added. Requests from outside `UPDATE_ALLOW`, and unsigned ones unless
+/// `UPDATE_REQUIRE_TSIG` is off, are refused.
+pub fn handle_update(
+    query: &[u8],
+    zone: Question,
+    src: SocketAddr,
+    signed: bool,
+    config: &Config,
+) -> Resolution {
+    let allowed = config
+        .update_allow
+        .iter()
+        .any(|network| network.contains(src.ip()));
+    let rcode = if !allowed || (config.update_require_tsig && !signed) {
+        info!("Client [{src}] refused UPDATE of [{}]", zone.name);
+        RCODE_REFUSED
+    } else if zone.qtype != TYPE_SOA || !zone.name.eq_ignore_ascii_case(&config.glue_name) {
+        RCODE_NOTAUTH
+    } else {
+        match Message::parse(query) {
+            None => RCODE_FORMERR,
+            Some(message) => match apply(&message, config) {
+                Ok(changed) => {
+                    info!(
+                        "Client [{src}] updated [{}]{}",
+                        zone.name,
+                        if changed { "" } else { " (no change)" }
+                    );
+                    0
+                }
+                Err(rcode) => rcode,
+            },
+        }
+    };
+
+    let mut response = build_error_response(query, rcode);
+    response[2] |= query[2] & 0x78; // Echo the opcode
+    Resolution {
+        response,
+        question: Some(zone),
+        outcome: Outcome::Update,
+        answers: Vec::new(),
+    }
+}
+
+/// Check the prerequisites and apply the updates of `message` atomically.
+/// `Ok(true)` if the records changed.
+fn apply(message: &Message, config: &Config) -> Result<bool, u8> {
+    for record in message.answers.iter().chain(&message.authority) {
+        if !is_in_zone(&record.name, &config.glue_name) {
+            return Err(RCODE_NOTZONE);
+        }
+    }
+    for update in &message.authority {
+        prescan(update)?;
+    }
+
+    config.records.update(|records| {
+        for prerequisite in &message.answers {
+            check(prerequisite, records)?;
+        }
+        let mut changed = false;
+        for update in &message.authority {
+            // The apex always has its SOA and NS, and its A record is the
+            // glue: a CNAME or A added there is ignored (§3.4.2.2).
+            if update.class == CLASS_IN && apex_conflict(update, &config.glue_name).is_some() {
+                continue;
+            }
+            changed |= perform(update, records);
+        }
+        Ok(changed)
+    })
+}
+
+/// RFC 2136 §3.2: one prerequisite against the current records.
+fn check(prerequisite: &Record, records: &[Record]) -> Result<(), u8> {
+    let at_name = || {
+        records
+            .iter()
+            .filter(|record| record.name.eq_ignore_ascii_case(&prerequisite.name))
+    };
+    let rrset_exists = || at_name().any(|record| record.rtype == prerequisite.rtype);
+    if prerequisite.ttl != 0 {
+        return Err(RCODE_FORMERR);
+    }
+    match (prerequisite.class, prerequisite.rtype) {
+        (CLASS_ANY, TYPE_ANY) if at_name().next().is_none() => Err(RCODE_NXDOMAIN),
+        (CLASS_ANY, _) if prerequisite.rtype != TYPE_ANY && !rrset_exists() => Err(RCODE_NXRRSET),
+        (CLASS_NONE, TYPE_ANY) if at_name().next().is_some() => Err(RCODE_YXDOMAIN),
+        (CLASS_NONE, _) if prerequisite.rtype != TYPE_ANY && rrset_exists() => Err(RCODE_YXRRSET),
+        (CLASS_ANY | CLASS_NONE, _) => Ok(()),
+        (CLASS_IN, _) => {
+            // "RRset exists (value dependent)"; approximated record by
+            // record rather than comparing whole RRsets.
+            let found = at_name().any(|record| {
+                record.rtype == prerequisite.rtype && record.rdata == prerequisite.rdata
+            });
+            found.then_some(()).ok_or(RCODE_NXRRSET)
+        }
+        _ => Err(RCODE_FORMERR),
+    }
+}
+
+/// RFC 2136 §3.4.1: reject the whole request before anything changes.
+fn prescan(update: &Record) -> Result<(), u8> {
+    match update.class {
+        CLASS_IN => {
+            let valid = match update.rtype {
+                TYPE_A => update.rdata.len() == 4,
+                TYPE_AAAA => update.rdata.len() == 16,
+                TYPE_CNAME => read_name(&update.rdata, 0).is_some(),
+                TYPE_TXT => valid_txt(&update.rdata),
+                _ => return Err(RCODE_REFUSED),
+            };
+            valid.then_some(()).ok_or(RCODE_FORMERR)
+        }
+        CLASS_ANY if update.ttl == 0 && update.rdata.is_empty() => Ok(()),
+        CLASS_NONE if update.ttl == 0 && update.rtype != TYPE_ANY => Ok(()),
+        _ => Err(RCODE_FORMERR),
+    }
+}
+
+/// One or more character-strings, exactly filling the RDATA.
+fn valid_txt(rdata: &[u8]) -> bool {
+    let mut pos = 0;
+    while pos < rdata.len() {
+        pos += 1 + usize::from(rdata[pos]);
+    }
+    !rdata.is_empty() && pos == rdata.len()
+}
+
+/// Add `record` as an update would, e.g. replacing a CNAME. `false` if it
+/// was already there or conflicts with a CNAME.
+pub fn add(record: &Record, records: &mut Vec<Record>) -> bool {
+    perform(record, records)
+}
+
+/// Delete `record` (name, type and data) as an update would.
+pub fn delete(record: &Record, records: &mut Vec<Record>) -> bool {
+    let delete = Record {
+        class: CLASS_NONE,
+        ttl: 0,
+        ..record.clone()
+    };
+    perform(&delete, records)
+}
+
+/// RFC 2136 §3.4.2: apply one update. Returns whether anything changed.
+fn perform(update: &Record, records: &mut Vec<Record>) -> bool {
+    let name = update.name.to_ascii_lowercase();
+    let before = records.len();
+    match update.class {
+        CLASS_IN => {
+            let mut at_name = records.iter().filter(|record| record.name == name);
+            let has_cname = at_name.clone().any(|record| record.rtype == TYPE_CNAME);
+            let has_other = at_name.any(|record| record.rtype != TYPE_CNAME);
+            if update.rtype == TYPE_CNAME && has_other || update.rtype != TYPE_CNAME && has_cname {
+                // A CNAME can't coexist with other data; ignored.
+                return false;
+            }
+            if update.rtype == TYPE_CNAME {
+                // Replaces the CNAME that is there, if any.
+                records.retain(|record| record.name != name || record.rdata == update.rdata);
+            }
+            let existing = records.iter_mut().find(|record| {
+                record.name == name && record.rtype == update.rtype && record.rdata == update.rdata
+            });
+            match existing {
+                Some(record) if record.ttl == update.ttl => return false,
+                Some(record) => record.ttl = update.ttl,
+                None => records.push(Record {
+                    name,
+                    ..update.clone()
+                }),
+            }
+            true
+        }
+        CLASS_ANY => {
+            records.retain(|record| {
+                record.name != name || (update.rtype != TYPE_ANY && record.rtype != update.rtype)
+            });
+            records.len() != before
+        }
+        _ => {
+            records.retain(|record| {
+                record.name != name || record.rtype != update.rtype || record.rdata != update.rdata
+            });
+            records.len() != before
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::records::Records;
+    use crate::dns::response::encode_domain_name;
+
+    type Entry<'a> = (&'a str, u16, u16, &'a [u8]);
+
+    fn update(prerequisites: &[Entry], updates: &[Entry]) -> Vec<u8> {
+        let mut message = vec![0x43, 0x21, 0x28, 0x00, 0, 1];
+        message.extend(&(prerequisites.len() as u16).to_be_bytes());
+        message.extend(&(updates.len() as u16).to_be_bytes());
+        message.extend(&[0, 0]);
+        message.extend(encode_domain_name("ns.example.com"));
+        message.extend(&[0x00, 0x06, 0x00, 0x01]);
+        let prerequisites = prerequisites.iter().map(|entry| (entry, 0u32));
+        let updates = updates
+            .iter()
+            .map(|entry| (entry, if entry.2 == CLASS_IN { 300 } else { 0 }));
+        for ((name, rtype, class, rdata), ttl) in prerequisites.chain(updates) {
+            message.extend(encode_domain_name(name));
+            message.extend(&rtype.to_be_bytes());
+            message.extend(&class.to_be_bytes());
+            message.extend(&ttl.to_be_bytes());
+            message.extend(&(rdata.len() as u16).to_be_bytes());
+            message.extend(*rdata);
+        }
+        message
+    }
+
+    fn config() -> Config {
+        let mut config = Config::for_tests("ns.example.com");
+        config.update_allow = vec!["192.0.2.0/24".parse().unwrap()];
+        config.update_require_tsig = false;
+        config.records = Records::new(1);
+        config
+    }
+
+    fn send(message: &[u8], config: &Config) -> u8 {
+        let zone = Question {
+            name: "ns.example.com".to_string(),
+            qtype: TYPE_SOA,
+            qclass: CLASS_IN,
+        };
+        let src = "192.0.2.10:5000".parse().unwrap();
+        let resolution = handle_update(message, zone, src, false, config);
+        assert_eq!(opcode(&resolution.response), OPCODE_UPDATE);
+        resolution.response[3] & 0x0F
+    }
+
+    #[test]
+    fn records_are_added_and_deleted() {
+        let config = config();
+        let add = update(
+            &[],
+            &[("www.ns.example.com", TYPE_A, CLASS_IN, &[192, 0, 2, 1])],
+        );
+        assert_eq!(send(&add, &config), 0);
+        assert_eq!(config.records.at("www.ns.example.com").len(), 1);
+        assert_eq!(config.records.serial(), 2);
+
+        // Adding the same record again changes nothing.
+        assert_eq!(send(&add, &config), 0);
+        assert_eq!(config.records.serial(), 2);
+
+        // A CNAME can't be added next to the A record.
+        let target = encode_domain_name("other.ns.example.com");
+        let cname = update(
+            &[],
+            &[("www.ns.example.com", TYPE_CNAME, CLASS_IN, &target)],
+        );
+        assert_eq!(send(&cname, &config), 0);
+        assert_eq!(config.records.at("www.ns.example.com")[0].rtype, TYPE_A);
+
+        let delete = update(&[], &[("www.ns.example.com", TYPE_ANY, CLASS_ANY, &[])]);
+        assert_eq!(send(&delete, &config), 0);
+        assert!(config.records.at("www.ns.example.com").is_empty());
+        assert_eq!(config.records.serial(), 3);
+
+        // Deleting an RRset carries no RDATA, even for types with names in it.
+        let no_cname = update(&[], &[("www.ns.example.com", TYPE_CNAME, CLASS_ANY, &[])]);
+        assert_eq!(send(&no_cname, &config), 0);
+    }
+
+    #[test]
+    fn apex_cnames_and_addresses_are_ignored() {
+        let config = config();
+        let target = encode_domain_name("lb.example.net");
+        let apex = update(
+            &[],
+            &[
+                ("ns.example.com", TYPE_CNAME, CLASS_IN, &target),
+                ("NS.example.com", TYPE_A, CLASS_IN, &[192, 0, 2, 1]),
+                ("ns.example.com", TYPE_TXT, CLASS_IN, b"\x02hi"),
+            ],
+        );
+        assert_eq!(send(&apex, &config), 0);
+        let at_apex = config.records.at("ns.example.com");
+        assert_eq!(at_apex.len(), 1);
+        assert_eq!(at_apex[0].rtype, TYPE_TXT);
+    }
+
+    #[test]
+    fn prerequisites_and_prescan_are_enforced() {
+        let config = config();
+        let exists = update(&[("www.ns.example.com", TYPE_ANY, CLASS_ANY, &[])], &[]);
+        assert_eq!(send(&exists, &config), RCODE_NXDOMAIN);
+        let txt = update(
+            &[("www.ns.example.com", TYPE_ANY, CLASS_NONE, &[])],
+            &[("www.ns.example.com", TYPE_TXT, CLASS_IN, b"\x02hi")],
+        );
+        assert_eq!(send(&txt, &config), 0);
+        assert_eq!(send(&txt, &config), RCODE_YXDOMAIN);
+
+        let mx = update(&[], &[("mx.ns.example.com", 15, CLASS_IN, &[0, 10, 0])]);
+        assert_eq!(send(&mx, &config), RCODE_REFUSED);
+        let outside = update(
+            &[],
+            &[("www.example.org", TYPE_A, CLASS_IN, &[192, 0, 2, 1])],
+        );
+        assert_eq!(send(&outside, &config), RCODE_NOTZONE);
+        let bad_txt = update(&[], &[("t.ns.example.com", TYPE_TXT, CLASS_IN, b"\x05hi")]);
+        assert_eq!(send(&bad_txt, &config), RCODE_FORMERR);
+        assert_eq!(config.records.all().len(), 1);
+    }
+
+    #[test]
+    fn unauthorized_updates_are_refused() {
+        let mut config = config();
+        config.update_require_tsig = true;
+        let add = update(
+            &[],
+            &[("www.ns.example.com", TYPE_A, CLASS_IN, &[192, 0, 2, 1])],
+        );
+        assert_eq!(send(&add, &config), RCODE_REFUSED);
+        assert!(config.records.all().is_empty());
+    }
+}
@@ -0,0 +1,391 @@
+use crate::dns::Transport;
+use log::{debug, warn};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufWriter, Result as IoResult};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// Frame Streams control frame types.
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+
+// dnstap.proto enum values.
+const DNSTAP_TYPE_MESSAGE: u64 = 1;
+const MESSAGE_AUTH_QUERY: u64 = 1;
+const MESSAGE_AUTH_RESPONSE: u64 = 2;
+const SOCKET_FAMILY_INET: u64 = 1;
+const SOCKET_FAMILY_INET6: u64 = 2;
+const SOCKET_PROTOCOL_UDP: u64 = 1;
+const SOCKET_PROTOCOL_TCP: u64 = 2;
+
+/// Frames queued for the writer thread before new ones are dropped, so a
+/// slow or absent collector never stalls the DNS listeners.
+const QUEUE_DEPTH: usize = 4096;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where dnstap frames are written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// Bidirectional Frame Streams over a Unix socket (e.g. `dnstap-receiver`).
+    Socket(PathBuf),
+    /// Unidirectional Frame Streams file, readable with `dnstap -r`.
+    File(PathBuf),
+}
+
+/// Handle for emitting AUTH_QUERY/AUTH_RESPONSE messages. Cloning shares
+/// the queue to the single writer thread.
+#[derive(Clone)]
+pub struct Dnstap {
+    identity: Vec<u8>,
+    version: Vec<u8>,
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl Dnstap {
+    /// Start the writer thread. A file is created immediately (so a bad path
+    /// fails at startup); a socket is connected lazily and re-connected when
+    /// the collector goes away.
+    pub fn start(output: &Output, identity: &str, version: &str) -> IoResult<Self> {
+        let (sender, receiver) = sync_channel(QUEUE_DEPTH);
+        match output {
+            Output::File(path) => {
+                let writer = BufWriter::new(File::create(path)?);
+                thread::spawn(move || write_file(writer, receiver));
+            }
+            Output::Socket(path) => {
+                let path = path.clone();
+                thread::spawn(move || write_socket(path, receiver));
+            }
+        }
+        Ok(Self {
+            identity: identity.as_bytes().to_vec(),
+            version: version.as_bytes().to_vec(),
+            sender,
+        })
+    }
+
+    /// Queue the query and, if one was sent, its response.
+    pub fn record(
+        &self,
+        src: SocketAddr,
+        transport: Transport,
+        query: &[u8],
+        query_time: SystemTime,
+        response: &[u8],
+        response_time: SystemTime,
+    ) {
+        let exchange = Exchange {
+            src,
+            transport,
+            query,
+            query_time,
+            response,
+            response_time,
+        };
+        self.send(encode_dnstap(
+            &self.identity,
+            &self.version,
+            &exchange.encode(MESSAGE_AUTH_QUERY),
+        ));
+        if !response.is_empty() {
+            self.send(encode_dnstap(
+                &self.identity,
+                &self.version,
+                &exchange.encode(MESSAGE_AUTH_RESPONSE),
+            ));
+        }
+    }
+
+    fn send(&self, frame: Vec<u8>) {
+        match self.sender.try_send(frame) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("dnstap queue full, dropping frame"),
+            Err(TrySendError::Disconnected(_)) => debug!("dnstap writer gone, dropping frame"),
+        }
+    }
+}
+
+struct Exchange<'a> {
+    src: SocketAddr,
+    transport: Transport,
+    query: &'a [u8],
+    query_time: SystemTime,
+    response: &'a [u8],
+    response_time: SystemTime,
+}
+
+impl Exchange<'_> {
+    /// Encode a `dnstap.Message` of the given type.
+    fn encode(&self, message_type: u64) -> Vec<u8> {
+        let mut message = Vec::with_capacity(128 + self.query.len() + self.response.len());
+        put_varint_field(&mut message, 1, message_type);
+
+        let client = self.src.ip().to_canonical();
+        let family = match client {
+            IpAddr::V4(_) => SOCKET_FAMILY_INET,
+            IpAddr::V6(_) => SOCKET_FAMILY_INET6,
+        };
+        let protocol = match self.transport {
+            Transport::Udp => SOCKET_PROTOCOL_UDP,
+            Transport::Tcp => SOCKET_PROTOCOL_TCP,
+        };
+        put_varint_field(&mut message, 2, family);
+        put_varint_field(&mut message, 3, protocol);
+        match client {
+            IpAddr::V4(ip) => put_bytes_field(&mut message, 4, &ip.octets()),
+            IpAddr::V6(ip) => put_bytes_field(&mut message, 4, &ip.octets()),
+        }
+        put_varint_field(&mut message, 6, u64::from(self.src.port()));
+
+        let (secs, nanos) = unix_time(self.query_time);
+        put_varint_field(&mut message, 8, secs);
+        put_fixed32_field(&mut message, 9, nanos);
+
+        if message_type == MESSAGE_AUTH_QUERY {
+            put_bytes_field(&mut message, 10, self.query);
+        } else {
+            let (secs, nanos) = unix_time(self.response_time);
+            put_varint_field(&mut message, 12, secs);
+            put_fixed32_field(&mut message, 13, nanos);
+            put_bytes_field(&mut message, 14, self.response);
+        }
+        message
+    }
+}
+
+/// Wrap a `dnstap.Message` in the top-level `dnstap.Dnstap` envelope.
+fn encode_dnstap(identity: &[u8], version: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut dnstap = Vec::with_capacity(16 + identity.len() + version.len() + message.len());
+    if !identity.is_empty() {
+        put_bytes_field(&mut dnstap, 1, identity);
+    }
+    put_bytes_field(&mut dnstap, 2, version);
+    put_bytes_field(&mut dnstap, 14, message);
+    put_varint_field(&mut dnstap, 15, DNSTAP_TYPE_MESSAGE);
+    dnstap
+}
+
+fn unix_time(time: SystemTime) -> (u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+    put_varint(buf, u64::from(field) << 3);
+    put_varint(buf, value);
+}
+
+fn put_fixed32_field(buf: &mut Vec<u8>, field: u32, value: u32) {
+    put_varint(buf, (u64::from(field) << 3) | 5);
+    buf.extend(&value.to_le_bytes());
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u32, value: &[u8]) {
+    put_varint(buf, (u64::from(field) << 3) | 2);
+    put_varint(buf, value.len() as u64);
+    buf.extend(value);
+}
+
+/// A Frame Streams control frame, with the content type field unless it is
+/// a STOP.
+fn control_frame(control_type: u32) -> Vec<u8> {
+    let mut payload = control_type.to_be_bytes().to_vec();
+    if control_type != CONTROL_STOP {
+        payload.extend(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        payload.extend(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        payload.extend(CONTENT_TYPE);
+    }
+    let mut frame = vec![0, 0, 0, 0]; // Escape: zero-length data frame
+    frame.extend(&(payload.len() as u32).to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+fn write_data_frame(writer: &mut impl Write, payload: &[u8]) -> IoResult<()> {
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+fn write_file(mut writer: BufWriter<File>, receiver: Receiver<Vec<u8>>) {
+    let result = (|| -> IoResult<()> {
+        writer.write_all(&control_frame(CONTROL_START))?;
+        for frame in receiver {
+            write_data_frame(&mut writer, &frame)?;
+            writer.flush()?;
+        }
+        writer.write_all(&control_frame(CONTROL_STOP))?;
+        writer.flush()
+    })();
+    if let Err(e) = result {
+        warn!("dnstap file output stopped: {e}");
+    }
+}
+
+fn write_socket(path: PathBuf, receiver: Receiver<Vec<u8>>) {
+    let mut stream: Option<UnixStream> = None;
+    let mut last_attempt: Option<Instant> = None;
+
+    for frame in receiver {
+        if stream.is_none() && last_attempt.is_none_or(|at| at.elapsed() >= RECONNECT_INTERVAL) {
+            last_attempt = Some(Instant::now());
+            match connect(&path) {
+                Ok(connected) => stream = Some(connected),
+                Err(e) => debug!("dnstap socket {} unavailable: {e}", path.display()),
+            }
+        }
+        if let Some(connected) = &mut stream {
+            if let Err(e) = write_data_frame(connected, &frame) {
+                warn!("dnstap socket {} write failed: {e}", path.display());
+                stream = None;
+            }
+        }
+    }
+
+    if let Some(mut connected) = stream {
+        let _ = connected.write_all(&control_frame(CONTROL_STOP));
+    }
+}
+
+/// Open the socket and run the READY/ACCEPT/START handshake.
+fn connect(path: &PathBuf) -> IoResult<UnixStream> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(&control_frame(CONTROL_READY))?;
+
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header)?;
+    let length = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let mut control = vec![0u8; length];
+    stream.read_exact(&mut control)?;
+    if header[..4] != [0, 0, 0, 0] || control.get(..4) != Some(&CONTROL_ACCEPT.to_be_bytes()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "collector did not ACCEPT the handshake",
+        ));
+    }
+
+    stream.write_all(&control_frame(CONTROL_START))?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    fn exchange<'a>(query: &'a [u8], response: &'a [u8]) -> Exchange<'a> {
+        Exchange {
+            src: "192.0.2.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            query,
+            query_time: UNIX_EPOCH + Duration::new(1, 2),
+            response,
+            response_time: UNIX_EPOCH + Duration::new(3, 4),
+        }
+    }
+
+    #[test]
+    fn varints_are_little_endian_base128() {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, 1);
+        put_varint(&mut buf, 300);
+        assert_eq!(buf, [0x01, 0xAC, 0x02]);
+    }
+
+    #[test]
+    fn auth_query_message_layout() {
+        let message = exchange(&[0xAA, 0xBB], &[0xCC]).encode(MESSAGE_AUTH_QUERY);
+        assert_eq!(
+            message,
+            [
+                0x08, 0x01, // type: AUTH_QUERY
+                0x10, 0x01, // socket_family: INET
+                0x18, 0x01, // socket_protocol: UDP
+                0x22, 0x04, 192, 0, 2, 1, // query_address
+                0x30, 0xE9, 0x29, // query_port: 5353
+                0x40, 0x01, // query_time_sec
+                0x4D, 0x02, 0x00, 0x00, 0x00, // query_time_nsec
+                0x52, 0x02, 0xAA, 0xBB, // query_message
+            ]
+        );
+    }
+
+    #[test]
+    fn auth_response_carries_response_message() {
+        let message = exchange(&[0xAA], &[0xCC, 0xDD]).encode(MESSAGE_AUTH_RESPONSE);
+        assert!(message.starts_with(&[0x08, 0x02]));
+        assert!(message.ends_with(&[0x60, 0x03, 0x6D, 0x04, 0, 0, 0, 0x72, 0x02, 0xCC, 0xDD]));
+    }
+
+    #[test]
+    fn start_frame_names_content_type() {
+        let frame = control_frame(CONTROL_START);
+        assert_eq!(&frame[..4], &[0, 0, 0, 0]);
+        assert_eq!(
+            u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]) as usize,
+            frame.len() - 8
+        );
+        assert_eq!(&frame[8..12], &CONTROL_START.to_be_bytes());
+        assert!(frame.ends_with(CONTENT_TYPE));
+        assert_eq!(control_frame(CONTROL_STOP).len(), 12);
+    }
+
+    #[test]
+    fn socket_handshake_then_data() {
+        let path =
+            std::env::temp_dir().join(format!("rustyalias-dnstap-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let collector = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut ready = vec![0u8; control_frame(CONTROL_READY).len()];
+            stream.read_exact(&mut ready).unwrap();
+            assert_eq!(ready, control_frame(CONTROL_READY));
+            stream.write_all(&control_frame(CONTROL_ACCEPT)).unwrap();
+
+            let mut start = vec![0u8; control_frame(CONTROL_START).len()];
+            stream.read_exact(&mut start).unwrap();
+            assert_eq!(start, control_frame(CONTROL_START));
+
+            let mut length = [0u8; 4];
+            stream.read_exact(&mut length).unwrap();
+            let mut payload = vec![0u8; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut payload).unwrap();
+            payload
+        });
+
+        let dnstap = Dnstap::start(&Output::Socket(path.clone()), "ns1", "RustyAlias").unwrap();
+        dnstap.record(
+            "192.0.2.1:5353".parse().unwrap(),
+            Transport::Udp,
+            &[0xAA],
+            UNIX_EPOCH,
+            &[],
+            UNIX_EPOCH,
+        );
+
+        let payload = collector.join().unwrap();
+        assert!(payload.starts_with(&[0x0A, 0x03, b'n', b's', b'1']));
+        assert!(payload.ends_with(&[0x78, 0x01]));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
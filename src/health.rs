@@ -0,0 +1,100 @@
+use crate::config::Config;
+use crate::dns::query::handle_query_internal;
+use crate::dns::response::encode_domain_name;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The address the readiness self-query asks for, in dash notation.
+const PROBE_IP: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
+
+/// Tracks whether the DNS listeners are up. Cheap to clone; every clone
+/// shares the same flags.
+#[derive(Clone, Default)]
+pub struct Health {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    udp_ready: AtomicBool,
+    tcp_ready: AtomicBool,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_udp_ready(&self) {
+        self.inner.udp_ready.store(true, Ordering::Release);
+    }
+
+    pub fn set_tcp_ready(&self) {
+        self.inner.tcp_ready.store(true, Ordering::Release);
+    }
+
+    /// Returns `Ok` when both listeners are serving and a synthesized query
+    /// resolves to the expected address, otherwise the reason it isn't ready.
+    pub fn check_ready(&self, config: &Config) -> Result<(), String> {
+        if !self.inner.udp_ready.load(Ordering::Acquire) {
+            return Err("UDP listener not ready".to_string());
+        }
+        if !self.inner.tcp_ready.load(Ordering::Acquire) {
+            return Err("TCP listener not ready".to_string());
+        }
+        self_query(config)
+    }
+}
+
+/// Resolve `127-0-0-1.<GLUE_NAME>` through the same code path as a real
+/// query and check the A record in the answer.
+fn self_query(config: &Config) -> Result<(), String> {
+    let octets = PROBE_IP.octets();
+    let name = format!(
+        "{}-{}-{}-{}.{}",
+        octets[0], octets[1], octets[2], octets[3], config.glue_name
+    );
+
+    let mut query = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+    query.extend(encode_domain_name(&name));
+    query.extend(&[0x00, 0x01, 0x00, 0x01]); // Type A, Class IN
+
+    let source = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let response = handle_query_internal(&query, source, config).response;
+
+    if response.len() < 12 || response[3] & 0x0F != 0 {
+        return Err(format!("self-query for {name} did not return NOERROR"));
+    }
+    if u16::from_be_bytes([response[6], response[7]]) != 1 || !response.ends_with(&octets) {
+        return Err(format!("self-query for {name} did not return {PROBE_IP}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_ready_until_both_listeners_are_up() {
+        let health = Health::new();
+        assert!(health
+            .check_ready(&Config::for_tests("ns.example.com"))
+            .is_err());
+        health.set_udp_ready();
+        assert!(health
+            .check_ready(&Config::for_tests("ns.example.com"))
+            .is_err());
+        health.set_tcp_ready();
+        assert_eq!(
+            health.check_ready(&Config::for_tests("ns.example.com")),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn self_query_resolves_probe_address() {
+        assert_eq!(self_query(&Config::for_tests("ns.example.com")), Ok(()));
+    }
+}
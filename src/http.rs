@@ -0,0 +1,608 @@
+use crate::acme;
+use crate::admin;
+use crate::base64;
+use crate::config::Config;
+use crate::dns::query::is_in_zone;
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimiter;
+use crate::tcp::ConnectionLimiter;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{BufReader, ErrorKind, Result as IoResult};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Everything the HTTP endpoints need to read. Apart from the config, each
+/// field is a cheap, shared handle onto the state the DNS listeners update.
+#[derive(Clone)]
+pub struct HttpState {
+    pub config: Config,
+    pub metrics: Metrics,
+    pub rate_limiter: RateLimiter,
+    pub connections: ConnectionLimiter,
+    pub health: Health,
+}
+
+/// Largest request body accepted; the API only takes small JSON objects.
+const MAX_BODY: usize = 4096;
+
+/// Largest request line and headers accepted, together, and most header
+/// lines, so that a client can't grow a request without end.
+const MAX_HEAD: u64 = 8192;
+const MAX_HEADERS: usize = 64;
+
+#[derive(Default)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// The query string, without the `?`.
+    pub query: String,
+    pub authorization: Option<String>,
+    pub body: String,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    pub fn json(status: u16, body: String) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+}
+
+/// Most HTTP connections handled at once. Past that, up to
+/// `PROBE_CONNECTIONS` more are served only if they are health probes, and
+/// further ones are closed.
+const MAX_CONNECTIONS: usize = 64;
+const PROBE_CONNECTIONS: usize = 8;
+
+/// Time a client has to send its whole request, however it trickles in.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve HTTP requests on `listener` until the process exits. Each
+/// connection gets its own thread and `REQUEST_TIMEOUT` to send its request,
+/// so a slow client can't hold up the health probes, and at most
+/// `MAX_CONNECTIONS` are open at once, not counting probes.
+pub fn serve(listener: TcpListener, state: HttpState) {
+    let state = Arc::new(state);
+    let open = Arc::new(AtomicUsize::new(0));
+    let probes = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let (count, probes_only) = if acquire(&open, MAX_CONNECTIONS) {
+                    (open.clone(), false)
+                } else if acquire(&probes, PROBE_CONNECTIONS) {
+                    (probes.clone(), true)
+                } else {
+                    debug!("HTTP connection refused: {MAX_CONNECTIONS} already open");
+                    continue;
+                };
+                let state = state.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, &state, probes_only) {
+                        debug!("Error handling HTTP request: {e}");
+                    }
+                    count.fetch_sub(1, Ordering::AcqRel);
+                });
+            }
+            Err(e) => eprintln!("Error accepting HTTP connection: {e}"),
+        }
+    }
+}
+
+/// Take one of `max` slots counted by `count`, if one is free.
+fn acquire(count: &AtomicUsize, max: usize) -> bool {
+    if count.fetch_add(1, Ordering::AcqRel) >= max {
+        count.fetch_sub(1, Ordering::AcqRel);
+        return false;
+    }
+    true
+}
+
+/// A stream whose reads fail once `deadline` has passed, rather than only
+/// when a single read takes too long.
+struct Deadline {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let remaining = self
+            .deadline
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or(ErrorKind::TimedOut)?;
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Answer one request. With `probes_only`, anything but a health probe
+/// gets a 503.
+fn handle_connection(stream: TcpStream, state: &HttpState, probes_only: bool) -> IoResult<()> {
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut reader = BufReader::new(Deadline {
+        stream,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    });
+    let response = match read_request(&mut reader)? {
+        Some(request) if probes_only && !is_probe(&request) => {
+            Response::text(503, "Service Unavailable\n")
+        }
+        Some(request) => route(&request, state),
+        None => Response::text(400, "Bad Request\n"),
+    };
+
+    let mut stream = reader.into_inner().stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+/// Read one line of the request head, `None` if it was cut short by
+/// `MAX_HEAD` or the end of the input. Empty at the end of the input.
+fn read_head_line(head: &mut impl BufRead) -> IoResult<Option<String>> {
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    Ok((line.is_empty() || line.ends_with('\n')).then_some(line))
+}
+
+fn read_request(reader: &mut impl BufRead) -> IoResult<Option<Request>> {
+    let mut head = reader.by_ref().take(MAX_HEAD);
+    let Some(request_line) = read_head_line(&mut head)? else {
+        return Ok(None);
+    };
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(None);
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (path, query) = (path.to_string(), query.to_string());
+    let method = method.to_string();
+
+    // Of the headers, only the ones the ACME API needs are kept.
+    let mut authorization = None;
+    let mut content_length = 0;
+    for count in 0.. {
+        let Some(line) = read_head_line(&mut head)? else {
+            return Ok(None);
+        };
+        if line.trim_end().is_empty() {
+            break;
+        }
+        if count == MAX_HEADERS {
+            return Ok(None);
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("content-length") {
+            match value.parse() {
+                Ok(length) if length <= MAX_BODY => content_length = length,
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let Ok(body) = String::from_utf8(body) else {
+        return Ok(None);
+    };
+
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        authorization,
+        body,
+    }))
+}
+
+fn is_probe(request: &Request) -> bool {
+    request.method == "GET" && matches!(request.path.as_str(), "/healthz" | "/readyz")
+}
+
+fn route(request: &Request, state: &HttpState) -> Response {
+    if let Some(action) = request.path.strip_prefix("/acme/") {
+        return acme_route(action, request, &state.config);
+    }
+    if let Some(action) = request.path.strip_prefix("/admin/") {
+        return admin::route(action, request, state);
+    }
+    if request.method != "GET" {
+        return Response::text(405, "Method Not Allowed\n");
+    }
+    match request.path.as_str() {
+        "/metrics" => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body: state
+                .metrics
+                .render(&state.rate_limiter, &state.connections),
+        },
+        "/healthz" => Response::text(200, "ok\n"),
+        "/readyz" => match state.health.check_ready(&state.config) {
+            Ok(()) => Response::text(200, "ok\n"),
+            Err(reason) => Response::text(503, format!("{reason}\n")),
+        },
+        _ => Response::text(404, "Not Found\n"),
+    }
+}
+
+/// The ACME challenge API, compatible with lego's `httpreq` provider:
+/// `POST /acme/present` and `POST /acme/cleanup` with a JSON body of
+/// `{"fqdn": "_acme-challenge.<name>.", "value": "<token>"}`. The
+/// `ACME_API_TOKEN` is given as a bearer token or as the password of
+/// basic authentication. Without a value, cleanup clears the whole name.
+fn acme_route(action: &str, request: &Request, config: &Config) -> Response {
+    let Some(token) = &config.acme_api_token else {
+        return Response::text(404, "Not Found\n");
+    };
+    if action != "present" && action != "cleanup" {
+        return Response::text(404, "Not Found\n");
+    }
+    if request.method != "POST" {
+        return Response::text(405, "Method Not Allowed\n");
+    }
+    if !authorized(request.authorization.as_deref(), token) {
+        return Response::text(401, "Unauthorized\n");
+    }
+    challenge(action, request, config)
+}
+
+/// Present or clean up a challenge from a JSON request, once authorized.
+pub fn challenge(action: &str, request: &Request, config: &Config) -> Response {
+    let Some(mut fields) = json_object(&request.body) else {
+        return Response::text(400, "Invalid JSON object\n");
+    };
+    let Some(fqdn) = fields.remove("fqdn") else {
+        return Response::text(400, "Missing fqdn\n");
+    };
+    let name = fqdn.trim_end_matches('.');
+    if !acme::is_challenge_name(name) || !is_in_zone(name, &config.glue_name) {
+        return Response::text(400, format!("Not a challenge name in the zone: {fqdn}\n"));
+    }
+    // Key authorization digests are 43 base64url characters; anything that
+    // fits a TXT string without quoting is accepted.
+    let value = fields.remove("value").filter(|value| {
+        !value.is_empty() && value.len() <= 255 && value.bytes().all(|b| b.is_ascii_graphic())
+    });
+
+    match (action, value) {
+        ("present", Some(value)) => {
+            info!("ACME challenge presented at [{name}]");
+            config.challenges.present(name, &value);
+        }
+        ("present", None) => return Response::text(400, "Missing or invalid value\n"),
+        (_, value) => {
+            info!("ACME challenge cleaned up at [{name}]");
+            config.challenges.cleanup(name, value.as_deref());
+        }
+    }
+    Response::text(200, "ok\n")
+}
+
+/// Whether an `Authorization` header carries `token`, as `Bearer <token>`
+/// or as the password in `Basic` credentials.
+pub fn authorized(header: Option<&str>, token: &str) -> bool {
+    let Some((scheme, credentials)) = header.and_then(|header| header.split_once(' ')) else {
+        return false;
+    };
+    let credentials = credentials.trim();
+    let given = if scheme.eq_ignore_ascii_case("bearer") {
+        credentials.as_bytes().to_vec()
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        let Some(decoded) = base64::decode(credentials) else {
+            return false;
+        };
+        match decoded.iter().position(|&b| b == b':') {
+            Some(colon) => decoded[colon + 1..].to_vec(),
+            None => return false,
+        }
+    } else {
+        return false;
+    };
+    // Compare in constant time, so the token can't be guessed byte by byte.
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Parse a flat JSON object whose values are all strings, which is all the
+/// API is ever sent.
+fn json_object(text: &str) -> Option<HashMap<String, String>> {
+    let mut chars = text.trim().chars().peekable();
+    let mut fields = HashMap::new();
+    let skip_whitespace = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    let string = |chars: &mut std::iter::Peekable<std::str::Chars>| -> Option<String> {
+        if chars.next()? != '"' {
+            return None;
+        }
+        let mut string = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(string),
+                '\\' => string.push(match chars.next()? {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'u' => {
+                        let hex: String = (0..4).map(|_| chars.next()).collect::<Option<_>>()?;
+                        char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                    }
+                    c @ ('"' | '\\' | '/') => c,
+                    _ => return None,
+                }),
+                c if c.is_control() => return None,
+                c => string.push(c),
+            }
+        }
+    };
+
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_whitespace(&mut chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return chars.next().is_none().then_some(fields);
+    }
+    loop {
+        skip_whitespace(&mut chars);
+        let name = string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_whitespace(&mut chars);
+        let value = string(&mut chars)?;
+        fields.insert(name, value);
+        skip_whitespace(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            '}' => return chars.next().is_none().then_some(fields),
+            _ => return None,
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> HttpState {
+        HttpState {
+            config: Config::for_tests("ns.example.com"),
+            metrics: Metrics::new(),
+            rate_limiter: RateLimiter::new(0, 0),
+            connections: ConnectionLimiter::new(0, 0),
+            health: Health::new(),
+        }
+    }
+
+    #[test]
+    fn request_line_is_parsed() {
+        let raw = b"GET /metrics?x=1 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = read_request(&mut &raw[..]).unwrap().unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/metrics");
+        assert_eq!(request.query, "x=1");
+    }
+
+    #[test]
+    fn oversized_heads_are_rejected() {
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "x".repeat(9000));
+        assert!(read_request(&mut long_line.as_bytes()).unwrap().is_none());
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "x".repeat(9000));
+        assert!(read_request(&mut long_header.as_bytes()).unwrap().is_none());
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X: y\r\n".repeat(65));
+        assert!(read_request(&mut many.as_bytes()).unwrap().is_none());
+        let enough = format!("GET / HTTP/1.1\r\n{}\r\n", "X: y\r\n".repeat(64));
+        assert!(read_request(&mut enough.as_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn metrics_route() {
+        let request = Request {
+            method: "GET".to_string(),
+            path: "/metrics".to_string(),
+            ..Request::default()
+        };
+        let response = route(&request, &state());
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("rustyalias_queries_total"));
+
+        let request = Request {
+            method: "GET".to_string(),
+            path: "/nope".to_string(),
+            ..Request::default()
+        };
+        assert_eq!(route(&request, &state()).status, 404);
+    }
+
+    #[test]
+    fn readiness_follows_listeners() {
+        let get = |path: &str| Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            ..Request::default()
+        };
+        let state = state();
+        assert_eq!(route(&get("/healthz"), &state).status, 200);
+        assert_eq!(route(&get("/readyz"), &state).status, 503);
+
+        state.health.set_udp_ready();
+        state.health.set_tcp_ready();
+        assert_eq!(route(&get("/readyz"), &state).status, 200);
+    }
+
+    #[test]
+    fn idle_connections_dont_block_probes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, state()));
+
+        // Idle clients take every regular slot; probes still get through,
+        // other requests don't.
+        let _idle: Vec<TcpStream> = (0..MAX_CONNECTIONS)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        thread::sleep(Duration::from_millis(100));
+        let get = |path: &str| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            write!(client, "GET {path} HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        let probe = get("/healthz");
+        assert!(probe.starts_with("HTTP/1.1 200 OK"), "{probe}");
+        let metrics = get("/metrics");
+        assert!(metrics.starts_with("HTTP/1.1 503"), "{metrics}");
+    }
+
+    #[test]
+    fn requests_must_arrive_before_the_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (&client).write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let mut late = Deadline {
+            stream,
+            deadline: Instant::now(),
+        };
+        let error = late.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn acme_challenges_are_set_through_the_api() {
+        let mut state = state();
+        state.config.acme_api_token = Some("s3cret".to_string());
+        let post = |path: &str, authorization: &str, body: &str| Request {
+            method: "POST".to_string(),
+            path: path.to_string(),
+            query: String::new(),
+            authorization: Some(authorization.to_string()),
+            body: body.to_string(),
+        };
+        let name = "_acme-challenge.app-10-0-0-1.ns.example.com";
+        let present = format!(
+            r#"{{"fqdn": "{name}.", "value": "LHDhK3oGRvkiefQnx7OOczTY5Tic_xZ6HcMOc_gmtoM"}}"#
+        );
+
+        let raw = format!(
+            "POST /acme/present HTTP/1.1\r\nAuthorization: Bearer s3cret\r\nContent-Length: {}\r\n\r\n{present}",
+            present.len()
+        );
+        let request = read_request(&mut raw.as_bytes()).unwrap().unwrap();
+        assert_eq!(route(&request, &state).status, 200);
+        assert_eq!(
+            state.config.challenges.get(name),
+            vec!["LHDhK3oGRvkiefQnx7OOczTY5Tic_xZ6HcMOc_gmtoM"]
+        );
+
+        // Basic authentication as sent by lego, with the token as password.
+        let basic = format!("Basic {}", base64::encode(b"lego:s3cret"));
+        let cleanup = format!(r#"{{"fqdn":"{name}."}}"#);
+        assert_eq!(
+            route(&post("/acme/cleanup", &basic, &cleanup), &state).status,
+            200
+        );
+        assert!(state.config.challenges.get(name).is_empty());
+
+        assert_eq!(
+            route(&post("/acme/present", "Bearer s3cre", &present), &state).status,
+            401
+        );
+        let outside = r#"{"fqdn":"_acme-challenge.example.org.","value":"x"}"#;
+        assert_eq!(
+            route(&post("/acme/present", "Bearer s3cret", outside), &state).status,
+            400
+        );
+        let not_challenge = r#"{"fqdn":"www.ns.example.com.","value":"x"}"#;
+        assert_eq!(
+            route(
+                &post("/acme/present", "Bearer s3cret", not_challenge),
+                &state
+            )
+            .status,
+            400
+        );
+        assert!(state.config.challenges.get(name).is_empty());
+
+        state.config.acme_api_token = None;
+        assert_eq!(
+            route(&post("/acme/present", "Bearer s3cret", &present), &state).status,
+            404
+        );
+    }
+
+    #[test]
+    fn json_objects_are_parsed() {
+        let fields = json_object(r#" { "a" : "x\"y\u0041" , "b":"" } "#).unwrap();
+        assert_eq!(fields["a"], "x\"yA");
+        assert_eq!(fields["b"], "");
+        assert!(json_object("{}").unwrap().is_empty());
+        assert!(json_object(r#"{"a": 1}"#).is_none());
+        assert!(json_object(r#"{"a": "x"} trailing"#).is_none());
+        assert!(json_object(r#"{"a": "x",}"#).is_none());
+    }
+}
@@ -1,21 +1,118 @@
+mod acme;
+mod admin;
+mod base64;
+mod cidr;
 mod config;
 mod dns;
+mod dnstap;
+mod health;
+mod http;
+mod metrics;
+mod query_log;
 mod rate_limit;
+mod rrl;
+mod tcp;
 
 use config::Config;
-use dns::query::{handle_query, handle_query_internal};
+use dns::cookie::Cookie;
+use dns::query::{
+    handle_badcookie, handle_query, handle_tcp_query, query_cookie, Resolution, UDP_RECEIVE_SIZE,
+};
+use dns::response::build_error_response;
+use dns::Transport;
+use dnstap::Dnstap;
 use env_logger::init;
-use log::{debug, info};
+use health::Health;
+use http::HttpState;
+use log::{debug, info, warn};
+use metrics::Metrics;
+use query_log::QueryLog;
 use rate_limit::RateLimiter;
+use rrl::{Action, ResponseRateLimiter};
 use std::io::prelude::*;
 use std::io::Result as IoResult;
-use std::net::{TcpListener, UdpSocket};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tcp::ConnectionLimiter;
+
+/// Everything that is told about a query once its response has been sent.
+#[derive(Clone)]
+struct Observers {
+    metrics: Metrics,
+    query_log: Option<QueryLog>,
+    dnstap: Option<Dnstap>,
+}
+
+impl Observers {
+    fn record(
+        &self,
+        src: SocketAddr,
+        transport: Transport,
+        query: &[u8],
+        resolution: &Resolution,
+        received: SystemTime,
+        started: Instant,
+    ) {
+        let elapsed = started.elapsed();
+        self.metrics.record_query(transport, resolution, elapsed);
+        if let Some(query_log) = &self.query_log {
+            query_log.record(src, transport, resolution, elapsed);
+        }
+        if let Some(dnstap) = &self.dnstap {
+            dnstap.record(
+                src,
+                transport,
+                query,
+                received,
+                &resolution.response,
+                received + elapsed,
+            );
+        }
+    }
+}
 
 fn main() -> IoResult<()> {
     init();
     let config = Config::from_env();
-    let rate_limiter = RateLimiter::new(config.rate_limit_seconds, config.rate_limit_requests);
+    let rate_limiter = RateLimiter::new(config.rate_limit_seconds, config.rate_limit_requests)
+        .with_algorithm(config.rate_limit_algorithm)
+        .with_prefixes(config.rate_limit_ipv4_prefix, config.rate_limit_ipv6_prefix)
+        .with_overrides(config.rate_limit_overrides.clone())
+        .with_max_clients(config.rate_limit_max_clients);
+    let rrl = ResponseRateLimiter::new(
+        config.rrl_limits,
+        config.rrl_slip,
+        config.rrl_ipv4_prefix,
+        config.rrl_ipv6_prefix,
+    );
+    let connections = ConnectionLimiter::new(
+        config.tcp_max_connections_per_client,
+        config.tcp_max_connections,
+    );
+    let metrics = Metrics::new();
+    let health = Health::new();
+    let query_log = match &config.query_log {
+        Some(destination) => Some(QueryLog::open(
+            destination,
+            config.query_log_max_bytes,
+            config.query_log_max_files,
+        )?),
+        None => None,
+    };
+    let dnstap = match &config.dnstap {
+        Some(output) => Some(Dnstap::start(
+            output,
+            &config.dnstap_identity,
+            &format!("RustyAlias v{}", config.version),
+        )?),
+        None => None,
+    };
+    let observers = Observers {
+        metrics: metrics.clone(),
+        query_log,
+        dnstap,
+    };
 
     let udp_socket = UdpSocket::bind("[::]:5053")?;
     let tcp_listener = TcpListener::bind("[::]:5053")?;
@@ -23,48 +120,148 @@ fn main() -> IoResult<()> {
     println!("RustyAlias Server Started on Port 5053 (UDP/TCP)");
     if rate_limiter.is_enabled() {
         println!(
-            "Rate limit: {} requests per {} second(s) per source IP",
-            config.rate_limit_requests, config.rate_limit_seconds
+            "Rate limit: {} requests per {} second(s) per /{} (IPv4) or /{} (IPv6) ({:?}), tracking up to {} clients",
+            config.rate_limit_requests,
+            config.rate_limit_seconds,
+            config.rate_limit_ipv4_prefix,
+            config.rate_limit_ipv6_prefix,
+            config.rate_limit_algorithm,
+            config.rate_limit_max_clients
         );
+        for (network, limit) in &config.rate_limit_overrides {
+            println!("Rate limit override: {network} {limit:?}");
+        }
+    }
+    if rrl.is_enabled() {
+        let limits = config.rrl_limits;
+        println!(
+            "Response rate limit: {} answers, {} nxdomain/nodata, {} refused, {} errors per second per /{} (IPv4) or /{} (IPv6), slip {}",
+            limits.answers,
+            limits.nxdomains,
+            limits.refused,
+            limits.errors,
+            config.rrl_ipv4_prefix,
+            config.rrl_ipv6_prefix,
+            config.rrl_slip
+        );
+    }
+    if let Some(key) = &config.dnssec {
+        println!(
+            "DNSSEC signing with {:?} key, tag {}",
+            key.algorithm(),
+            key.key_tag()
+        );
+        println!("DNSKEY: {}", key.dnskey_text());
+        println!("DS for the parent zone: {}", key.ds_record());
+    }
+
+    if let Some(addr) = config.http_listen {
+        let http_listener = TcpListener::bind(addr)?;
+        let mut endpoints = vec!["/metrics", "/healthz", "/readyz"];
+        if config.acme_api_token.is_some() {
+            endpoints.push("/acme");
+        }
+        if config.admin_api_token.is_some() {
+            endpoints.push("/admin");
+        }
+        println!("HTTP listener started on {addr} ({})", endpoints.join(", "));
+        let state = HttpState {
+            config: config.clone(),
+            metrics: metrics.clone(),
+            rate_limiter: rate_limiter.clone(),
+            connections: connections.clone(),
+            health: health.clone(),
+        };
+        thread::spawn(move || http::serve(http_listener, state));
     }
 
     let udp_config = config.clone();
     let udp_rate_limiter = rate_limiter.clone();
-    thread::spawn(move || loop {
-        let mut buf = [0; 512];
-        if let Ok((amt, src)) = udp_socket.recv_from(&mut buf) {
-            debug!("Received UDP query from {}: {:?}", src, &buf[..amt]);
-            if !udp_rate_limiter.check(src.ip()) {
-                info!("Client [{src}] rate limited (UDP)");
-                continue;
-            }
-            if let Err(e) = handle_query(&buf[..amt], &udp_socket, src, &udp_config) {
-                eprintln!("Error handling UDP query: {e}");
+    let udp_observers = observers.clone();
+    let udp_health = health.clone();
+    thread::spawn(move || {
+        udp_health.set_udp_ready();
+        let mut buf = vec![0; UDP_RECEIVE_SIZE];
+        loop {
+            if let Ok((amt, src)) = udp_socket.recv_from(&mut buf) {
+                let started = Instant::now();
+                let received = SystemTime::now();
+                debug!("Received UDP query from {}: {:?}", src, &buf[..amt]);
+                let query = &buf[..amt];
+                let exempt = udp_config.cookie_rate_limit_exempt
+                    && matches!(query_cookie(query, src, &udp_config), Cookie::Valid(_));
+                if !exempt && !udp_rate_limiter.check(src.ip()) {
+                    info!("Client [{src}] rate limited (UDP)");
+                    udp_observers.metrics.record_rate_limited(Transport::Udp);
+                    let badcookie = udp_config
+                        .cookie_badcookie
+                        .then(|| handle_badcookie(query, src, &udp_config))
+                        .flatten();
+                    if let Some(resolution) = badcookie {
+                        if let Err(e) = udp_socket.send_to(&resolution.response, src) {
+                            eprintln!("Error sending BADCOOKIE response: {e}");
+                            continue;
+                        }
+                        udp_observers.record(
+                            src,
+                            Transport::Udp,
+                            query,
+                            &resolution,
+                            received,
+                            started,
+                        );
+                    }
+                    continue;
+                }
+                match handle_query(&buf[..amt], &udp_socket, src, &udp_config, &rrl) {
+                    Ok((_, Action::Drop)) => udp_observers.metrics.record_rrl(Action::Drop),
+                    Ok((resolution, action)) => {
+                        udp_observers.metrics.record_rrl(action);
+                        udp_observers.record(
+                            src,
+                            Transport::Udp,
+                            &buf[..amt],
+                            &resolution,
+                            received,
+                            started,
+                        );
+                    }
+                    Err(e) => eprintln!("Error handling UDP query: {e}"),
+                }
             }
         }
     });
 
+    // The TCP loop starts accepting right below.
+    health.set_tcp_ready();
     for stream in tcp_listener.incoming() {
         match stream {
-            Ok(mut stream) => {
+            Ok(stream) => {
                 let peer = stream.peer_addr()?;
                 if !rate_limiter.check(peer.ip()) {
                     info!("Client [{peer}] rate limited (TCP)");
+                    observers.metrics.record_rate_limited(Transport::Tcp);
                     continue;
                 }
-                let mut length_buf = [0; 2];
-                if stream.read_exact(&mut length_buf).is_ok() {
-                    let length = u16::from_be_bytes(length_buf) as usize;
-                    let mut buf = vec![0; length];
-
-                    if stream.read_exact(&mut buf).is_ok() {
-                        debug!("Received TCP query from {peer}: {buf:?}");
-                        let response = handle_query_internal(&buf, peer, &config)?;
-                        let response_len = (response.len() as u16).to_be_bytes();
-                        stream.write_all(&response_len)?;
-                        stream.write_all(&response)?;
+                let connection = match connections.acquire(peer.ip()) {
+                    Ok(connection) => connection,
+                    Err(refusal) => {
+                        info!(
+                            "Client [{peer}] refused: TCP connection limit reached ({})",
+                            refusal.as_str()
+                        );
+                        observers.metrics.record_tcp_refused(refusal);
+                        continue;
                     }
-                }
+                };
+                let config = config.clone();
+                let observers = observers.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_tcp(stream, peer, &config, &observers) {
+                        debug!("TCP connection from {peer} ended: {e}");
+                    }
+                    drop(connection);
+                });
             }
             Err(e) => eprintln!("Error accepting TCP connection: {e}"),
         }
@@ -72,3 +269,51 @@ fn main() -> IoResult<()> {
 
     Ok(())
 }
+
+const RCODE_SERVFAIL: u8 = 2;
+
+/// Write timeout of TCP connections when `TCP_IDLE_TIMEOUT` is 0.
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Answer one length-prefixed query on an accepted TCP connection. A
+/// response too long for the two-byte length prefix is replaced with
+/// SERVFAIL.
+fn handle_tcp(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    config: &Config,
+    observers: &Observers,
+) -> IoResult<()> {
+    let idle_timeout = Some(Duration::from_secs(config.tcp_idle_timeout)).filter(|t| !t.is_zero());
+    stream.set_read_timeout(idle_timeout)?;
+    // A client that stops reading mustn't keep its slot either, even
+    // without an idle timeout.
+    stream.set_write_timeout(idle_timeout.or(Some(TCP_WRITE_TIMEOUT)))?;
+    let mut length_buf = [0; 2];
+    stream.read_exact(&mut length_buf)?;
+    let length = u16::from_be_bytes(length_buf) as usize;
+    let mut buf = vec![0; length];
+    stream.read_exact(&mut buf)?;
+
+    let started = Instant::now();
+    let received = SystemTime::now();
+    debug!("Received TCP query from {peer}: {buf:?}");
+    let (resolution, rest) = handle_tcp_query(&buf, peer, config);
+    for message in std::iter::once(&resolution.response).chain(&rest) {
+        let Ok(length) = u16::try_from(message.len()) else {
+            warn!(
+                "Response to {peer} is too long for TCP ({} bytes), sending SERVFAIL",
+                message.len()
+            );
+            // No longer than the query, whose length fit in two bytes.
+            let servfail = build_error_response(&buf, RCODE_SERVFAIL);
+            stream.write_all(&(servfail.len() as u16).to_be_bytes())?;
+            stream.write_all(&servfail)?;
+            break;
+        };
+        stream.write_all(&length.to_be_bytes())?;
+        stream.write_all(message)?;
+    }
+    observers.record(peer, Transport::Tcp, &buf, &resolution, received, started);
+    Ok(())
+}
@@ -0,0 +1,374 @@
+use crate::dns::ip_parser::Encoding;
+use crate::dns::query::{qtype_name, rcode_name, Outcome, Resolution};
+use crate::dns::Transport;
+use crate::rate_limit::RateLimiter;
+use crate::rrl::Action;
+use crate::tcp::{ConnectionLimiter, Refusal};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the response latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+];
+
+/// Prometheus counters and histograms for the resolver.
+///
+/// Like [`RateLimiter`], cloning only clones an `Arc`, so the UDP thread,
+/// the TCP loop and the HTTP listener all update and read the same values.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    queries: Mutex<HashMap<QueryKey, u64>>,
+    refused_out_of_zone: AtomicU64,
+    rate_limited: [AtomicU64; 2],
+    rrl_dropped: AtomicU64,
+    rrl_slipped: AtomicU64,
+    tcp_refused: [AtomicU64; 2],
+    parse_failures: [AtomicU64; 2],
+    latency: [Histogram; 2],
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct QueryKey {
+    transport: Transport,
+    /// `None` for types without a mnemonic, labelled `other`, so clients
+    /// can't create a series per type number.
+    qtype: Option<u16>,
+    rcode: u16,
+    encoding: Option<Encoding>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+fn index(transport: Transport) -> usize {
+    match transport {
+        Transport::Udp => 0,
+        Transport::Tcp => 1,
+    }
+}
+
+const TRANSPORTS: [Transport; 2] = [Transport::Udp, Transport::Tcp];
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for one query that made it past the rate limiter.
+    pub fn record_query(&self, transport: Transport, resolution: &Resolution, elapsed: Duration) {
+        let (Some(question), Some(rcode)) = (&resolution.question, resolution.rcode()) else {
+            self.inner.parse_failures[index(transport)].fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+
+        if resolution.outcome == Outcome::OutOfZone {
+            self.inner
+                .refused_out_of_zone
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        let encoding = match resolution.outcome {
+            Outcome::Synthesized(encoding) => Some(encoding),
+            _ => None,
+        };
+        let key = QueryKey {
+            transport,
+            qtype: Some(question.qtype).filter(|&qtype| !qtype_name(qtype).starts_with("TYPE")),
+            rcode,
+            encoding,
+        };
+        let mut queries = match self.inner.queries.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *queries.entry(key).or_insert(0) += 1;
+        drop(queries);
+
+        self.inner.latency[index(transport)].observe(elapsed);
+    }
+
+    pub fn record_rate_limited(&self, transport: Transport) {
+        self.inner.rate_limited[index(transport)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rrl(&self, action: Action) {
+        match action {
+            Action::Send => {}
+            Action::Slip => {
+                self.inner.rrl_slipped.fetch_add(1, Ordering::Relaxed);
+            }
+            Action::Drop => {
+                self.inner.rrl_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn record_tcp_refused(&self, refusal: Refusal) {
+        self.inner.tcp_refused[refusal as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render every metric in the Prometheus text exposition format.
+    pub fn render(&self, rate_limiter: &RateLimiter, connections: &ConnectionLimiter) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP rustyalias_queries_total Queries answered, by transport, QTYPE, RCODE and encoding.\n");
+        out.push_str("# TYPE rustyalias_queries_total counter\n");
+        let guard = match self.inner.queries.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut queries: Vec<(QueryKey, u64)> = guard.iter().map(|(k, v)| (*k, *v)).collect();
+        drop(guard);
+        queries.sort_by_key(|(k, _)| {
+            (
+                index(k.transport),
+                k.qtype,
+                k.rcode,
+                k.encoding.map(|e| e.as_str()),
+            )
+        });
+        for (key, count) in queries {
+            let _ = writeln!(
+                out,
+                "rustyalias_queries_total{{transport=\"{}\",qtype=\"{}\",rcode=\"{}\",encoding=\"{}\"}} {}",
+                key.transport.as_str(),
+                key.qtype.map_or("other".to_string(), qtype_name),
+                rcode_name(key.rcode),
+                key.encoding.map_or("none", |e| e.as_str()),
+                count
+            );
+        }
+
+        out.push_str("# HELP rustyalias_refused_out_of_zone_total Queries refused because the name is outside the zone.\n");
+        out.push_str("# TYPE rustyalias_refused_out_of_zone_total counter\n");
+        let _ = writeln!(
+            out,
+            "rustyalias_refused_out_of_zone_total {}",
+            self.inner.refused_out_of_zone.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP rustyalias_rate_limited_total Queries dropped by the rate limiter.\n");
+        out.push_str("# TYPE rustyalias_rate_limited_total counter\n");
+        for transport in TRANSPORTS {
+            let _ = writeln!(
+                out,
+                "rustyalias_rate_limited_total{{transport=\"{}\"}} {}",
+                transport.as_str(),
+                self.inner.rate_limited[index(transport)].load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# HELP rustyalias_rrl_responses_total UDP responses held back by response rate limiting.\n");
+        out.push_str("# TYPE rustyalias_rrl_responses_total counter\n");
+        let _ = writeln!(
+            out,
+            "rustyalias_rrl_responses_total{{action=\"drop\"}} {}",
+            self.inner.rrl_dropped.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "rustyalias_rrl_responses_total{{action=\"slip\"}} {}",
+            self.inner.rrl_slipped.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP rustyalias_parse_failures_total Queries that could not be parsed.\n");
+        out.push_str("# TYPE rustyalias_parse_failures_total counter\n");
+        for transport in TRANSPORTS {
+            let _ = writeln!(
+                out,
+                "rustyalias_parse_failures_total{{transport=\"{}\"}} {}",
+                transport.as_str(),
+                self.inner.parse_failures[index(transport)].load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# HELP rustyalias_response_duration_seconds Time from receiving a query to sending its response.\n");
+        out.push_str("# TYPE rustyalias_response_duration_seconds histogram\n");
+        for transport in TRANSPORTS {
+            let histogram = &self.inner.latency[index(transport)];
+            for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "rustyalias_response_duration_seconds_bucket{{transport=\"{}\",le=\"{}\"}} {}",
+                    transport.as_str(),
+                    bound,
+                    bucket.load(Ordering::Relaxed)
+                );
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "rustyalias_response_duration_seconds_bucket{{transport=\"{}\",le=\"+Inf\"}} {}",
+                transport.as_str(),
+                count
+            );
+            let _ = writeln!(
+                out,
+                "rustyalias_response_duration_seconds_sum{{transport=\"{}\"}} {}",
+                transport.as_str(),
+                histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9
+            );
+            let _ = writeln!(
+                out,
+                "rustyalias_response_duration_seconds_count{{transport=\"{}\"}} {}",
+                transport.as_str(),
+                count
+            );
+        }
+
+        out.push_str("# HELP rustyalias_rate_limiter_tracked_clients Source addresses currently held in the rate-limiter table.\n");
+        out.push_str("# TYPE rustyalias_rate_limiter_tracked_clients gauge\n");
+        let _ = writeln!(
+            out,
+            "rustyalias_rate_limiter_tracked_clients {}",
+            rate_limiter.tracked_clients()
+        );
+
+        out.push_str("# HELP rustyalias_rate_limiter_evictions_total Clients evicted from a full rate-limiter table.\n");
+        out.push_str("# TYPE rustyalias_rate_limiter_evictions_total counter\n");
+        let _ = writeln!(
+            out,
+            "rustyalias_rate_limiter_evictions_total {}",
+            rate_limiter.evictions()
+        );
+
+        out.push_str("# HELP rustyalias_tcp_connections_open TCP connections currently open.\n");
+        out.push_str("# TYPE rustyalias_tcp_connections_open gauge\n");
+        let _ = writeln!(
+            out,
+            "rustyalias_tcp_connections_open {}",
+            connections.open_connections()
+        );
+
+        out.push_str("# HELP rustyalias_tcp_connections_refused_total TCP connections closed because a connection cap was reached.\n");
+        out.push_str("# TYPE rustyalias_tcp_connections_refused_total counter\n");
+        for refusal in [Refusal::PerClient, Refusal::Total] {
+            let _ = writeln!(
+                out,
+                "rustyalias_tcp_connections_refused_total{{limit=\"{}\"}} {}",
+                refusal.as_str(),
+                self.inner.tcp_refused[refusal as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::query::Question;
+
+    fn resolution(outcome: Outcome, rcode: u8) -> Resolution {
+        Resolution {
+            response: vec![0x12, 0x34, 0x81, 0x80 | rcode],
+            question: Some(Question {
+                name: "app.10.0.0.1.ns.example.com".to_string(),
+                qtype: 1,
+                qclass: 1,
+            }),
+            outcome,
+            answers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn queries_are_labelled() {
+        let metrics = Metrics::new();
+        let answered = resolution(Outcome::Synthesized(Encoding::Dot), 0);
+        metrics.record_query(Transport::Udp, &answered, Duration::from_micros(50));
+        metrics.record_query(Transport::Udp, &answered, Duration::from_micros(50));
+        metrics.record_query(
+            Transport::Tcp,
+            &resolution(Outcome::OutOfZone, 5),
+            Duration::from_millis(2),
+        );
+
+        for qtype in [999, 12345] {
+            let mut unknown = resolution(Outcome::Intermediate, 0);
+            unknown.question.as_mut().unwrap().qtype = qtype;
+            metrics.record_query(Transport::Udp, &unknown, Duration::from_secs(1));
+        }
+
+        let text = metrics.render(&RateLimiter::new(0, 0), &ConnectionLimiter::new(0, 0));
+        assert!(text.contains(
+            "rustyalias_queries_total{transport=\"udp\",qtype=\"A\",rcode=\"NOERROR\",encoding=\"dot\"} 2"
+        ));
+        assert!(text.contains(
+            "rustyalias_queries_total{transport=\"udp\",qtype=\"other\",rcode=\"NOERROR\",encoding=\"none\"} 2"
+        ));
+        assert!(!text.contains("TYPE999"));
+        assert!(text.contains(
+            "rustyalias_queries_total{transport=\"tcp\",qtype=\"A\",rcode=\"REFUSED\",encoding=\"none\"} 1"
+        ));
+        assert!(text.contains("rustyalias_refused_out_of_zone_total 1"));
+        assert!(text.contains(
+            "rustyalias_response_duration_seconds_bucket{transport=\"udp\",le=\"0.0001\"} 2"
+        ));
+        assert!(text.contains(
+            "rustyalias_response_duration_seconds_bucket{transport=\"tcp\",le=\"0.0025\"} 1"
+        ));
+        assert!(text.contains(
+            "rustyalias_response_duration_seconds_bucket{transport=\"tcp\",le=\"0.001\"} 0"
+        ));
+    }
+
+    #[test]
+    fn drops_and_parse_failures_are_counted() {
+        let metrics = Metrics::new();
+        metrics.record_rate_limited(Transport::Udp);
+        metrics.record_rrl(Action::Slip);
+        metrics.record_rrl(Action::Send);
+        metrics.record_tcp_refused(Refusal::PerClient);
+        metrics.record_query(
+            Transport::Tcp,
+            &Resolution {
+                response: Vec::new(),
+                question: None,
+                outcome: Outcome::Malformed,
+                answers: Vec::new(),
+            },
+            Duration::ZERO,
+        );
+
+        let text = metrics.render(&RateLimiter::new(0, 0), &ConnectionLimiter::new(0, 0));
+        assert!(text.contains("rustyalias_rate_limited_total{transport=\"udp\"} 1"));
+        assert!(text.contains("rustyalias_rate_limited_total{transport=\"tcp\"} 0"));
+        assert!(text.contains("rustyalias_parse_failures_total{transport=\"tcp\"} 1"));
+        assert!(text.contains("rustyalias_rate_limiter_tracked_clients 0"));
+        assert!(text.contains("rustyalias_rate_limiter_evictions_total 0"));
+        assert!(text.contains("rustyalias_rrl_responses_total{action=\"slip\"} 1"));
+        assert!(text.contains("rustyalias_rrl_responses_total{action=\"drop\"} 0"));
+        assert!(text.contains("rustyalias_tcp_connections_open 0"));
+        assert!(text.contains("rustyalias_tcp_connections_refused_total{limit=\"per_client\"} 1"));
+        assert!(text.contains("rustyalias_tcp_connections_refused_total{limit=\"total\"} 0"));
+    }
+}
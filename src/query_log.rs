@@ -0,0 +1,293 @@
+use crate::dns::query::{qtype_name, rcode_name, Outcome, Resolution};
+use crate::dns::Transport;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, Result as IoResult};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where structured query log lines go.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    Stdout,
+    File(PathBuf),
+}
+
+impl Destination {
+    /// `stdout` (or `-`) writes to standard output, anything else is a path.
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "stdout" | "-" => Destination::Stdout,
+            path => Destination::File(PathBuf::from(path)),
+        }
+    }
+}
+
+/// One JSON object per answered query, written independently of the
+/// `RUST_LOG` debug logger.
+///
+/// Cloning is cheap: the UDP thread and the TCP loop share the same writer.
+#[derive(Clone)]
+pub struct QueryLog {
+    inner: Arc<Mutex<Sink>>,
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: u32) -> IoResult<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            written,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> IoResult<()> {
+        if self.max_bytes > 0 && self.written + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift `log.1` to `log.2` and so on, dropping the oldest, then move the
+    /// current file to `log.1` and start a fresh one.
+    fn rotate(&mut self) -> IoResult<()> {
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+            self.written = 0;
+            return Ok(());
+        }
+        for n in (1..self.max_files).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+}
+
+impl QueryLog {
+    /// Open the log. `max_bytes` of `0` disables rotation; `max_files` is how
+    /// many rotated files to keep next to the live one.
+    pub fn open(destination: &Destination, max_bytes: u64, max_files: u32) -> IoResult<Self> {
+        let sink = match destination {
+            Destination::Stdout => Sink::Stdout,
+            Destination::File(path) => {
+                Sink::File(RotatingFile::open(path.clone(), max_bytes, max_files)?)
+            }
+        };
+        Ok(Self {
+            inner: Arc::new(Mutex::new(sink)),
+        })
+    }
+
+    pub fn record(
+        &self,
+        src: SocketAddr,
+        transport: Transport,
+        resolution: &Resolution,
+        elapsed: Duration,
+    ) {
+        let line = format_entry(SystemTime::now(), src, transport, resolution, elapsed);
+        let mut sink = match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let result = match &mut *sink {
+            Sink::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Sink::File(file) => file.write_line(&line),
+        };
+        if let Err(e) = result {
+            eprintln!("Error writing query log: {e}");
+        }
+    }
+}
+
+fn format_entry(
+    now: SystemTime,
+    src: SocketAddr,
+    transport: Transport,
+    resolution: &Resolution,
+    elapsed: Duration,
+) -> String {
+    let question = resolution.question.as_ref();
+    let encoding = match resolution.outcome {
+        Outcome::Synthesized(encoding) => json_string(encoding.as_str()),
+        _ => "null".to_string(),
+    };
+    let answers: Vec<String> = resolution.answers.iter().map(|a| json_string(a)).collect();
+
+    format!(
+        "{{\"timestamp\":{},\"client\":{},\"transport\":{},\"qname\":{},\"qtype\":{},\"rcode\":{},\"answer\":[{}],\"encoding\":{},\"response_size\":{},\"latency_us\":{}}}\n",
+        json_string(&format_timestamp(now)),
+        json_string(&src.to_string()),
+        json_string(transport.as_str()),
+        question.map_or("null".to_string(), |q| json_string(&q.name)),
+        question.map_or("null".to_string(), |q| json_string(&qtype_name(q.qtype))),
+        resolution
+            .rcode()
+            .map_or("null".to_string(), |rcode| json_string(&rcode_name(rcode))),
+        answers.join(","),
+        encoding,
+        resolution.response.len(),
+        elapsed.as_micros()
+    )
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// RFC 3339 UTC timestamp with millisecond precision.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Days since 1970-01-01 to a (year, month, day) date in the proleptic
+/// Gregorian calendar (Howard Hinnant's `civil_from_days`).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::ip_parser::Encoding;
+    use crate::dns::query::Question;
+
+    #[test]
+    fn timestamps_are_rfc3339() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_251_199_123);
+        assert_eq!(format_timestamp(time), "2024-02-29T23:59:59.123Z");
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\n\"");
+        assert_eq!(json_string("\u{1}"), "\"\\u0001\"");
+    }
+
+    #[test]
+    fn entry_has_every_field() {
+        let resolution = Resolution {
+            response: vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0],
+            question: Some(Question {
+                name: "app-10-0-0-1.ns.example.com".to_string(),
+                qtype: 1,
+                qclass: 1,
+            }),
+            outcome: Outcome::Synthesized(Encoding::Dash),
+            answers: vec!["10.0.0.1".to_string()],
+        };
+        let line = format_entry(
+            UNIX_EPOCH,
+            "192.0.2.1:5353".parse().unwrap(),
+            Transport::Udp,
+            &resolution,
+            Duration::from_micros(42),
+        );
+        assert_eq!(
+            line,
+            "{\"timestamp\":\"1970-01-01T00:00:00.000Z\",\"client\":\"192.0.2.1:5353\",\
+             \"transport\":\"udp\",\"qname\":\"app-10-0-0-1.ns.example.com\",\"qtype\":\"A\",\
+             \"rcode\":\"NOERROR\",\"answer\":[\"10.0.0.1\"],\"encoding\":\"dash\",\
+             \"response_size\":12,\"latency_us\":42}\n"
+        );
+    }
+
+    #[test]
+    fn file_rotates_when_full() {
+        let dir = std::env::temp_dir().join(format!("rustyalias-qlog-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.log");
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["aaaaaaaa\n", "bbbbbbbb\n", "cccccccc\n", "dddddddd\n"] {
+            file.write_line(line).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddddddd\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("queries.log.1")).unwrap(),
+            "cccccccc\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("queries.log.2")).unwrap(),
+            "bbbbbbbb\n"
+        );
+        assert!(!dir.join("queries.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,97 @@
-use std::collections::HashMap;
+use crate::cidr::{client_prefix, Cidr};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
 use std::net::IpAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::time::{Duration, Instant};
 
-/// Per-client-IP fixed-window rate limiter.
+/// How a client's quota is counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    /// At most `max_requests` per fixed window. Cheap, but a client can send
+    /// twice the limit across a window boundary.
+    FixedWindow,
+    /// Tokens refill continuously at `max_requests` per window, and up to
+    /// `burst` of them can be saved up and spent at once.
+    TokenBucket { burst: u32 },
+}
+
+impl Algorithm {
+    /// Parse the `RATE_LIMIT_ALGORITHM` value. A `burst` of `0` means "same
+    /// as the per-window limit".
+    pub fn parse(name: &str, burst: u32) -> Option<Self> {
+        match name {
+            "fixed-window" => Some(Algorithm::FixedWindow),
+            "token-bucket" => Some(Algorithm::TokenBucket { burst }),
+            _ => None,
+        }
+    }
+}
+
+/// A per-network exception to the default quota.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Override {
+    /// Never rate limited.
+    Exempt,
+    /// This many requests per window instead of the default. With the token
+    /// bucket it is also the burst size.
+    Limit(u32),
+}
+
+/// Parse `RATE_LIMIT_OVERRIDES`: comma-separated `network=limit` entries,
+/// where `limit` is a number of requests per window or `exempt`.
+pub fn parse_overrides(s: &str) -> Result<Vec<(Cidr, Override)>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (network, limit) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected network=limit, got {entry:?}"))?;
+            let limit = match limit.trim() {
+                "exempt" => Override::Exempt,
+                n => Override::Limit(
+                    n.parse()
+                        .map_err(|_| format!("invalid limit in {entry:?}"))?,
+                ),
+            };
+            Ok((network.parse()?, limit))
+        })
+        .collect()
+}
+
+/// Check that no override is narrower than the prefix clients are grouped
+/// by: the addresses sharing a quota must also share its limit.
+pub fn check_overrides(
+    overrides: &[(Cidr, Override)],
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+) -> Result<(), String> {
+    for (network, _) in overrides {
+        let prefix = if network.is_ipv4() {
+            ipv4_prefix
+        } else {
+            ipv6_prefix
+        };
+        if network.prefix_len() > prefix {
+            return Err(format!(
+                "{network} is narrower than the /{prefix} clients are grouped by"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Per-client rate limiter. A client is a source address, or the network it
+/// belongs to when prefix aggregation is configured.
+///
+/// State lives in a fixed number of shards, each behind its own lock and
+/// holding at most its share of `max_clients` buckets. When a shard is full
+/// the least recently seen client is forgotten, so a flood of spoofed
+/// sources costs bounded memory; an evicted client simply starts over with a
+/// fresh quota.
 ///
 /// Cloning is cheap: the inner state is shared via an `Arc`, so each clone
 /// (e.g. the one handed to the UDP thread) sees the same counters.
@@ -12,73 +100,386 @@ pub struct RateLimiter {
     inner: Arc<Inner>,
 }
 
+/// Number of independently locked parts of the client table.
+const SHARDS: usize = 16;
+
 struct Inner {
+    settings: Settings,
+    /// Keyed randomly per process so a sender can't aim all of its addresses
+    /// at one shard.
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+    evictions: AtomicU64,
+}
+
+#[derive(Clone)]
+struct Settings {
+    algorithm: Algorithm,
     window: Duration,
     max_requests: u32,
-    state: Mutex<HashMap<IpAddr, Bucket>>,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    /// Shared by every clone, so they can be replaced at runtime.
+    overrides: Arc<RwLock<Vec<(Cidr, Override)>>>,
+    max_clients: usize,
+}
+
+/// The quota that applies to one client.
+#[derive(Clone, Copy)]
+struct Quota {
+    max_requests: u32,
+    burst: u32,
 }
 
-struct Bucket {
-    window_start: Instant,
-    count: u32,
+enum Bucket {
+    Window { start: Instant, count: u32 },
+    Tokens { refilled: Instant, tokens: f64 },
+}
+
+/// One part of the client table, with least-recently-used eviction.
+#[derive(Default)]
+struct Shard {
+    clients: HashMap<IpAddr, Entry>,
+    /// Client by the tick it was last seen at, oldest first.
+    recency: BTreeMap<u64, IpAddr>,
+    tick: u64,
+}
+
+struct Entry {
+    bucket: Bucket,
+    seen: u64,
+}
+
+/// How much of its quota a tracked client has used, for inspection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Usage {
+    /// The address, or the network address when prefixes are aggregated.
+    pub client: IpAddr,
+    pub prefix_len: u8,
+    /// Requests counted in the current window, or tokens spent.
+    pub used: u32,
+    /// Requests allowed per window, or the burst.
+    pub limit: u32,
 }
 
 impl RateLimiter {
-    /// Build a new limiter. Pass `0` for either argument to disable rate
-    /// limiting entirely (every request is allowed).
+    /// Build a new fixed-window limiter that tracks each source address on
+    /// its own. Pass `0` for either argument to disable rate limiting
+    /// entirely (every request is allowed).
     pub fn new(window_seconds: u64, max_requests: u32) -> Self {
+        Self::from_settings(Settings {
+            algorithm: Algorithm::FixedWindow,
+            window: Duration::from_secs(window_seconds),
+            max_requests,
+            ipv4_prefix: 32,
+            ipv6_prefix: 128,
+            overrides: Arc::default(),
+            max_clients: 100_000,
+        })
+    }
+
+    /// Switch to another counting algorithm, keeping the same limit. Meant
+    /// to be chained onto [`RateLimiter::new`] before the limiter is cloned.
+    pub fn with_algorithm(self, algorithm: Algorithm) -> Self {
+        let mut settings = self.inner.settings.clone();
+        settings.algorithm = match algorithm {
+            Algorithm::TokenBucket { burst: 0 } => Algorithm::TokenBucket {
+                burst: settings.max_requests,
+            },
+            other => other,
+        };
+        Self::from_settings(settings)
+    }
+
+    /// Share one quota between all addresses in the same IPv4 `/ipv4_prefix`
+    /// or IPv6 `/ipv6_prefix` network, so rotating through a block of
+    /// addresses doesn't earn a fresh quota per address.
+    pub fn with_prefixes(self, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        let mut settings = self.inner.settings.clone();
+        settings.ipv4_prefix = ipv4_prefix.min(32);
+        settings.ipv6_prefix = ipv6_prefix.min(128);
+        Self::from_settings(settings)
+    }
+
+    /// Exempt networks from rate limiting or give them their own limit. When
+    /// several networks contain a client, the most specific one wins.
+    pub fn with_overrides(self, overrides: Vec<(Cidr, Override)>) -> Self {
+        let mut settings = self.inner.settings.clone();
+        settings.overrides = Arc::default();
+        let limiter = Self::from_settings(settings);
+        limiter.set_overrides(overrides);
+        limiter
+    }
+
+    /// Replace the overrides of this limiter and all its clones, e.g. after
+    /// the configuration is reloaded. Clients already tracked keep their
+    /// counts and get the new quota from their next request.
+    pub fn set_overrides(&self, mut overrides: Vec<(Cidr, Override)>) {
+        overrides.sort_by_key(|(network, _)| std::cmp::Reverse(network.prefix_len()));
+        let mut current = match self.inner.settings.overrides.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *current = overrides;
+    }
+
+    /// The overrides in effect, most specific first.
+    pub fn overrides(&self) -> Vec<(Cidr, Override)> {
+        self.inner.settings.overrides().clone()
+    }
+
+    /// Cap the number of clients tracked at once (rounded up to a multiple
+    /// of the shard count).
+    pub fn with_max_clients(self, max_clients: usize) -> Self {
+        let mut settings = self.inner.settings.clone();
+        settings.max_clients = max_clients;
+        Self::from_settings(settings)
+    }
+
+    fn from_settings(settings: Settings) -> Self {
         Self {
             inner: Arc::new(Inner {
-                window: Duration::from_secs(window_seconds),
-                max_requests,
-                state: Mutex::new(HashMap::new()),
+                settings,
+                hasher: RandomState::new(),
+                shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+                evictions: AtomicU64::new(0),
             }),
         }
     }
 
     pub fn is_enabled(&self) -> bool {
-        self.inner.max_requests > 0 && !self.inner.window.is_zero()
+        let settings = &self.inner.settings;
+        settings.max_requests > 0 && !settings.window.is_zero()
+    }
+
+    /// Number of clients (addresses or networks) currently held in the table.
+    pub fn tracked_clients(&self) -> usize {
+        self.inner
+            .shards
+            .iter()
+            .map(|shard| lock(shard).clients.len())
+            .sum()
+    }
+
+    /// Clients forgotten to make room for new ones while still active.
+    pub fn evictions(&self) -> u64 {
+        self.inner.evictions.load(Ordering::Relaxed)
+    }
+
+    /// The `count` clients closest to their quota, busiest first. Clients
+    /// that have gone idle but weren't pruned yet are left out.
+    pub fn top_clients(&self, count: usize) -> Vec<Usage> {
+        let settings = &self.inner.settings;
+        let now = Instant::now();
+        let mut usage: Vec<Usage> = Vec::new();
+        for shard in &self.inner.shards {
+            let shard = lock(shard);
+            for (&client, entry) in &shard.clients {
+                let Some(quota) = settings.quota(client) else {
+                    continue;
+                };
+                if settings.is_idle(client, &entry.bucket, now) {
+                    continue;
+                }
+                let (used, limit) = match entry.bucket {
+                    Bucket::Window { count, .. } => (count, quota.max_requests),
+                    Bucket::Tokens { refilled, tokens } => {
+                        let earned =
+                            now.duration_since(refilled).as_secs_f64() * settings.rate(quota);
+                        let tokens = (tokens + earned).min(f64::from(quota.burst));
+                        ((f64::from(quota.burst) - tokens) as u32, quota.burst)
+                    }
+                };
+                let prefix_len = match client {
+                    IpAddr::V4(_) => settings.ipv4_prefix,
+                    IpAddr::V6(_) => settings.ipv6_prefix,
+                };
+                usage.push(Usage {
+                    client,
+                    prefix_len,
+                    used,
+                    limit,
+                });
+            }
+        }
+        usage.sort_by(|a, b| {
+            let share = |usage: &Usage| f64::from(usage.used) / f64::from(usage.limit.max(1));
+            share(b).total_cmp(&share(a)).then(b.used.cmp(&a.used))
+        });
+        usage.truncate(count);
+        usage
     }
 
     /// Returns `true` if the request from `ip` is allowed, `false` if it
-    /// should be dropped because the client exceeded its quota for the
-    /// current window.
+    /// should be dropped because the client exceeded its quota.
     pub fn check(&self, ip: IpAddr) -> bool {
         if !self.is_enabled() {
             return true;
         }
+        let settings = &self.inner.settings;
+        // The quota belongs to the bucket, so it is looked up by the bucket's
+        // key, as when pruning and reporting.
+        let client = client_prefix(ip, settings.ipv4_prefix, settings.ipv6_prefix);
+        let Some(quota) = settings.quota(client) else {
+            return true;
+        };
 
         let now = Instant::now();
-        let mut state = match self.inner.state.lock() {
-            Ok(guard) => guard,
-            // A poisoned mutex would only happen if a previous holder panicked.
-            // Fail open so a logic bug doesn't take the whole resolver down.
-            Err(poisoned) => poisoned.into_inner(),
-        };
+        let shard = self.inner.hasher.hash_one(client) as usize % SHARDS;
+        let mut shard = lock(&self.inner.shards[shard]);
+
+        // Clients that have gone quiet are dropped from the old end as we
+        // go, so the table only fills up under real pressure.
+        shard.prune(settings, now);
+        let capacity = settings.max_clients.div_ceil(SHARDS).max(1);
+        if shard.clients.len() >= capacity && !shard.clients.contains_key(&client) {
+            shard.evict_oldest();
+            self.inner.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match settings.algorithm {
+            Algorithm::FixedWindow => {
+                let bucket = shard.touch(client, || Bucket::Window {
+                    start: now,
+                    count: 0,
+                });
+                let Bucket::Window { start, count } = bucket else {
+                    unreachable!("fixed-window limiter only stores window buckets");
+                };
+
+                if now.duration_since(*start) >= settings.window {
+                    *start = now;
+                    *count = 1;
+                    return true;
+                }
 
-        // Opportunistic pruning so the map can't grow unbounded over time.
-        if state.len() > 1024 {
-            let window = self.inner.window;
-            state.retain(|_, bucket| now.duration_since(bucket.window_start) < window);
+                if *count >= quota.max_requests {
+                    return false;
+                }
+
+                *count += 1;
+                true
+            }
+            Algorithm::TokenBucket { .. } => {
+                let bucket = shard.touch(client, || Bucket::Tokens {
+                    refilled: now,
+                    tokens: f64::from(quota.burst),
+                });
+                let Bucket::Tokens { refilled, tokens } = bucket else {
+                    unreachable!("token-bucket limiter only stores token buckets");
+                };
+
+                let earned = now.duration_since(*refilled).as_secs_f64() * settings.rate(quota);
+                *tokens = (*tokens + earned).min(f64::from(quota.burst));
+                *refilled = now;
+
+                if *tokens < 1.0 {
+                    return false;
+                }
+
+                *tokens -= 1.0;
+                true
+            }
         }
+    }
+}
 
-        let bucket = state.entry(ip).or_insert(Bucket {
-            window_start: now,
-            count: 0,
+fn lock(shard: &Mutex<Shard>) -> MutexGuard<'_, Shard> {
+    match shard.lock() {
+        Ok(guard) => guard,
+        // A poisoned mutex would only happen if a previous holder panicked.
+        // Fail open so a logic bug doesn't take the whole resolver down.
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl Shard {
+    /// The bucket for `client`, created with `fresh` if it isn't tracked
+    /// yet, marked as the most recently seen.
+    fn touch(&mut self, client: IpAddr, fresh: impl FnOnce() -> Bucket) -> &mut Bucket {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.clients.entry(client).or_insert_with(|| Entry {
+            bucket: fresh(),
+            seen: tick,
         });
+        self.recency.remove(&entry.seen);
+        self.recency.insert(tick, client);
+        entry.seen = tick;
+        &mut entry.bucket
+    }
 
-        if now.duration_since(bucket.window_start) >= self.inner.window {
-            bucket.window_start = now;
-            bucket.count = 1;
-            return true;
+    fn evict_oldest(&mut self) {
+        if let Some((_, client)) = self.recency.pop_first() {
+            self.clients.remove(&client);
         }
+    }
 
-        if bucket.count >= self.inner.max_requests {
-            return false;
+    /// Forget the least recently seen clients while they are idle. Bounded
+    /// per call to keep the time spent under the lock predictable.
+    fn prune(&mut self, settings: &Settings, now: Instant) {
+        for _ in 0..8 {
+            let Some((_, client)) = self.recency.first_key_value() else {
+                return;
+            };
+            let client = *client;
+            if !settings.is_idle(client, &self.clients[&client].bucket, now) {
+                return;
+            }
+            self.evict_oldest();
         }
+    }
+}
 
-        bucket.count += 1;
-        true
+impl Settings {
+    fn overrides(&self) -> std::sync::RwLockReadGuard<'_, Vec<(Cidr, Override)>> {
+        match self.overrides.read() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// The quota for `ip`, or `None` if it is exempt.
+    fn quota(&self, ip: IpAddr) -> Option<Quota> {
+        let default_burst = match self.algorithm {
+            Algorithm::TokenBucket { burst } => burst,
+            Algorithm::FixedWindow => self.max_requests,
+        };
+        match self
+            .overrides()
+            .iter()
+            .find(|(network, _)| network.contains(ip))
+        {
+            Some((_, Override::Exempt)) => None,
+            Some((_, Override::Limit(limit))) => Some(Quota {
+                max_requests: *limit,
+                burst: *limit,
+            }),
+            None => Some(Quota {
+                max_requests: self.max_requests,
+                burst: default_burst,
+            }),
+        }
+    }
+
+    /// Tokens earned per second by the token bucket.
+    fn rate(&self, quota: Quota) -> f64 {
+        f64::from(quota.max_requests) / self.window.as_secs_f64()
+    }
+
+    /// A bucket is idle once forgetting it changes nothing: its window has
+    /// ended, or it has refilled to the full burst.
+    fn is_idle(&self, client: IpAddr, bucket: &Bucket, now: Instant) -> bool {
+        match bucket {
+            Bucket::Window { start, .. } => now.duration_since(*start) >= self.window,
+            Bucket::Tokens { refilled, tokens } => {
+                let Some(quota) = self.quota(client) else {
+                    return true;
+                };
+                let missing = f64::from(quota.burst) - tokens;
+                now.duration_since(*refilled).as_secs_f64() * self.rate(quota) >= missing
+            }
+        }
     }
 }
 
@@ -121,6 +522,16 @@ mod tests {
         assert!(!rl.check(ip(2)));
     }
 
+    #[test]
+    fn tracked_clients_counts_distinct_ips() {
+        let rl = RateLimiter::new(60, 5);
+        assert_eq!(rl.tracked_clients(), 0);
+        rl.check(ip(1));
+        rl.check(ip(1));
+        rl.check(ip(2));
+        assert_eq!(rl.tracked_clients(), 2);
+    }
+
     #[test]
     fn window_resets_after_elapsing() {
         let rl = RateLimiter::new(1, 2);
@@ -130,4 +541,277 @@ mod tests {
         sleep(Duration::from_millis(1100));
         assert!(rl.check(ip(1)));
     }
+
+    #[test]
+    fn token_bucket_allows_burst_then_refills() {
+        // 10 tokens per second, saving up at most 3.
+        let rl = RateLimiter::new(1, 10).with_algorithm(Algorithm::TokenBucket { burst: 3 });
+        assert!(rl.check(ip(1)));
+        assert!(rl.check(ip(1)));
+        assert!(rl.check(ip(1)));
+        assert!(!rl.check(ip(1)));
+        sleep(Duration::from_millis(150));
+        assert!(rl.check(ip(1)));
+        assert!(!rl.check(ip(1)));
+    }
+
+    #[test]
+    fn token_bucket_has_no_window_boundary() {
+        // A fixed window would allow another 2 right after the reset; the
+        // token bucket only hands out what has refilled since.
+        let rl = RateLimiter::new(1, 2).with_algorithm(Algorithm::TokenBucket { burst: 2 });
+        assert!(rl.check(ip(1)));
+        assert!(rl.check(ip(1)));
+        sleep(Duration::from_millis(600));
+        assert!(rl.check(ip(1)));
+        assert!(!rl.check(ip(1)));
+    }
+
+    #[test]
+    fn token_bucket_burst_defaults_to_limit() {
+        let rl = RateLimiter::new(60, 2).with_algorithm(Algorithm::TokenBucket { burst: 0 });
+        assert!(rl.check(ip(1)));
+        assert!(rl.check(ip(1)));
+        assert!(!rl.check(ip(1)));
+        assert!(rl.check(ip(2)));
+    }
+
+    #[test]
+    fn algorithm_names_parse() {
+        assert_eq!(
+            Algorithm::parse("fixed-window", 5),
+            Some(Algorithm::FixedWindow)
+        );
+        assert_eq!(
+            Algorithm::parse("token-bucket", 5),
+            Some(Algorithm::TokenBucket { burst: 5 })
+        );
+        assert_eq!(Algorithm::parse("leaky", 5), None);
+    }
+
+    fn v6(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv6_rotation_inside_prefix_shares_quota() {
+        let rl = RateLimiter::new(60, 2).with_prefixes(24, 56);
+        assert!(rl.check(v6("2001:db8:1:100::1")));
+        assert!(rl.check(v6("2001:db8:1:1ff:dead:beef::2")));
+        // A fresh address in the same /64 and in another /64 of the same /56.
+        assert!(!rl.check(v6("2001:db8:1:100::3")));
+        assert!(!rl.check(v6("2001:db8:1:1a0::1")));
+        // Another /56 has its own quota.
+        assert!(rl.check(v6("2001:db8:1:200::1")));
+        assert_eq!(rl.tracked_clients(), 2);
+    }
+
+    #[test]
+    fn ipv6_rotation_without_prefix_gets_fresh_quota() {
+        let rl = RateLimiter::new(60, 1);
+        for n in 1..=10 {
+            assert!(rl.check(v6(&format!("2001:db8:1:100::{n}"))));
+        }
+        assert_eq!(rl.tracked_clients(), 10);
+    }
+
+    #[test]
+    fn ipv4_prefix_groups_addresses() {
+        let rl = RateLimiter::new(60, 1).with_prefixes(24, 56);
+        assert!(rl.check(ip(1)));
+        assert!(!rl.check(ip(2)));
+        assert!(rl.check(IpAddr::V4(Ipv4Addr::new(127, 0, 1, 1))));
+        // IPv4-mapped addresses from the dual-stack socket land in the same bucket.
+        assert!(!rl.check(v6("::ffff:127.0.0.9")));
+    }
+
+    #[test]
+    fn prefix_applies_to_token_bucket() {
+        let rl = RateLimiter::new(60, 1)
+            .with_algorithm(Algorithm::TokenBucket { burst: 1 })
+            .with_prefixes(32, 64);
+        assert!(rl.check(v6("2001:db8::1")));
+        assert!(!rl.check(v6("2001:db8::ffff")));
+    }
+
+    #[test]
+    fn exempt_networks_are_never_limited() {
+        let rl = RateLimiter::new(60, 1)
+            .with_overrides(vec![("10.0.0.0/8".parse().unwrap(), Override::Exempt)]);
+        for _ in 0..100 {
+            assert!(rl.check(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))));
+        }
+        assert_eq!(rl.tracked_clients(), 0);
+        assert!(rl.check(ip(1)));
+        assert!(!rl.check(ip(1)));
+    }
+
+    #[test]
+    fn networks_can_have_their_own_limit() {
+        let rl = RateLimiter::new(60, 1)
+            .with_algorithm(Algorithm::TokenBucket { burst: 1 })
+            .with_overrides(vec![("2001:db8::/32".parse().unwrap(), Override::Limit(3))]);
+        for _ in 0..3 {
+            assert!(rl.check(v6("2001:db8::53")));
+        }
+        assert!(!rl.check(v6("2001:db8::53")));
+        assert!(rl.check(v6("2001:db9::53")));
+        assert!(!rl.check(v6("2001:db9::53")));
+    }
+
+    #[test]
+    fn most_specific_override_wins() {
+        let rl = RateLimiter::new(60, 1).with_overrides(vec![
+            ("10.0.0.0/8".parse().unwrap(), Override::Exempt),
+            ("10.9.0.0/16".parse().unwrap(), Override::Limit(2)),
+        ]);
+        let ci = IpAddr::V4(Ipv4Addr::new(10, 9, 0, 1));
+        assert!(rl.check(ci));
+        assert!(rl.check(ci));
+        assert!(!rl.check(ci));
+        for _ in 0..10 {
+            assert!(rl.check(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1))));
+        }
+    }
+
+    #[test]
+    fn overrides_parse() {
+        let overrides = parse_overrides("10.0.0.0/8=exempt, 192.0.2.0/24=500").unwrap();
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides[0].1, Override::Exempt);
+        assert_eq!(overrides[1].1, Override::Limit(500));
+        assert!(parse_overrides("10.0.0.0/8").is_err());
+        assert!(parse_overrides("10.0.0.0/8=lots").is_err());
+        assert!(parse_overrides("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn overrides_apply_to_whole_prefixes() {
+        assert!(check_overrides(
+            &parse_overrides("10.0.0.0/8=exempt, 2001:db8::/48=5").unwrap(),
+            24,
+            56
+        )
+        .is_ok());
+        let narrow = parse_overrides("192.0.2.7/32=exempt").unwrap();
+        assert!(check_overrides(&narrow, 24, 56).is_err());
+        assert!(check_overrides(&narrow, 32, 56).is_ok());
+        let narrow_v6 = parse_overrides("2001:db8:0:1::/64=5").unwrap();
+        assert!(check_overrides(&narrow_v6, 32, 56).is_err());
+
+        // Every address in an aggregated prefix gets the same quota.
+        let rl = RateLimiter::new(60, 1)
+            .with_prefixes(24, 56)
+            .with_overrides(parse_overrides("192.0.2.0/24=3").unwrap());
+        let host = |n| IpAddr::V4(Ipv4Addr::new(192, 0, 2, n));
+        assert!(rl.check(host(1)));
+        assert!(rl.check(host(2)));
+        assert!(rl.check(host(3)));
+        assert!(!rl.check(host(4)));
+        let top = rl.top_clients(1);
+        assert_eq!((top[0].used, top[0].limit), (3, 3));
+    }
+
+    fn nth(n: u32) -> IpAddr {
+        IpAddr::V6(std::net::Ipv6Addr::from(
+            0x2001_0db8_u128 << 96 | u128::from(n),
+        ))
+    }
+
+    #[test]
+    fn table_is_bounded_by_max_clients() {
+        let rl = RateLimiter::new(60, 1).with_max_clients(64);
+        for n in 0..10_000 {
+            assert!(rl.check(nth(n)));
+        }
+        assert!(rl.tracked_clients() <= 64);
+        assert_eq!(rl.evictions() as usize, 10_000 - rl.tracked_clients());
+    }
+
+    #[test]
+    fn busy_client_survives_a_flood_of_new_sources() {
+        let rl = RateLimiter::new(60, 1).with_max_clients(SHARDS * 4);
+        assert!(rl.check(ip(1)));
+        for n in 0..10_000 {
+            rl.check(nth(n));
+            // Staying active keeps the client at the recent end of the LRU.
+            assert!(!rl.check(ip(1)));
+        }
+    }
+
+    #[test]
+    fn idle_clients_are_pruned_before_evicting() {
+        // One token per millisecond: a client is idle again 1ms after its
+        // only request.
+        let rl = RateLimiter::new(1, 1000)
+            .with_algorithm(Algorithm::TokenBucket { burst: 1 })
+            .with_max_clients(SHARDS);
+        for n in 0..200 {
+            rl.check(nth(n));
+            sleep(Duration::from_millis(2));
+        }
+        assert_eq!(rl.evictions(), 0);
+
+        for n in 200..400 {
+            rl.check(nth(n));
+        }
+        assert!(rl.evictions() > 0);
+    }
+
+    /// Throughput with many distinct sources across threads. Run with
+    /// `cargo test --release -- --ignored --nocapture many_sources`.
+    #[test]
+    #[ignore]
+    fn bench_many_sources() {
+        let rl = RateLimiter::new(60, 100)
+            .with_algorithm(Algorithm::TokenBucket { burst: 0 })
+            .with_max_clients(100_000);
+        let threads = 8;
+        let per_thread = 1_000_000u32;
+        let started = Instant::now();
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let rl = rl.clone();
+                std::thread::spawn(move || {
+                    for n in 0..per_thread {
+                        rl.check(nth(t * per_thread + n));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let elapsed = started.elapsed();
+        let total = f64::from(threads * per_thread);
+        println!(
+            "{total} checks from distinct sources in {elapsed:?} ({:.0}/s), {} tracked, {} evicted",
+            total / elapsed.as_secs_f64(),
+            rl.tracked_clients(),
+            rl.evictions()
+        );
+        assert!(rl.tracked_clients() <= 100_000);
+    }
+
+    #[test]
+    fn top_clients_are_the_busiest() {
+        let rl = RateLimiter::new(60, 10).with_prefixes(24, 64);
+        for _ in 0..5 {
+            rl.check(ip(1));
+        }
+        rl.check(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        rl.check(ip(2));
+
+        let top = rl.top_clients(1);
+        assert_eq!(
+            top,
+            vec![Usage {
+                client: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)),
+                prefix_len: 24,
+                used: 6,
+                limit: 10,
+            }]
+        );
+        assert_eq!(rl.top_clients(10).len(), 2);
+    }
 }
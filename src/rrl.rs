@@ -0,0 +1,415 @@
+use crate::cidr::client_prefix;
+use crate::dns::query::Resolution;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// How RRL groups responses. Each kind has its own per-second limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ResponseKind {
+    /// NOERROR with at least one answer.
+    Answer,
+    /// NXDOMAIN, or NOERROR with an empty answer section (NODATA).
+    NxDomain,
+    Refused,
+    /// FORMERR, SERVFAIL, NOTIMP and anything else.
+    Error,
+}
+
+impl ResponseKind {
+    pub fn of(response: &[u8]) -> Self {
+        if response.len() < 12 {
+            return ResponseKind::Error;
+        }
+        let ancount = u16::from_be_bytes([response[6], response[7]]);
+        match response[3] & 0x0F {
+            0 if ancount > 0 => ResponseKind::Answer,
+            0 | 3 => ResponseKind::NxDomain,
+            5 => ResponseKind::Refused,
+            _ => ResponseKind::Error,
+        }
+    }
+}
+
+/// What to do with a UDP response after RRL has looked at it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Send,
+    /// Send a truncated (TC=1) response so a real client retries over TCP.
+    Slip,
+    Drop,
+}
+
+/// Per-second limits for each [`ResponseKind`]. `0` means unlimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub answers: u32,
+    pub nxdomains: u32,
+    pub refused: u32,
+    pub errors: u32,
+}
+
+impl Limits {
+    fn for_kind(&self, kind: ResponseKind) -> u32 {
+        match kind {
+            ResponseKind::Answer => self.answers,
+            ResponseKind::NxDomain => self.nxdomains,
+            ResponseKind::Refused => self.refused,
+            ResponseKind::Error => self.errors,
+        }
+    }
+}
+
+/// Response Rate Limiting in the style of BIND's `rate-limit` block.
+///
+/// Responses are accounted per (client prefix, response kind, name), so a
+/// flood of identical answers towards one victim network is cut down while
+/// other names and other clients are unaffected. Limited responses are
+/// dropped, except every `slip`-th one, which is sent truncated: a spoofed
+/// victim gets a tiny packet, a real client behind the prefix retries over
+/// TCP, which RRL never limits.
+///
+/// Like [`crate::rate_limit::RateLimiter`], clones share the same state.
+#[derive(Clone)]
+pub struct ResponseRateLimiter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    limits: Limits,
+    slip: u32,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    state: Mutex<State>,
+}
+
+/// Most accounts tracked at once. Accounts refill fully within a second, so
+/// idle ones are dropped first; past that, the least recently used account
+/// is evicted and starts over with a full quota.
+const MAX_ACCOUNTS: usize = 4096;
+
+/// The accounts, with least-recently-used eviction as in the per-source
+/// limiter.
+#[derive(Default)]
+struct State {
+    accounts: HashMap<Key, Account>,
+    /// Account by the tick it was last used at, oldest first.
+    recency: BTreeMap<u64, Key>,
+    tick: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    prefix: IpAddr,
+    kind: ResponseKind,
+    name: String,
+}
+
+struct Account {
+    refilled: Instant,
+    tokens: f64,
+    limited: u32,
+    seen: u64,
+}
+
+impl State {
+    /// The account for `key`, created with `tokens` if it isn't tracked
+    /// yet, marked as the most recently used.
+    fn touch(&mut self, key: Key, now: Instant, tokens: f64) -> &mut Account {
+        self.tick += 1;
+        let tick = self.tick;
+        self.recency.insert(tick, key.clone());
+        let account = self.accounts.entry(key).or_insert(Account {
+            refilled: now,
+            tokens,
+            limited: 0,
+            seen: tick,
+        });
+        if account.seen != tick {
+            self.recency.remove(&account.seen);
+            account.seen = tick;
+        }
+        account
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((_, key)) = self.recency.pop_first() {
+            self.accounts.remove(&key);
+        }
+    }
+
+    /// Forget the least recently used accounts while they have been idle
+    /// for a second. Bounded per call to keep the time spent under the lock
+    /// predictable.
+    fn prune(&mut self, now: Instant) {
+        for _ in 0..8 {
+            let Some((_, key)) = self.recency.first_key_value() else {
+                return;
+            };
+            if now.duration_since(self.accounts[key].refilled).as_secs() < 1 {
+                return;
+            }
+            self.evict_oldest();
+        }
+    }
+}
+
+impl ResponseRateLimiter {
+    /// `slip` of `0` drops every limited response, `1` truncates every one,
+    /// `n` truncates one in `n`.
+    pub fn new(limits: Limits, slip: u32, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                limits,
+                slip,
+                ipv4_prefix,
+                ipv6_prefix,
+                state: Mutex::default(),
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        let limits = self.inner.limits;
+        limits.answers > 0 || limits.nxdomains > 0 || limits.refused > 0 || limits.errors > 0
+    }
+
+    /// Decide whether `resolution` may be sent to `ip`. `zone` is the apex the
+    /// server is authoritative for: negative and error responses are counted
+    /// against it rather than the query name, so random subdomains can't be
+    /// used to get a fresh quota each time.
+    pub fn check(&self, ip: IpAddr, resolution: &Resolution, zone: &str) -> Action {
+        if !self.is_enabled() {
+            return Action::Send;
+        }
+        let Some(question) = &resolution.question else {
+            return Action::Send;
+        };
+
+        let kind = ResponseKind::of(&resolution.response);
+        let limit = self.inner.limits.for_kind(kind);
+        if limit == 0 {
+            return Action::Send;
+        }
+
+        let name = match kind {
+            ResponseKind::Answer => question.name.to_ascii_lowercase(),
+            ResponseKind::NxDomain => zone.to_ascii_lowercase(),
+            ResponseKind::Refused | ResponseKind::Error => String::new(),
+        };
+        let key = Key {
+            prefix: client_prefix(ip, self.inner.ipv4_prefix, self.inner.ipv6_prefix),
+            kind,
+            name,
+        };
+
+        let now = Instant::now();
+        let mut state = match self.inner.state.lock() {
+            Ok(guard) => guard,
+            // Fail open, as the per-source limiter does.
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        state.prune(now);
+        if state.accounts.len() >= MAX_ACCOUNTS && !state.accounts.contains_key(&key) {
+            state.evict_oldest();
+        }
+
+        let account = state.touch(key, now, f64::from(limit));
+        let earned = now.duration_since(account.refilled).as_secs_f64() * f64::from(limit);
+        account.tokens = (account.tokens + earned).min(f64::from(limit));
+        account.refilled = now;
+
+        if account.tokens >= 1.0 {
+            account.tokens -= 1.0;
+            return Action::Send;
+        }
+
+        account.limited = account.limited.wrapping_add(1);
+        if self.inner.slip > 0 && account.limited % self.inner.slip == 0 {
+            Action::Slip
+        } else {
+            Action::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::query::{Outcome, Question};
+    use std::net::Ipv4Addr;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    const ZONE: &str = "ns.example.com";
+
+    fn limits(per_second: u32) -> Limits {
+        Limits {
+            answers: per_second,
+            nxdomains: per_second,
+            refused: per_second,
+            errors: per_second,
+        }
+    }
+
+    fn resolution(name: &str, rcode: u8, ancount: u8) -> Resolution {
+        Resolution {
+            response: vec![0, 0, 0x84, rcode, 0, 1, 0, ancount, 0, 0, 0, 0],
+            question: Some(Question {
+                name: name.to_string(),
+                qtype: 1,
+                qclass: 1,
+            }),
+            outcome: Outcome::Glue,
+            answers: Vec::new(),
+        }
+    }
+
+    fn ip(a: u8, d: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, a, d))
+    }
+
+    #[test]
+    fn responses_are_classified() {
+        assert_eq!(
+            ResponseKind::of(&resolution("a", 0, 1).response),
+            ResponseKind::Answer
+        );
+        assert_eq!(
+            ResponseKind::of(&resolution("a", 0, 0).response),
+            ResponseKind::NxDomain
+        );
+        assert_eq!(
+            ResponseKind::of(&resolution("a", 3, 0).response),
+            ResponseKind::NxDomain
+        );
+        assert_eq!(
+            ResponseKind::of(&resolution("a", 5, 0).response),
+            ResponseKind::Refused
+        );
+        assert_eq!(
+            ResponseKind::of(&resolution("a", 2, 0).response),
+            ResponseKind::Error
+        );
+    }
+
+    #[test]
+    fn disabled_when_all_limits_are_zero() {
+        let rrl = ResponseRateLimiter::new(limits(0), 2, 24, 56);
+        for _ in 0..100 {
+            assert_eq!(
+                rrl.check(ip(2, 1), &resolution("x", 0, 1), ZONE),
+                Action::Send
+            );
+        }
+    }
+
+    #[test]
+    fn limited_responses_alternate_drop_and_slip() {
+        let rrl = ResponseRateLimiter::new(limits(2), 2, 24, 56);
+        let answer = resolution("10-0-0-1.ns.example.com", 0, 1);
+        assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Send);
+        assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Send);
+        assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Drop);
+        assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Slip);
+        assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Drop);
+    }
+
+    #[test]
+    fn slip_zero_always_drops() {
+        let rrl = ResponseRateLimiter::new(limits(1), 0, 24, 56);
+        let answer = resolution("a.ns.example.com", 0, 1);
+        assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Send);
+        for _ in 0..10 {
+            assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Drop);
+        }
+    }
+
+    #[test]
+    fn clients_in_one_prefix_share_a_quota() {
+        let rrl = ResponseRateLimiter::new(limits(1), 0, 24, 56);
+        let answer = resolution("a.ns.example.com", 0, 1);
+        assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Send);
+        assert_eq!(rrl.check(ip(2, 200), &answer, ZONE), Action::Drop);
+        assert_eq!(rrl.check(ip(3, 1), &answer, ZONE), Action::Send);
+    }
+
+    #[test]
+    fn answers_are_keyed_by_name_but_nodata_by_zone() {
+        let rrl = ResponseRateLimiter::new(limits(1), 0, 24, 56);
+        assert_eq!(
+            rrl.check(ip(2, 1), &resolution("a.ns.example.com", 0, 1), ZONE),
+            Action::Send
+        );
+        assert_eq!(
+            rrl.check(ip(2, 1), &resolution("b.ns.example.com", 0, 1), ZONE),
+            Action::Send
+        );
+
+        assert_eq!(
+            rrl.check(ip(2, 1), &resolution("x1.ns.example.com", 0, 0), ZONE),
+            Action::Send
+        );
+        assert_eq!(
+            rrl.check(ip(2, 1), &resolution("x2.ns.example.com", 0, 0), ZONE),
+            Action::Drop
+        );
+    }
+
+    #[test]
+    fn kinds_have_separate_limits() {
+        let rrl = ResponseRateLimiter::new(
+            Limits {
+                answers: 0,
+                nxdomains: 0,
+                refused: 1,
+                errors: 0,
+            },
+            0,
+            24,
+            56,
+        );
+        let refused = resolution("google.com", 5, 0);
+        let answer = resolution("a.ns.example.com", 0, 1);
+        assert_eq!(rrl.check(ip(2, 1), &refused, ZONE), Action::Send);
+        assert_eq!(rrl.check(ip(2, 1), &refused, ZONE), Action::Drop);
+        for _ in 0..10 {
+            assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Send);
+        }
+    }
+
+    #[test]
+    fn accounts_are_bounded_under_a_flood_of_names() {
+        let rrl = ResponseRateLimiter::new(limits(1), 0, 24, 56);
+        let busy = resolution("busy.ns.example.com", 0, 1);
+        assert_eq!(rrl.check(ip(2, 1), &busy, ZONE), Action::Send);
+        for i in 0..10_000 {
+            let name = format!("{i}.ns.example.com");
+            assert_eq!(
+                rrl.check(ip(2, 1), &resolution(&name, 0, 1), ZONE),
+                Action::Send
+            );
+            if i % 1000 == 0 {
+                // Still tracked, so still limited.
+                assert_eq!(rrl.check(ip(2, 1), &busy, ZONE), Action::Drop);
+            }
+        }
+        let state = rrl.inner.state.lock().unwrap();
+        assert_eq!(state.accounts.len(), MAX_ACCOUNTS);
+        assert_eq!(state.recency.len(), MAX_ACCOUNTS);
+    }
+
+    #[test]
+    fn quota_refills_over_time() {
+        let rrl = ResponseRateLimiter::new(limits(10), 0, 24, 56);
+        let answer = resolution("a.ns.example.com", 0, 1);
+        for _ in 0..10 {
+            assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Send);
+        }
+        assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Drop);
+        sleep(Duration::from_millis(150));
+        assert_eq!(rrl.check(ip(2, 1), &answer, ZONE), Action::Send);
+    }
+}
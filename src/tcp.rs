@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Why a TCP connection was turned away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    /// The client already holds its maximum number of connections.
+    PerClient,
+    /// The server already holds its maximum number of connections.
+    Total,
+}
+
+impl Refusal {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Refusal::PerClient => "per_client",
+            Refusal::Total => "total",
+        }
+    }
+}
+
+/// Caps on concurrently open TCP connections, per source address and for
+/// the whole listener. A limit of `0` means unlimited.
+///
+/// Like the rate limiters, clones share the same counts.
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    per_client: usize,
+    total: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    open: HashMap<IpAddr, usize>,
+    total: usize,
+}
+
+/// An open connection's place in the limiter, given back when dropped.
+pub struct Connection {
+    limiter: ConnectionLimiter,
+    ip: IpAddr,
+}
+
+impl ConnectionLimiter {
+    pub fn new(per_client: usize, total: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                per_client,
+                total,
+                state: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Reserve a connection for `ip`, or say which cap it would exceed. The
+    /// reservation lasts until the returned [`Connection`] is dropped.
+    pub fn acquire(&self, ip: IpAddr) -> Result<Connection, Refusal> {
+        let ip = ip.to_canonical();
+        let mut state = self.lock();
+        if self.inner.total > 0 && state.total >= self.inner.total {
+            return Err(Refusal::Total);
+        }
+        let open = state.open.entry(ip).or_insert(0);
+        if self.inner.per_client > 0 && *open >= self.inner.per_client {
+            return Err(Refusal::PerClient);
+        }
+        *open += 1;
+        state.total += 1;
+        Ok(Connection {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    /// Connections currently open.
+    pub fn open_connections(&self) -> usize {
+        self.lock().total
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.inner.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut state = self.limiter.lock();
+        state.total -= 1;
+        if let Some(open) = state.open.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                state.open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn per_client_cap() {
+        let limiter = ConnectionLimiter::new(2, 0);
+        let _a = limiter.acquire(ip("192.0.2.1")).unwrap();
+        let b = limiter.acquire(ip("::ffff:192.0.2.1")).unwrap();
+        assert_eq!(
+            limiter.acquire(ip("192.0.2.1")).err(),
+            Some(Refusal::PerClient)
+        );
+        assert!(limiter.acquire(ip("192.0.2.2")).is_ok());
+
+        drop(b);
+        assert!(limiter.acquire(ip("192.0.2.1")).is_ok());
+    }
+
+    #[test]
+    fn total_cap() {
+        let limiter = ConnectionLimiter::new(0, 2);
+        let a = limiter.acquire(ip("192.0.2.1")).unwrap();
+        let _b = limiter.acquire(ip("2001:db8::1")).unwrap();
+        assert_eq!(limiter.open_connections(), 2);
+        assert_eq!(limiter.acquire(ip("192.0.2.3")).err(), Some(Refusal::Total));
+
+        drop(a);
+        assert_eq!(limiter.open_connections(), 1);
+        assert!(limiter.acquire(ip("192.0.2.3")).is_ok());
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let limiter = ConnectionLimiter::new(0, 0);
+        let held: Vec<_> = (0..100)
+            .map(|_| limiter.acquire(ip("192.0.2.1")).unwrap())
+            .collect();
+        assert_eq!(limiter.open_connections(), held.len());
+    }
+}